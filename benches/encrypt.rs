@@ -0,0 +1,66 @@
+// Throughput of relaying data through a pair of encryptors, run by `cargo bench`.
+extern crate shadowsocks;
+
+use std::time::{Duration, Instant};
+
+use shadowsocks::crypto::{Encryptor, Method};
+
+const PASSWORD: &'static str = "foo";
+const MB: usize = 1024 * 1024;
+const TOTAL_MB: usize = 64;
+// same as the read buffer of `TcpProcessor`
+const PIECE: usize = 32 * 1024;
+const METHODS: &'static [Method] = &[Method::aes_256_cfb,
+                                     Method::aes_256_ctr,
+                                     Method::chacha20,
+                                     Method::aes_128_gcm,
+                                     Method::aes_256_gcm,
+                                     Method::chacha20_ietf_poly1305];
+
+fn mb_per_sec(elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    TOTAL_MB as f64 / secs
+}
+
+// encrypt and decrypt by `encrypt` and `decrypt`
+fn relay_by_vec(method: Method) -> f64 {
+    let mut encryptor = Encryptor::new(PASSWORD, method).unwrap();
+    let mut decryptor = Encryptor::new(PASSWORD, method).unwrap();
+    let data = vec![0u8; PIECE];
+
+    let start = Instant::now();
+    for _ in 0..TOTAL_MB * MB / PIECE {
+        let encrypted = encryptor.encrypt(&data).unwrap();
+        let decrypted = decryptor.decrypt(&encrypted).unwrap();
+        assert_eq!(decrypted.len(), PIECE);
+    }
+    mb_per_sec(start.elapsed())
+}
+
+// encrypt and decrypt by `encrypt_in_place` and `decrypt_in_place` on one buffer
+fn relay_in_place(method: Method) -> f64 {
+    let mut encryptor = Encryptor::new(PASSWORD, method).unwrap();
+    let mut decryptor = Encryptor::new(PASSWORD, method).unwrap();
+    let data = vec![0u8; PIECE];
+    let mut buf = Vec::with_capacity(PIECE);
+
+    let start = Instant::now();
+    for _ in 0..TOTAL_MB * MB / PIECE {
+        buf.clear();
+        buf.extend_from_slice(&data);
+        encryptor.encrypt_in_place(&mut buf).unwrap();
+        decryptor.decrypt_in_place(&mut buf).unwrap();
+        assert_eq!(buf.len(), PIECE);
+    }
+    mb_per_sec(start.elapsed())
+}
+
+fn main() {
+    println!("{:<24}{:>16}{:>16}", "method", "by vec (MB/s)", "in place (MB/s)");
+    for &method in METHODS {
+        println!("{:<24}{:>16.1}{:>16.1}",
+                 method.to_string(),
+                 relay_by_vec(method),
+                 relay_in_place(method));
+    }
+}
@@ -1,31 +1,45 @@
-#[macro_use(slog_log, slog_error)]
+#[macro_use(slog_log, slog_info, slog_error)]
 extern crate slog;
-#[macro_use(error)]
+#[macro_use(info, error)]
 extern crate slog_scope;
 extern crate shadowsocks;
 
+use std::sync::Arc;
 use std::process::exit;
 use std::thread::spawn;
 
 use shadowsocks::my_logger;
 use shadowsocks::my_daemonize;
-use shadowsocks::config::CONFIG;
+use shadowsocks::config::{SharedConfig, init_config, reload_config};
 use shadowsocks::relay::{TcpRelay, UdpRelay};
 
 fn main() {
-    my_daemonize::init(CONFIG.daemon, &CONFIG.pid_file);
-    let _ = my_logger::init(CONFIG.log_level, CONFIG.log_file.as_ref()).map_err(|e| {
+    let config = init_config().unwrap_or_else(|e| {
+        println!("{:?}", e);
+        exit(1);
+    });
+    my_daemonize::init(config.daemon, &config.pid_file);
+    let _ = my_logger::init(config.log_level, config.log_file.as_ref()).map_err(|e| {
         println!("init logger failed: {}", e);
         exit(1);
     });
+    let config = Arc::new(SharedConfig::new(config));
+
+    // new connections use the reloaded config, while listening addresses are unchanged
+    let shared = config.clone();
+    my_daemonize::on_sighup(move || match reload_config(&shared) {
+        Ok(()) => info!("config reloaded"),
+        Err(e) => error!("reload config failed: {:?}", e),
+    });
 
-    let childs = vec![spawn(|| {
-                          TcpRelay::new()
+    let tcp_config = config.clone();
+    let childs = vec![spawn(move || {
+                          TcpRelay::new(tcp_config)
                               .and_then(|r| r.run())
                               .unwrap_or_else(|e| error!("{:?}", e))
                       }),
-                      spawn(|| {
-                          UdpRelay::new()
+                      spawn(move || {
+                          UdpRelay::new(config)
                               .and_then(|r| r.run())
                               .unwrap_or_else(|e| error!("{:?}", e))
                       })];
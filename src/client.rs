@@ -0,0 +1,135 @@
+// Connect to a destination through ssserver directly, without running sslocal.
+use std::cmp;
+use std::io::Write;
+use std::time::Duration;
+use std::net::{TcpStream, UdpSocket, SocketAddr, ToSocketAddrs};
+
+use config::ProxyConfig;
+use crypto::{Encryptor, EncryptedStream};
+use network::Address;
+use socks5::{pack_header, parse_header, addr_type, Socks5Header};
+use error::{Result, SocketError, ProcessError, Socks5Error};
+
+const BUF_SIZE: usize = 64 * 1024;
+
+/// Connect to `target` through `server`, the returned stream is ready to transfer data.
+pub fn connect(server: &ProxyConfig, target: Address) -> Result<EncryptedStream<TcpStream>> {
+    connect_with(server, target, None)
+}
+
+/// Same as `connect`, but fail if `server` can't be connected in `timeout`.
+pub fn connect_timeout(server: &ProxyConfig,
+                       target: Address,
+                       timeout: Duration)
+                       -> Result<EncryptedStream<TcpStream>> {
+    connect_with(server, target, Some(timeout))
+}
+
+fn connect_with(server: &ProxyConfig,
+                target: Address,
+                timeout: Option<Duration>)
+                -> Result<EncryptedStream<TcpStream>> {
+    let mut header = pack_header(&target).ok_or(Socks5Error::InvalidHeader)?;
+    let server_addr = resolve(server)?;
+    let conn = match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&server_addr, timeout),
+        None => TcpStream::connect(server_addr),
+    };
+    let conn = conn.map_err(|e| ProcessError::ConnectFailed(format!("{}: {}", server_addr, e)))?;
+    conn.set_nodelay(true)?;
+
+    let encryptor = Encryptor::new(&server.password, server.method)
+        .map_err(ProcessError::InitEncryptorFailed)?;
+    let mut stream = EncryptedStream::with_encryptor(conn, encryptor);
+    // AEAD ciphers authenticate every chunk, so OTA is meaningless
+    if server.one_time_auth && !server.method.is_aead() {
+        header[0] |= addr_type::AUTH;
+        stream.enable_ota(header[0], header.len(), &header)?;
+    } else {
+        stream.write_all(&header)?;
+    }
+    Ok(stream)
+}
+
+/// A UDP socket which relays datagrams through ssserver.
+pub struct UdpConnector {
+    sock: UdpSocket,
+    server_addr: SocketAddr,
+    encryptor: Encryptor,
+    is_ota_enabled: bool,
+    buf: Vec<u8>,
+}
+
+impl UdpConnector {
+    pub fn new(server: &ProxyConfig) -> Result<UdpConnector> {
+        let server_addr = resolve(server)?;
+        let local_addr = if server_addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let sock = UdpSocket::bind(local_addr)?;
+        let encryptor = Encryptor::new(&server.password, server.method)
+            .map_err(ProcessError::InitEncryptorFailed)?;
+
+        Ok(UdpConnector {
+            sock: sock,
+            server_addr: server_addr,
+            encryptor: encryptor,
+            is_ota_enabled: server.one_time_auth && !server.method.is_aead(),
+            buf: vec![0u8; BUF_SIZE],
+        })
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.sock
+    }
+
+    /// Send `data` to `target`, return the length of `data` sent.
+    pub fn send_to(&mut self, data: &[u8], target: &Address) -> Result<usize> {
+        let mut request = pack_header(target).ok_or(Socks5Error::InvalidHeader)?;
+        request.extend_from_slice(data);
+
+        let encrypted = if self.is_ota_enabled {
+            let addr_type = request[0] | addr_type::AUTH;
+            self.encryptor.encrypt_udp_ota(addr_type, &request)
+        } else {
+            self.encryptor.encrypt_udp(&request)
+        };
+        let encrypted = encrypted.ok_or(ProcessError::EncryptFailed)?;
+        self.sock.send_to(&encrypted, self.server_addr).map_err(SocketError::WriteFailed)?;
+        Ok(data.len())
+    }
+
+    /// Receive a datagram relayed by ssserver, return its length and where it comes from.
+    /// Datagrams not from ssserver are dropped.
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, Address)> {
+        loop {
+            let (nread, addr) = self.sock
+                .recv_from(&mut self.buf)
+                .map_err(SocketError::ReadFailed)?;
+            if addr != self.server_addr {
+                continue;
+            }
+
+            let data = self.encryptor
+                .decrypt_udp(&self.buf[..nread])
+                .ok_or(ProcessError::DecryptFailed)?;
+            if data.is_empty() {
+                return err_from!(Socks5Error::InvalidHeader);
+            }
+            let Socks5Header(_, address, port, header_length) =
+                parse_header(&data).ok_or(Socks5Error::InvalidHeader)?;
+            let data = &data[header_length..];
+            let len = cmp::min(data.len(), buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            return Ok((len, Address(address, port)));
+        }
+    }
+}
+
+fn resolve(server: &ProxyConfig) -> Result<SocketAddr> {
+    let addr = format!("{}:{}", server.address, server.port);
+    let mut addrs = (&server.address[..], server.port).to_socket_addrs()?;
+    addrs.next().ok_or(From::from(SocketError::ParseAddrFailed(addr)))
+}
@@ -0,0 +1,77 @@
+use std::cmp;
+use std::f64::consts::LN_2;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::collections::hash_map::RandomState;
+
+/// A Bloom filter sized by the expected number of items and false positive rate.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    nbits: u64,
+    nhashes: u64,
+    len: usize,
+    hash_builder: RandomState,
+}
+
+impl BloomFilter {
+    pub fn new(capacity: usize, fp_rate: f64) -> BloomFilter {
+        let capacity = cmp::max(capacity, 1) as f64;
+        let nbits = (-capacity * fp_rate.ln() / (LN_2 * LN_2)).ceil() as u64;
+        let nbits = cmp::max(nbits, 64);
+        let nhashes = (nbits as f64 / capacity * LN_2).round() as u64;
+
+        BloomFilter {
+            bits: vec![0; ((nbits + 63) / 64) as usize],
+            nbits: nbits,
+            nhashes: cmp::max(nhashes, 1),
+            len: 0,
+            hash_builder: RandomState::new(),
+        }
+    }
+
+    /// Number of inserted items.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        for b in &mut self.bits {
+            *b = 0;
+        }
+        self.len = 0;
+    }
+
+    pub fn contains<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        let (h1, h2) = self.hash(item);
+        (0..self.nhashes).all(|i| {
+            let (idx, mask) = self.position(h1, h2, i);
+            self.bits[idx] & mask != 0
+        })
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) {
+        let (h1, h2) = self.hash(item);
+        for i in 0..self.nhashes {
+            let (idx, mask) = self.position(h1, h2, i);
+            self.bits[idx] |= mask;
+        }
+        self.len += 1;
+    }
+
+    // split one 64 bits hash into two 32 bits hashes
+    fn hash<T: Hash + ?Sized>(&self, item: &T) -> (u64, u64) {
+        let mut hasher = self.hash_builder.build_hasher();
+        item.hash(&mut hasher);
+        let h = hasher.finish();
+        (h & 0xFFFFFFFF, h >> 32)
+    }
+
+    // g_i(x) = h1(x) + i * h2(x)
+    fn position(&self, h1: u64, h2: u64, i: u64) -> (usize, u64) {
+        let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.nbits;
+        ((bit / 64) as usize, 1 << (bit % 64))
+    }
+}
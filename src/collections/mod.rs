@@ -1,6 +1,10 @@
 pub use self::holder::Holder;
+pub use self::bloom::BloomFilter;
+pub use self::ring::HashRing;
 
 mod holder;
+mod bloom;
+mod ring;
 
 use std::hash::BuildHasherDefault;
 use std::collections::{HashSet, HashMap};
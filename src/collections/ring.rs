@@ -0,0 +1,64 @@
+use std::hash::{Hash, Hasher};
+
+use fnv::FnvHasher;
+
+/// A consistent hash ring. Every node is placed at `replicas` points of the
+/// ring, and a key belongs to the first node after it, so adding or removing
+/// a node only moves the keys of that node.
+///
+/// The points of a node are decided by its `id` rather than the node itself,
+/// so a node changed in other ways (e.g. its password) keeps its keys.
+pub struct HashRing<T> {
+    replicas: usize,
+    // sorted by the points
+    points: Vec<(u64, T)>,
+}
+
+impl<T: Eq + Clone> HashRing<T> {
+    pub fn new(replicas: usize) -> HashRing<T> {
+        HashRing {
+            replicas: replicas,
+            points: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn insert<I: Hash + ?Sized>(&mut self, id: &I, node: T) {
+        for i in 0..self.replicas {
+            let point = hash(&(id, i));
+            let pos = match self.points.binary_search_by_key(&point, |&(p, _)| p) {
+                Ok(pos) | Err(pos) => pos,
+            };
+            self.points.insert(pos, (point, node.clone()));
+        }
+    }
+
+    pub fn remove(&mut self, node: &T) {
+        self.points.retain(|&(_, ref n)| n != node);
+    }
+
+    pub fn get<K: Hash + ?Sized>(&self, key: &K) -> Option<&T> {
+        self.find(key, |_| true)
+    }
+
+    /// Find the first node after `key` which is accepted by `f`.
+    pub fn find<K: Hash + ?Sized, F: Fn(&T) -> bool>(&self, key: &K, f: F) -> Option<&T> {
+        let point = hash(key);
+        let start = match self.points.binary_search_by_key(&point, |&(p, _)| p) {
+            Ok(pos) | Err(pos) => pos,
+        };
+        let (before, after) = self.points.split_at(start);
+        after.iter().chain(before).map(|&(_, ref node)| node).find(|node| f(*node))
+    }
+}
+
+// FNV is used instead of `RandomState`, so that keys are mapped to the same
+// nodes after restart
+fn hash<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = FnvHasher::default();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
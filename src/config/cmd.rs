@@ -1,11 +1,16 @@
+use std::env;
 use std::sync::Arc;
 
 use clap::{Arg, App, ArgMatches};
 
-use super::{ConfigError, ConfigResult, ProxyConfig, Config};
+use super::{ConfigError, ConfigErrors, ConfigResult, Config};
 
 // TODO: change to use macro when https://github.com/kbknapp/clap-rs/pull/731 decided
 pub fn parse_cmds<'a>() -> ArgMatches<'a> {
+    build_app().get_matches()
+}
+
+pub fn build_app<'a, 'b>() -> App<'a, 'b> {
     lazy_static! {
         static ref CONFIG_HELP: String = format!("path to config file\n[default: {}]",
                                                  Config::default_config_path().display());
@@ -19,7 +24,7 @@ pub fn parse_cmds<'a>() -> ArgMatches<'a> {
     let mut args = App::new(NAME.to_string())
         .version("0.6.1")
         .arg(Arg::with_name("input")
-            .help("parse config from base64 encoded input")
+            .help("parse config from ss:// URI (legacy base64 or SIP002)")
             .takes_value(true))
         .arg(Arg::with_name("config")
             .short("c")
@@ -31,7 +36,7 @@ pub fn parse_cmds<'a>() -> ArgMatches<'a> {
             .short("k")
             .long("password")
             .value_name("str")
-            .help("password")
+            .help("password (base64 encoded key for 2022-* methods)")
             .takes_value(true))
         .arg(Arg::with_name("method")
             .short("m")
@@ -74,7 +79,16 @@ pub fn parse_cmds<'a>() -> ArgMatches<'a> {
             .help("enable one time auth"))
         .arg(Arg::with_name("prefer_ipv6")
             .long("prefer-ipv6")
-            .help("priority use IPv6"));
+            .help("priority use IPv6"))
+        .arg(Arg::with_name("share_server")
+            .long("share-server")
+            .help("print ss:// URIs and QR codes of servers, then exit"))
+        .arg(Arg::with_name("check_config")
+            .long("check-config")
+            .help("check config and report all the errors, then exit"))
+        .arg(Arg::with_name("print_config")
+            .long("print-config")
+            .help("print the effective config with passwords redacted, then exit"));
 
     if cfg!(target_family = "unix") {
         args = args.arg(Arg::with_name("daemon")
@@ -91,6 +105,15 @@ pub fn parse_cmds<'a>() -> ArgMatches<'a> {
                 .takes_value(true));
     }
 
+    if !cfg!(feature = "sslocal") {
+        args = args.arg(Arg::with_name("replay_filter_capacity")
+            .long("replay-filter-capacity")
+            .value_name("int")
+            .help("number of recent IVs remembered to reject replayed requests, 0 to disable \
+                   [default: 1000000]")
+            .takes_value(true));
+    }
+
     if cfg!(feature = "sslocal") {
         args = args.arg(Arg::with_name("server")
                 .short("s")
@@ -103,37 +126,49 @@ pub fn parse_cmds<'a>() -> ArgMatches<'a> {
                 .takes_value(true)
                 .value_name("str")
                 .help("the way to choose server")
-                .possible_values(&["fast", "balance"]))
+                .possible_values(&["fast",
+                                   "balance",
+                                   "failover",
+                                   "sticky",
+                                   "weighted",
+                                   "least_conn"]))
             .arg(Arg::with_name("add_server")
                 .long("add-server")
                 .value_name("str")
                 .takes_value(true)
-                .help("append base64 encoded server config"));
+                .help("append server config from ss:// URI (legacy base64 or SIP002)"));
     }
 
-    args.get_matches()
+    args
 }
 
 pub fn check_and_set_from_args(args: &ArgMatches, conf: &mut Config) -> ConfigResult<()> {
+    let mut errors = ConfigErrors::default();
     macro_rules! try_set {
         ($set:ident, $name:expr, str) => {
             try_set!($set, args.value_of($name))
         };
+        // absent flags don't override config file and environment variables,
+        // neither do absent `-v` and `-q`
         ($set:ident, $name:expr, bool) => {
-            try_set!($set, Some(args.is_present($name)))
+            if args.is_present($name) {
+                try_set!($set, Some(true))
+            }
         };
         ($set:ident, $name:expr, int) => {{
             if let Some(v) = args.value_of($name) {
                 match v.parse::<i64>() {
                     Ok(v) => try_set!($set, Some(v)),
-                    Err(_) => return Err(ConfigError::InvalidNumber(v.to_string())),
+                    Err(_) => errors.add(ConfigError::InvalidNumber(v.to_string())),
                 }
             }
         }};
         ($set:ident, $name:expr, occurrences) => {
-            try_set!($set, Some(args.occurrences_of($name) as i64))
+            if args.occurrences_of($name) > 0 {
+                try_set!($set, Some(args.occurrences_of($name) as i64))
+            }
         };
-        ($set:ident, $val:expr) => { conf.$set($val)?; };
+        ($set:ident, $val:expr) => {{ errors.check(conf.$set($val)); }};
     }
 
     try_set!(set_quiet, "quiet", occurrences);
@@ -143,6 +178,7 @@ pub fn check_and_set_from_args(args: &ArgMatches, conf: &mut Config) -> ConfigRe
     try_set!(set_prefer_ipv6, "prefer_ipv6", bool);
     try_set!(set_daemon, "daemon", str);
     try_set!(set_mode, "mode", str);
+    try_set!(set_replay_filter_capacity, "replay_filter_capacity", int);
 
     try_set!(set_address, "address", str);
     try_set!(set_port, "port", int);
@@ -151,37 +187,49 @@ pub fn check_and_set_from_args(args: &ArgMatches, conf: &mut Config) -> ConfigRe
     try_set!(set_timeout, "timeout", int);
     try_set!(set_one_time_auth, "one_time_auth", bool);
 
-    Ok(())
+    errors.into_result()
 }
 
-// `server` present in command line will override `servers`.
+// `server` present in command line will override `servers`, the other fields
+// of the server are set by command line or `SS_*` environment variables already.
 pub fn check_and_set_server_from_args(args: &ArgMatches, conf: &mut Config) -> ConfigResult<()> {
-    let password = args.value_of("password").ok_or(ConfigError::MissServerPassword)?;
-    let method = args.value_of("method").ok_or(ConfigError::MissServerMethod)?;
+    check_and_set_server_from_vars_and_args(|name| env::var(name).ok(), args, conf)
+}
+
+// `var` returns the value of a `SS_*` environment variable, see `env::check_and_set_from_vars`
+pub fn check_and_set_server_from_vars_and_args<F>(var: F,
+                                                  args: &ArgMatches,
+                                                  conf: &mut Config)
+                                                  -> ConfigResult<()>
+    where F: Fn(&str) -> Option<String>
+{
+    let mut errors = ConfigErrors::default();
+    let mut server_conf = (*conf.proxy_conf).clone();
+    let is_given = |name: &str, vars: &[&str]| {
+        args.value_of(name).is_some() || vars.iter().any(|v| var(v).is_some())
+    };
+    if !is_given("method", &["SS_METHOD"]) {
+        errors.add(ConfigError::MissServerMethod);
+    }
+    if !is_given("password", &["SS_PASSWORD", "SS_PASSWORD_FILE"]) {
+        errors.add(ConfigError::MissServerPassword);
+    }
 
     let server_address = args.value_of("server").unwrap();
-    let mut port_addr: Vec<&str> = server_address.rsplitn(2, ':').collect();
-    if port_addr.len() == 2 {
-        let addr = port_addr.pop().unwrap();
-        let port = port_addr.pop().unwrap();
-        let port = port.parse::<i64>().map_err(|_| ConfigError::InvalidNumber(port.to_string()))?;
-
-        let mut server_conf = ProxyConfig::default();
-        server_conf.set_address(Some(addr))?;
-        server_conf.set_port(Some(port))?;
-
-        server_conf.set_method(Some(method))?;
-        server_conf.set_password(Some(password))?;
-        server_conf.set_one_time_auth(Some(args.is_present("one_time_auth")))?;
-
-        if let Some(t) = args.value_of("timeout") {
-            let t = t.parse::<i64>().map_err(|_| ConfigError::InvalidNumber(t.to_string()))?;
-            server_conf.set_timeout(Some(t))?;
+    match server_address.rfind(':') {
+        Some(i) => {
+            errors.check(server_conf.set_address(Some(&server_address[..i])));
+            let port = &server_address[i + 1..];
+            match port.parse::<i64>() {
+                Ok(port) => {
+                    errors.check(server_conf.set_port(Some(port)));
+                }
+                Err(_) => errors.add(ConfigError::InvalidNumber(port.to_string())),
+            }
         }
-
-        conf.server_confs = Some(vec![Arc::new(server_conf)]);
-        Ok(())
-    } else {
-        Err(ConfigError::InvalidAddress(server_address.to_string()))
+        None => errors.add(ConfigError::InvalidAddress(server_address.to_string())),
     }
+
+    conf.server_confs = Some(vec![Arc::new(server_conf)]);
+    errors.into_result()
 }
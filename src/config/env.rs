@@ -0,0 +1,144 @@
+// `SS_*` environment variables, e.g. `SS_PASSWORD` for `password`, which keep
+// secrets out of command line and config file.
+use std::env;
+
+use super::{ConfigError, ConfigErrors, ConfigResult, Config};
+
+/// Whether any `SS_*` environment variable is set.
+pub fn is_set() -> bool {
+    env::vars_os().any(|(k, _)| k.to_str().map_or(false, |k| k.starts_with("SS_")))
+}
+
+pub fn check_and_set_from_env(conf: &mut Config) -> ConfigResult<()> {
+    check_and_set_from_vars(|name| env::var(name).ok(), conf)
+}
+
+// `var` returns the value of a variable, so that it can be tested without the
+// environment shared by the whole process
+fn check_and_set_from_vars<F>(var: F, conf: &mut Config) -> ConfigResult<()>
+    where F: Fn(&str) -> Option<String>
+{
+    let mut errors = ConfigErrors::default();
+    macro_rules! try_set {
+        ($set:ident, $name:expr, str) => {
+            if let Some(v) = var($name) {
+                errors.check(conf.$set(Some(&v[..])));
+            }
+        };
+        ($set:ident, $name:expr, int) => {
+            if let Some(v) = var($name) {
+                match v.parse::<i64>() {
+                    Ok(n) => {
+                        errors.check(conf.$set(Some(n)));
+                    }
+                    Err(_) => errors.add(ConfigError::InvalidNumber(format!("{}={}", $name, v))),
+                }
+            }
+        };
+        ($set:ident, $name:expr, bool) => {
+            if let Some(v) = var($name) {
+                if let Some(b) = errors.check(parse_bool($name, &v)) {
+                    errors.check(conf.$set(Some(b)));
+                }
+            }
+        };
+    }
+
+    try_set!(set_quiet, "SS_QUIET", int);
+    try_set!(set_verbose, "SS_VERBOSE", int);
+    try_set!(set_log_file, "SS_LOG_FILE", str);
+    try_set!(set_pid_file, "SS_PID_FILE", str);
+    try_set!(set_prefer_ipv6, "SS_PREFER_IPV6", bool);
+    try_set!(set_daemon, "SS_DAEMON", str);
+    try_set!(set_mode, "SS_MODE", str);
+    try_set!(set_replay_filter_capacity, "SS_REPLAY_FILTER_CAPACITY", int);
+
+    try_set!(set_address, "SS_ADDRESS", str);
+    try_set!(set_port, "SS_PORT", int);
+    try_set!(set_method, "SS_METHOD", str);
+    try_set!(set_password, "SS_PASSWORD", str);
+    try_set!(set_password_file, "SS_PASSWORD_FILE", str);
+    try_set!(set_timeout, "SS_TIMEOUT", int);
+    try_set!(set_one_time_auth, "SS_ONE_TIME_AUTH", bool);
+
+    errors.into_result()
+}
+
+fn parse_bool(name: &str, val: &str) -> ConfigResult<bool> {
+    match val {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => Err(ConfigError::Other(format!("invalid boolean: {}={}", name, val))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    use toml::Parser;
+
+    use config::{Config, ConfigError};
+    use config::cmd::{build_app, check_and_set_from_args};
+    use config::toml::check_and_set_from_toml;
+    use crypto::Method;
+    use super::check_and_set_from_vars;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn precedence() {
+        let tbl = Parser::new("method = \"aes-256-cfb\"\npassword = \"foo\"\ntimeout = 10")
+            .parse()
+            .unwrap();
+        let password_path = env::temp_dir().join("shadowsocks_password_file_test");
+        File::create(&password_path).unwrap().write_all(b"bar\n").unwrap();
+        let password_path = password_path.to_str().unwrap();
+        let vars = vars(&[("SS_METHOD", "aes-256-gcm"),
+                          ("SS_PASSWORD_FILE", password_path),
+                          ("SS_TIMEOUT", "20"),
+                          ("SS_QUIET", "1")]);
+        let var = |name: &str| vars.get(name).cloned();
+
+        // command line > environment variables > config file
+        let args = build_app().get_matches_from(vec!["ssserver", "-t", "30"]);
+        let mut conf = Config::default();
+        check_and_set_from_toml(&tbl, &mut conf).unwrap();
+        check_and_set_from_vars(&var, &mut conf).unwrap();
+        check_and_set_from_args(&args, &mut conf).unwrap();
+        assert_eq!(conf.method(), Method::aes_256_gcm);
+        // the trailing newline is ignored
+        assert_eq!(conf.password(), "bar");
+        assert_eq!(conf.timeout(), 30);
+        // absent `-v` and `-q` don't override `SS_QUIET`
+        assert_eq!(conf.log_level, -1);
+
+        if cfg!(feature = "sslocal") {
+            use config::cmd::check_and_set_server_from_vars_and_args;
+
+            let args = build_app().get_matches_from(vec!["sslocal", "-s", "1.2.3.4:8388"]);
+            let mut conf = Config::default();
+            check_and_set_from_vars(&var, &mut conf).unwrap();
+            check_and_set_from_args(&args, &mut conf).unwrap();
+            check_and_set_server_from_vars_and_args(&var, &args, &mut conf).unwrap();
+            let server = &conf.server_confs.unwrap()[0];
+            assert_eq!((&server.address[..], server.port), ("1.2.3.4", 8388));
+            assert_eq!(server.method, Method::aes_256_gcm);
+            assert_eq!(server.password, "bar");
+        }
+    }
+
+    #[test]
+    fn invalid_vars() {
+        let vars = vars(&[("SS_ONE_TIME_AUTH", "maybe"), ("SS_PORT", "http")]);
+        match check_and_set_from_vars(|name| vars.get(name).cloned(), &mut Config::default()) {
+            Err(ConfigError::Multiple(errors)) => assert_eq!(errors.len(), 2),
+            _ => panic!("all the invalid variables should be reported"),
+        }
+    }
+}
@@ -0,0 +1,305 @@
+// Config files of shadowsocks-libev and shadowsocks-python are JSON, which are
+// translated into the TOML table used by `check_and_set_*_from_toml`.
+// Server lists in SIP008 format are JSON too.
+use std::sync::Arc;
+use std::path::Path;
+
+use rustc_serialize::json::{Json, Object};
+use toml::{Value, Table};
+
+use super::{ConfigError, ConfigResult, ProxyConfig};
+use super::toml::read_file;
+
+// keys which make no sense here
+const IGNORED_KEYS: &'static [&'static str] = &["fast_open", "workers", "reuse_port", "no_delay"];
+
+pub fn is_json(path: &Path, input: &str) -> bool {
+    match path.extension() {
+        Some(ext) if ext == "json" => true,
+        _ => input.trim_left().starts_with('{'),
+    }
+}
+
+pub fn parse_json(input: &str) -> ConfigResult<Table> {
+    let obj = match Json::from_str(input) {
+        Ok(Json::Object(obj)) => obj,
+        Ok(_) => {
+            let errmsg = "JSON config should be an object".to_string();
+            return Err(ConfigError::ParseConfigFailed(errmsg));
+        }
+        Err(e) => return Err(ConfigError::ParseConfigFailed(format!("{}", e))),
+    };
+
+    let mut tbl = Table::new();
+    let mut servers = vec![];
+    let mut server_port = None;
+    let mut local_address = None;
+    let mut local_port = None;
+
+    for (key, val) in &obj {
+        let key = &key[..];
+        match key {
+            "server" => {
+                servers = match *val {
+                    Json::Array(ref addrs) => {
+                        let mut servers = vec![];
+                        for addr in addrs {
+                            servers.push(convert(key, addr, Kind::Str)?);
+                        }
+                        servers
+                    }
+                    _ => vec![convert(key, val, Kind::Str)?],
+                }
+            }
+            "server_port" => server_port = Some(convert(key, val, Kind::Int)?),
+            "local_address" => local_address = Some(convert(key, val, Kind::Str)?),
+            "local_port" => local_port = Some(convert(key, val, Kind::Int)?),
+            "password" | "method" | "log_file" | "pid_file" | "plugin" | "plugin_opts" => {
+                tbl.insert(key.to_string(), convert(key, val, Kind::Str)?);
+            }
+            "timeout" | "verbose" | "quiet" | "replay_filter_capacity" => {
+                tbl.insert(key.to_string(), convert(key, val, Kind::Int)?);
+            }
+            "one_time_auth" | "prefer_ipv6" | "daemon" => {
+                tbl.insert(key.to_string(), convert(key, val, Kind::Bool)?);
+            }
+            "port_password" => {
+                tbl.insert(key.to_string(), convert(key, val, Kind::Table)?);
+            }
+            // `mode` of shadowsocks-libev means which protocols to relay,
+            // both TCP and UDP are always relayed here
+            "mode" => {
+                let mode = convert(key, val, Kind::Str)?;
+                match mode.as_str() {
+                    Some("tcp_only") | Some("udp_only") | Some("tcp_and_udp") => {}
+                    _ => {
+                        tbl.insert(key.to_string(), mode);
+                    }
+                }
+            }
+            _ if IGNORED_KEYS.iter().any(|k| *k == key) => {}
+            _ => {
+                let errmsg = format!("unknown key `{}` in JSON config", key);
+                return Err(ConfigError::ParseConfigFailed(errmsg));
+            }
+        }
+    }
+
+    if cfg!(feature = "sslocal") {
+        if let Some(address) = local_address {
+            tbl.insert("address".to_string(), address);
+        }
+        if let Some(port) = local_port {
+            tbl.insert("port".to_string(), port);
+        }
+
+        let mut server_tbls = vec![];
+        for address in servers {
+            let mut server = Table::new();
+            server.insert("address".to_string(), address);
+            if let Some(ref port) = server_port {
+                server.insert("port".to_string(), port.clone());
+            }
+            server_tbls.push(Value::Table(server));
+        }
+        if !server_tbls.is_empty() {
+            tbl.insert("servers".to_string(), Value::Array(server_tbls));
+        }
+    } else {
+        // ssserver listens on one address only, so the first one is used
+        if let Some(address) = servers.into_iter().next() {
+            tbl.insert("address".to_string(), address);
+        }
+        if let Some(port) = server_port {
+            tbl.insert("port".to_string(), port);
+        }
+    }
+
+    Ok(tbl)
+}
+
+/// Read servers from a SIP008 file, the fields missing in the file are inherited from `base`.
+pub fn read_servers_file<P: AsRef<Path>>(path: P,
+                                         base: &Arc<ProxyConfig>)
+                                         -> ConfigResult<Vec<Arc<ProxyConfig>>> {
+    let input = read_file(path.as_ref())?;
+    parse_sip008(&input, base).map_err(|e| {
+        let errmsg = format!("{} ({:?})", path.as_ref().display(), e);
+        ConfigError::ParseConfigFailed(errmsg)
+    })
+}
+
+// {
+//     "version": 1,
+//     "servers": [
+//         {
+//             "id": "27b8a625-4f4b-4428-9f0f-8a2317db7c79",
+//             "remarks": "Name of the server",
+//             "server": "example.com",
+//             "server_port": 8388,
+//             "password": "example",
+//             "method": "chacha20-ietf-poly1305",
+//             "plugin": "xxx",
+//             "plugin_opts": "xxxxx"
+//         }
+//     ]
+// }
+fn parse_sip008(input: &str, base: &Arc<ProxyConfig>) -> ConfigResult<Vec<Arc<ProxyConfig>>> {
+    let json = Json::from_str(input).map_err(|e| ConfigError::ParseConfigFailed(format!("{}", e)))?;
+    let servers = match json.find("servers") {
+        Some(&Json::Array(ref servers)) => servers,
+        _ => {
+            let errmsg = "`servers` should be an array".to_string();
+            return Err(ConfigError::ParseConfigFailed(errmsg));
+        }
+    };
+    let mut server_confs = vec![];
+
+    for server in servers {
+        let obj = match *server {
+            Json::Object(ref obj) => obj,
+            _ => {
+                let errmsg = format!("server should be an object: {}", server);
+                return Err(ConfigError::ParseConfigFailed(errmsg));
+            }
+        };
+        let mut server_conf = base.clone();
+
+        {
+            let mut tmp = Arc::make_mut(&mut server_conf);
+            let address = field(obj, "server", Kind::Str)?.ok_or(ConfigError::MissServerAddress)?;
+            tmp.set_address(address.as_str())?;
+            let port = field(obj, "server_port", Kind::Int)?.ok_or(ConfigError::MissServerPort)?;
+            tmp.set_port(port.as_integer())?;
+            tmp.set_method(field(obj, "method", Kind::Str)?.as_ref().and_then(Value::as_str))?;
+            tmp.set_password(field(obj, "password", Kind::Str)?.as_ref().and_then(Value::as_str))?;
+            tmp.set_plugin(field(obj, "plugin", Kind::Str)?.as_ref().and_then(Value::as_str))?;
+            tmp.set_plugin_opts(field(obj, "plugin_opts", Kind::Str)?
+                .as_ref()
+                .and_then(Value::as_str))?;
+            tmp.set_tag(field(obj, "remarks", Kind::Str)?.as_ref().and_then(Value::as_str))?;
+        }
+
+        server_confs.push(server_conf);
+    }
+
+    Ok(server_confs)
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Str,
+    Int,
+    Bool,
+    Table,
+}
+
+fn convert(key: &str, val: &Json, kind: Kind) -> ConfigResult<Value> {
+    let res = match (kind, val) {
+        (Kind::Str, &Json::String(ref s)) => Some(Value::String(s.clone())),
+        (Kind::Int, &Json::I64(n)) => Some(Value::Integer(n)),
+        (Kind::Int, &Json::U64(n)) if n <= i64::max_value() as u64 => {
+            Some(Value::Integer(n as i64))
+        }
+        (Kind::Bool, &Json::Boolean(b)) => Some(Value::Boolean(b)),
+        (Kind::Table, &Json::Object(ref obj)) => {
+            let mut tbl = Table::new();
+            for (k, v) in obj {
+                let v = match *v {
+                    Json::Object(_) => convert(k, v, Kind::Table)?,
+                    Json::Boolean(_) => convert(k, v, Kind::Bool)?,
+                    Json::I64(_) | Json::U64(_) => convert(k, v, Kind::Int)?,
+                    _ => convert(k, v, Kind::Str)?,
+                };
+                tbl.insert(k.clone(), v);
+            }
+            Some(Value::Table(tbl))
+        }
+        _ => None,
+    };
+
+    res.ok_or_else(|| {
+        let expected = match kind {
+            Kind::Str => "a string",
+            Kind::Int => "an integer",
+            Kind::Bool => "a boolean",
+            Kind::Table => "an object",
+        };
+        ConfigError::ParseConfigFailed(format!("`{}` should be {}, but got {}", key, expected, val))
+    })
+}
+
+fn field(obj: &Object, key: &str, kind: Kind) -> ConfigResult<Option<Value>> {
+    match obj.get(key) {
+        Some(val) => convert(key, val, kind).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use toml::Value;
+
+    use super::{is_json, parse_json};
+
+    const LIBEV_CONFIG: &'static str = r#"{
+        "server": ["1.2.3.4", "::1"],
+        "server_port": 8388,
+        "local_address": "127.0.0.1",
+        "local_port": 1080,
+        "password": "foo",
+        "method": "chacha20-ietf-poly1305",
+        "timeout": 300,
+        "mode": "tcp_and_udp",
+        "fast_open": true,
+        "port_password": {
+            "8389": "bar",
+            "8390": {"password": "baz", "timeout": 30}
+        }
+    }"#;
+
+    #[test]
+    fn detect_json() {
+        assert!(is_json(Path::new("config.json"), ""));
+        assert!(is_json(Path::new("config"), "\n  {\"server\": \"::1\"}"));
+        assert!(!is_json(Path::new("config.toml"), "address = \"::1\""));
+    }
+
+    #[test]
+    fn translate_keys() {
+        let tbl = parse_json(LIBEV_CONFIG).unwrap();
+        assert_eq!(tbl.get("password"), Some(&Value::String("foo".to_string())));
+        assert_eq!(tbl.get("timeout"), Some(&Value::Integer(300)));
+        // `mode` of shadowsocks-libev and ignored keys are dropped
+        assert_eq!(tbl.get("mode"), None);
+        assert_eq!(tbl.get("fast_open"), None);
+
+        let ports = tbl.get("port_password").and_then(Value::as_table).unwrap();
+        assert_eq!(ports.get("8389"), Some(&Value::String("bar".to_string())));
+        let port = ports.get("8390").and_then(Value::as_table).unwrap();
+        assert_eq!(port.get("timeout"), Some(&Value::Integer(30)));
+
+        if cfg!(feature = "sslocal") {
+            assert_eq!(tbl.get("address"), Some(&Value::String("127.0.0.1".to_string())));
+            assert_eq!(tbl.get("port"), Some(&Value::Integer(1080)));
+            let servers = tbl.get("servers").and_then(Value::as_slice).unwrap();
+            assert_eq!(servers.len(), 2);
+            let server = servers[1].as_table().unwrap();
+            assert_eq!(server.get("address"), Some(&Value::String("::1".to_string())));
+            assert_eq!(server.get("port"), Some(&Value::Integer(8388)));
+        } else {
+            assert_eq!(tbl.get("address"), Some(&Value::String("1.2.3.4".to_string())));
+            assert_eq!(tbl.get("port"), Some(&Value::Integer(8388)));
+            assert_eq!(tbl.get("servers"), None);
+        }
+    }
+
+    #[test]
+    fn invalid_json() {
+        assert!(parse_json("[]").is_err());
+        assert!(parse_json("{\"server_port\": \"8388\"}").is_err());
+        assert!(parse_json("{\"unknown\": 1}").is_err());
+    }
+}
@@ -1,30 +1,101 @@
 use std::fmt;
-use std::sync::Arc;
+use std::sync::{Arc, Weak, Mutex, RwLock};
 use std::net::{TcpStream, ToSocketAddrs};
 use std::io::prelude::*;
 use std::process::{exit, Command};
+use std::fs;
 use std::path::PathBuf;
 
+use clap::ArgMatches;
+use toml::Table;
+
 use my_daemonize;
+use mode::HealthChecker;
 
 #[macro_use]
 mod toml;
+mod json;
 mod cmd;
+mod env;
 mod proxy_config;
+mod user_config;
 mod running_config;
+mod share;
 
 use self::cmd::{parse_cmds, check_and_set_from_args, check_and_set_server_from_args};
+use self::env::check_and_set_from_env;
 use self::toml::{read_config, save_if_not_exists, append_to_default_config,
-                 check_and_set_from_toml, check_and_set_servers_from_toml};
+                 check_and_set_from_toml, check_and_set_servers_from_toml,
+                 check_and_set_users_from_toml, check_and_set_port_password_from_toml};
 
 pub use self::proxy_config::ProxyConfig;
+pub use self::user_config::UserConfig;
+pub use self::json::read_servers_file;
 pub use self::running_config::RunningConfig as Config;
 
+/// The config in use, which can be replaced while running.
+pub struct SharedConfig {
+    conf: RwLock<Arc<Config>>,
+    // only sslocal: the health checker used by the relays, it stops after they drop it
+    health_checker: Mutex<Weak<HealthChecker>>,
+}
+
+impl SharedConfig {
+    pub fn new(conf: Config) -> SharedConfig {
+        SharedConfig {
+            conf: RwLock::new(Arc::new(conf)),
+            health_checker: Mutex::new(Weak::new()),
+        }
+    }
+
+    /// Callers keep the returned config until they finish, even if it is replaced.
+    pub fn get(&self) -> Arc<Config> {
+        self.conf.read().unwrap().clone()
+    }
+
+    pub fn set(&self, conf: Config) {
+        *self.conf.write().unwrap() = Arc::new(conf);
+    }
+
+    /// The health checker of `conf`, which is shared by the callers with the same
+    /// `health_check_target` and `health_check_interval`, so that servers are probed once.
+    pub fn health_checker(&self, conf: &Config) -> Option<Arc<HealthChecker>> {
+        let target = try_opt!(conf.health_check_target.as_ref());
+        let mut shared = self.health_checker.lock().unwrap();
+        if let Some(checker) = shared.upgrade() {
+            if checker.target() == target && checker.interval() == conf.health_check_interval {
+                return Some(checker);
+            }
+        }
+
+        let checker = Arc::new(HealthChecker::new(target.clone(), conf.health_check_interval));
+        *shared = Arc::downgrade(&checker);
+        Some(checker)
+    }
+}
+
 lazy_static! {
-    pub static ref CONFIG: Config = init_config().unwrap_or_else(|e| {
-        println!("{:?}", e);
-        exit(1);
-    });
+    // parsed once on start, and applied again on reload
+    static ref ARGS: ArgMatches<'static> = parse_cmds();
+}
+
+/// Read the config file again (e.g. on SIGHUP), `SS_*` environment variables and
+/// command line are applied as on start. The current config is kept if the new one is invalid.
+pub fn reload_config(config: &SharedConfig) -> ConfigResult<()> {
+    let config_path = match config.get().config_file {
+        Some(ref path) => path.clone(),
+        None => return Err(ConfigError::Other("config is not read from file".to_string())),
+    };
+    let tbl = read_config(&config_path)?;
+    let mut conf = Config::default();
+    let mut errors = ConfigErrors::default();
+    errors.check(check_and_set_from_file(&tbl, &ARGS, &mut conf));
+    errors.check(check_config(&mut conf));
+    errors.into_result()?;
+
+    conf.config_file = Some(config_path);
+    config.set(conf);
+    Ok(())
 }
 
 pub type ConfigResult<T> = Result<T, ConfigError>;
@@ -34,14 +105,19 @@ pub enum ConfigError {
     MissServerPassword,
     MissServerAddress,
     MissServerPort,
+    MissUserName,
+    MissUserPassword(String),
+    DuplicateUser(String),
     OpenFileFailed(String),
     ParseConfigFailed(String),
     InvalidMode(String),
     InvalidMethod(String),
     InvalidNumber(String),
     InvalidAddress(String),
+    InvalidPassword(String),
     OutOfRange(i64),
     Other(String),
+    Multiple(Vec<ConfigError>),
 }
 
 impl fmt::Debug for ConfigError {
@@ -51,6 +127,11 @@ impl fmt::Debug for ConfigError {
             ConfigError::MissServerPassword => write!(f, "server password is missing"),
             ConfigError::MissServerAddress => write!(f, "server address is missing"),
             ConfigError::MissServerPort => write!(f, "server port is missing"),
+            ConfigError::MissUserName => write!(f, "user name is missing"),
+            ConfigError::MissUserPassword(ref name) => {
+                write!(f, "password of user {} is missing", name)
+            }
+            ConfigError::DuplicateUser(ref name) => write!(f, "duplicate user {}", name),
             ConfigError::OpenFileFailed(ref desc) => write!(f, "open config file failed: {}", desc),
             ConfigError::ParseConfigFailed(ref desc) => {
                 write!(f, "parse config file error: {}", desc)
@@ -61,20 +142,72 @@ impl fmt::Debug for ConfigError {
             }
             ConfigError::InvalidNumber(ref desc) => write!(f, "invalid number: {}", desc),
             ConfigError::InvalidAddress(ref desc) => write!(f, "invalid address: {}", desc),
+            ConfigError::InvalidPassword(ref desc) => write!(f, "invalid password: {}", desc),
             ConfigError::OutOfRange(n) => write!(f, "{} is out of range", n),
             ConfigError::Other(ref desc) => write!(f, "{}", desc),
+            ConfigError::Multiple(ref errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n")?;
+                    }
+                    write!(f, "{:?}", e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Errors found so far, used to report all the errors instead of the first one.
+#[derive(Default)]
+struct ConfigErrors(Vec<ConfigError>);
+
+impl ConfigErrors {
+    fn add(&mut self, e: ConfigError) {
+        match e {
+            ConfigError::Multiple(errors) => self.0.extend(errors),
+            e => self.0.push(e),
+        }
+    }
+
+    fn check<T>(&mut self, res: ConfigResult<T>) -> Option<T> {
+        match res {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.add(e);
+                None
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn into_result(mut self) -> ConfigResult<()> {
+        match self.0.len() {
+            0 => Ok(()),
+            1 => Err(self.0.pop().unwrap()),
+            _ => Err(ConfigError::Multiple(self.0)),
         }
     }
 }
 
 /// The working config follows a few rules:
-/// 1. Command line is prior to config file.
+/// 1. Command line is prior to `SS_*` environment variables, which are prior to config file.
+///    Every option of command line has its variable, e.g. `--one-time-auth` is
+///    `SS_ONE_TIME_AUTH=true`, and `SS_PASSWORD_FILE` reads password from file.
 /// 2. If no arguments provide, then read from default config file.
 /// 3. If default config file doesn't exists, then randomly generated one and save it.
+///
+/// With `--check-config` or `--print-config`, nothing is saved and the process exits
+/// after the config is checked.
 pub fn init_config() -> Result<Config, ConfigError> {
     let mut conf = Config::default();
     let default_config_path = Config::default_config_path();
-    let args = parse_cmds();
+    let args: &ArgMatches = &ARGS;
+    let is_dry_run = args.is_present("check_config") || args.is_present("print_config");
+    let mut errors = ConfigErrors::default();
 
     if cfg!(feature = "sslocal") {
         if let Some(server_conf) = args.value_of("add_server") {
@@ -83,33 +216,29 @@ pub fn init_config() -> Result<Config, ConfigError> {
             append_to_default_config(tmp);
             exit(0);
         }
-        // TODO: share sslocal server according mode
-        if args.is_present("share_server") {
-            exit(0);
-        }
-    } else {
-        // TODO: share ssserver server (check 0.0.0.0 & 127.0.0.1)
-        if args.is_present("share_server") {
-            exit(0);
-        }
     }
 
     // setup from input and save it if no default config
     if let Some(input) = args.value_of("input") {
         let mut proxy_conf = ProxyConfig::default();
-        proxy_conf.base64_decode(input)?;
+        errors.check(proxy_conf.base64_decode(input));
         let proxy_conf = Arc::new(proxy_conf);
         if cfg!(feature = "sslocal") {
             conf.server_confs = Some(vec![proxy_conf]);
         } else {
             conf.proxy_conf = proxy_conf;
         }
-        check_and_set_from_args(&args, &mut conf)?;
-        save_if_not_exists(&conf);
+        // saved without `SS_*` environment variables, which may hold secrets like `SS_PASSWORD`
+        let mut saved_conf = conf.clone();
+        let is_saved = check_and_set_from_args(args, &mut saved_conf).is_ok();
+        errors.check(check_and_set_from_env_and_args(args, &mut conf));
+        if !is_dry_run && is_saved && errors.is_empty() {
+            save_if_not_exists(&saved_conf);
+        }
         // setup from command line
     } else if args.value_of("server").is_some() {
-        check_and_set_from_args(&args, &mut conf)?;
-        check_and_set_server_from_args(&args, &mut conf)?;
+        errors.check(check_and_set_from_env_and_args(args, &mut conf));
+        errors.check(check_and_set_server_from_args(args, &mut conf));
         // setup from config file
     } else if args.value_of("config").is_some() || default_config_path.exists() {
         let config_path = match args.value_of("config") {
@@ -117,19 +246,20 @@ pub fn init_config() -> Result<Config, ConfigError> {
             None => default_config_path,
         };
         let tbl = read_config(&config_path)?;
-        check_and_set_from_toml(&tbl, &mut conf)?;
-        check_and_set_from_args(&args, &mut conf)?;
-        // setup `server` or `servers`
-        if conf.daemon != my_daemonize::Cmd::Stop {
-            if cfg!(feature = "sslocal") {
-                check_and_set_servers_from_toml(&tbl, &mut conf)?;
-                println!("start sslocal with {}", config_path.display());
+        errors.check(check_and_set_from_file(&tbl, args, &mut conf));
+        // reloaded after the working directory is changed by daemon
+        let config_file = fs::canonicalize(&config_path).unwrap_or_else(|_| config_path.clone());
+        conf.config_file = Some(config_file);
+        if conf.daemon != my_daemonize::Cmd::Stop && !is_dry_run {
+            let name = if cfg!(feature = "sslocal") {
+                "sslocal"
             } else {
-                println!("start ssserver with {}", config_path.display());
-            }
+                "ssserver"
+            };
+            println!("start {} with {}", name, config_path.display());
         }
         // create config if no args
-    } else if !cfg!(feature = "sslocal") && args.args.is_empty() {
+    } else if !cfg!(feature = "sslocal") && args.args.is_empty() && !env::is_set() {
         {
             // set `address` to external ip
             let mut tmp = Arc::make_mut(&mut conf.proxy_conf);
@@ -139,9 +269,56 @@ pub fn init_config() -> Result<Config, ConfigError> {
         println!("{}", conf.proxy_conf.base64_encode());
         save_if_not_exists(&conf);
     } else {
-        check_and_set_from_args(&args, &mut conf)?;
+        errors.check(check_and_set_from_env_and_args(args, &mut conf));
     }
 
+    errors.check(check_config(&mut conf));
+    errors.into_result()?;
+
+    if args.is_present("print_config") {
+        println!("{}", conf.redacted());
+        exit(0);
+    }
+    if args.is_present("check_config") {
+        println!("config is valid");
+        exit(0);
+    }
+
+    if args.is_present("share_server") {
+        share::share_servers(&conf);
+        exit(0);
+    }
+
+    Ok(conf)
+}
+
+fn check_and_set_from_env_and_args(args: &ArgMatches, conf: &mut Config) -> ConfigResult<()> {
+    let mut errors = ConfigErrors::default();
+    errors.check(check_and_set_from_env(conf));
+    errors.check(check_and_set_from_args(args, conf));
+    errors.into_result()
+}
+
+// setup from config file on start and reload
+fn check_and_set_from_file(tbl: &Table, args: &ArgMatches, conf: &mut Config) -> ConfigResult<()> {
+    let mut errors = ConfigErrors::default();
+    errors.check(check_and_set_from_toml(tbl, conf));
+    errors.check(check_and_set_from_env_and_args(args, conf));
+    // setup `server` or `servers`
+    if conf.daemon != my_daemonize::Cmd::Stop {
+        if cfg!(feature = "sslocal") {
+            errors.check(check_and_set_servers_from_toml(tbl, conf));
+        } else {
+            errors.check(check_and_set_port_password_from_toml(tbl, conf));
+            errors.check(check_and_set_users_from_toml(tbl, conf));
+        }
+    }
+    errors.into_result()
+}
+
+// fill the defaults depending on other fields, and check the servers
+fn check_config(conf: &mut Config) -> ConfigResult<()> {
+    let mut errors = ConfigErrors::default();
     if (conf.daemon == my_daemonize::Cmd::Start || conf.daemon == my_daemonize::Cmd::Restart) &&
        conf.log_file.is_none() {
         conf.log_file = Some(Config::default_log_path());
@@ -149,10 +326,28 @@ pub fn init_config() -> Result<Config, ConfigError> {
 
     if cfg!(feature = "sslocal") && conf.server_confs.is_none() &&
        conf.daemon != my_daemonize::Cmd::Stop {
-        return Err(ConfigError::MissServerAddress);
+        errors.add(ConfigError::MissServerAddress);
     }
 
-    Ok(conf)
+    if cfg!(feature = "sslocal") {
+        let servers = conf.server_confs.iter().chain(conf.file_server_confs.iter());
+        for server in servers.flat_map(|servers| servers) {
+            errors.check(server.check_password());
+        }
+    } else {
+        errors.check(conf.proxy_conf.check_password());
+        if let Some(ref ports) = conf.port_confs {
+            for port in ports {
+                errors.check(port.check_password());
+            }
+        }
+        if let Some(ref users) = conf.user_confs {
+            for user in users {
+                errors.check(user.proxy_conf.check_password());
+            }
+        }
+    }
+    errors.into_result()
 }
 
 fn get_external_ip() -> ConfigResult<String> {
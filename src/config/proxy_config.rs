@@ -1,12 +1,14 @@
 use std::fmt;
+use std::path::Path;
 
 use rand::{Rng, thread_rng};
-use rustc_serialize::base64::{ToBase64, FromBase64, STANDARD};
+use rustc_serialize::base64::{ToBase64, FromBase64, STANDARD, URL_SAFE};
 
 use crypto::Method;
 use util::slice2str;
-use network::{is_ip, is_hostname};
+use network::{is_ip, is_ipv6, is_hostname};
 use super::{ConfigError, ConfigResult};
+use super::toml::{read_file, quote};
 
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub struct ProxyConfig {
@@ -16,23 +18,48 @@ pub struct ProxyConfig {
     pub password: String,
     pub timeout: u16,
     pub one_time_auth: bool,
+    // SIP003 plugin and its options, only recorded but not started
+    pub plugin: Option<String>,
+    pub plugin_opts: Option<String>,
+    // remarks of the server
+    pub tag: Option<String>,
+    // servers with higher priority are preferred in failover mode
+    pub priority: u32,
+    // share of connections in weighted and least_conn mode
+    pub weight: u32,
 }
 
 impl fmt::Display for ProxyConfig {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
-               "address = \"{}\"\n\
+               "address = {}\n\
                 port = {}\n\
                 method = \"{}\"\n\
-                password = \"{}\"\n\
+                password = {}\n\
                 timeout = {}\n\
                 one_time_auth = {}",
-               self.address,
+               quote(&self.address),
                self.port,
                self.method,
-               self.password,
+               quote(&self.password),
                self.timeout,
-               self.one_time_auth)
+               self.one_time_auth)?;
+        if let Some(ref plugin) = self.plugin {
+            write!(f, "\nplugin = {}", quote(plugin))?;
+        }
+        if let Some(ref plugin_opts) = self.plugin_opts {
+            write!(f, "\nplugin_opts = {}", quote(plugin_opts))?;
+        }
+        if let Some(ref tag) = self.tag {
+            write!(f, "\ntag = {}", quote(tag))?;
+        }
+        if self.priority != 0 {
+            write!(f, "\npriority = {}", self.priority)?;
+        }
+        if self.weight != 1 {
+            write!(f, "\nweight = {}", self.weight)?;
+        }
+        Ok(())
     }
 }
 
@@ -57,8 +84,17 @@ impl Default for ProxyConfig {
             rng.gen::<u16>()
         };
 
-        let method = rng.choose(&Method::all()).cloned().or(Some(Method::aes_256_ctr)).unwrap();
-        let password = rng.gen_ascii_chars().take(4).collect();
+        // never generate a config without encryption
+        let methods: Vec<Method> =
+            Method::all().into_iter().filter(|m| *m != Method::none).collect();
+        let method = rng.choose(&methods).cloned().or(Some(Method::aes_256_ctr)).unwrap();
+        // shadowsocks 2022 requires a base64 encoded PSK
+        let password = if method.is_aead_2022() {
+            let psk: Vec<u8> = rng.gen_iter().take(method.info().0).collect();
+            psk.to_base64(STANDARD)
+        } else {
+            rng.gen_ascii_chars().take(4).collect()
+        };
         let timeout = 60;
         let one_time_auth = false;
 
@@ -69,6 +105,11 @@ impl Default for ProxyConfig {
             password: password,
             timeout: timeout,
             one_time_auth: one_time_auth,
+            plugin: None,
+            plugin_opts: None,
+            tag: None,
+            priority: 0,
+            weight: 1,
         }
     }
 }
@@ -84,28 +125,137 @@ impl ProxyConfig {
         format!("ss://{}", encoded.as_bytes().to_base64(STANDARD))
     }
 
+    /// Encode as SIP002 URI: `ss://base64url(method:password)@host:port/?plugin=...#tag`.
+    /// The user info of shadowsocks 2022 is percent encoded instead of base64 encoded.
+    pub fn sip002_encode(&self) -> String {
+        let userinfo = if self.method.is_aead_2022() {
+            format!("{}:{}",
+                    percent_encode(&self.method.to_string()),
+                    percent_encode(&self.password))
+        } else {
+            format!("{}:{}", self.method, self.password).as_bytes().to_base64(URL_SAFE)
+        };
+        let host = if is_ipv6(&self.address) {
+            format!("[{}]", self.address)
+        } else {
+            self.address.clone()
+        };
+
+        let mut uri = format!("ss://{}@{}:{}", userinfo, host, self.port);
+        if let Some(ref plugin) = self.plugin {
+            let plugin = match self.plugin_opts {
+                Some(ref opts) => format!("{};{}", plugin, opts),
+                None => plugin.clone(),
+            };
+            uri = format!("{}/?plugin={}", uri, percent_encode(&plugin));
+        }
+        if let Some(ref tag) = self.tag {
+            uri = format!("{}#{}", uri, percent_encode(tag));
+        }
+        uri
+    }
+
+    /// Decode both the legacy form `ss://BASE64(method:password@host:port)`
+    /// and the SIP002 form (see `sip002_encode`).
     pub fn base64_decode(&mut self, s: &str) -> ConfigResult<()> {
-        if s.starts_with("ss://") {
-            let s = &s[5..].from_base64()
-                .map_err(|_| ConfigError::Other(format!("decode config failed: {}", s)))?;
-            let s =
-                slice2str(s).ok_or(ConfigError::Other("decode config failed: invalid UTF-8 chars"
-                        .to_string()))?;
-
-            let parts: Vec<&str> = s.rsplitn(2, '@').collect();
-            let port_address: Vec<&str> = parts[0].rsplitn(2, ':').collect();
-            let method_password: Vec<&str> = parts[1].splitn(2, ':').collect();
-
-            self.method = method_password[0].parse::<Method>()
-                .map_err(|_| ConfigError::InvalidMethod(method_password[0].to_string()))?;
-            self.password = method_password[1].to_string();
-            self.address = port_address[1].to_string();
-            self.port = port_address[0].parse::<u16>()
-                .map_err(|_| ConfigError::InvalidNumber(port_address[0].to_string()))?;
-            Ok(())
+        let s = s.trim();
+        if !s.starts_with("ss://") {
+            return Err(decode_error(s));
+        }
+
+        let (uri, tag) = match s[5..].find('#') {
+            Some(i) => (&s[5..5 + i], Some(percent_decode(&s[6 + i..])?)),
+            None => (&s[5..], None),
+        };
+        // base64 never contains `@`
+        if uri.contains('@') {
+            self.sip002_decode(uri)?;
         } else {
-            Err(ConfigError::Other(format!("decode config failed: {}", s)))
+            let decoded = uri.from_base64().map_err(|_| decode_error(s))?;
+            let decoded = slice2str(&decoded).ok_or(decode_error(s))?;
+            let (userinfo, host_port) = split_last(decoded, '@').ok_or(decode_error(s))?;
+            self.set_userinfo(userinfo)?;
+            self.set_host_port(host_port)?;
+        }
+
+        if tag.is_some() {
+            self.tag = tag;
         }
+        Ok(())
+    }
+
+    // `userinfo@host:port/?query`, the fragment is removed already
+    fn sip002_decode(&mut self, uri: &str) -> ConfigResult<()> {
+        let (uri, query) = match uri.find('?') {
+            Some(i) => (&uri[..i], Some(&uri[i + 1..])),
+            None => (uri, None),
+        };
+        let uri = uri.trim_right_matches('/');
+        let (userinfo, host_port) = split_last(uri, '@').ok_or(decode_error(uri))?;
+
+        // user info is either base64 encoded or percent encoded
+        let userinfo = if userinfo.contains(':') || userinfo.contains('%') {
+            percent_decode(userinfo)?
+        } else {
+            let decoded = userinfo.from_base64().map_err(|_| decode_error(userinfo))?;
+            String::from_utf8(decoded).map_err(|_| decode_error(userinfo))?
+        };
+        self.set_userinfo(&userinfo)?;
+        self.set_host_port(host_port)?;
+
+        for param in query.unwrap_or("").split('&') {
+            let mut kv = param.splitn(2, '=');
+            if let (Some("plugin"), Some(val)) = (kv.next(), kv.next()) {
+                let plugin = percent_decode(val)?;
+                let mut parts = plugin.splitn(2, ';');
+                self.plugin = parts.next().map(|p| p.to_string());
+                self.plugin_opts = parts.next().map(|opts| opts.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // `method:password`
+    fn set_userinfo(&mut self, userinfo: &str) -> ConfigResult<()> {
+        let mut parts = userinfo.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(method), Some(password)) => {
+                self.set_method(Some(method))?;
+                self.set_password(Some(password))
+            }
+            _ => Err(decode_error(userinfo)),
+        }
+    }
+
+    // `host:port` or `[ipv6]:port`
+    fn set_host_port(&mut self, host_port: &str) -> ConfigResult<()> {
+        let (host, port) = split_last(host_port, ':').ok_or(decode_error(host_port))?;
+        let host = if host.starts_with('[') && host.ends_with(']') {
+            &host[1..host.len() - 1]
+        } else {
+            host
+        };
+        let port = port.parse::<u16>().map_err(|_| ConfigError::InvalidNumber(port.to_string()))?;
+        self.set_address(Some(host))?;
+        self.port = port;
+        Ok(())
+    }
+
+    pub fn check_password(&self) -> ConfigResult<()> {
+        if self.method.is_aead_2022() {
+            let key_len = self.method.info().0;
+            let is_valid = self.password
+                .from_base64()
+                .map(|psk| psk.len() == key_len)
+                .unwrap_or(false);
+            if !is_valid {
+                let desc = format!("{} requires a base64 encoded {} bytes key",
+                                   self.method,
+                                   key_len);
+                return Err(ConfigError::InvalidPassword(desc));
+            }
+        }
+        Ok(())
     }
 
     pub fn set_address(&mut self, val: Option<&str>) -> ConfigResult<()> {
@@ -146,6 +296,15 @@ impl ProxyConfig {
         Ok(())
     }
 
+    /// Read password from file, the trailing newline is ignored.
+    pub fn set_password_file(&mut self, val: Option<&str>) -> ConfigResult<()> {
+        if let Some(path) = val {
+            let password = read_file(Path::new(path))?;
+            self.password = password.trim_right_matches(|c| c == '\r' || c == '\n').to_string();
+        }
+        Ok(())
+    }
+
     pub fn set_timeout(&mut self, val: Option<i64>) -> ConfigResult<()> {
         if let Some(v) = val {
             if v < 0 {
@@ -163,4 +322,88 @@ impl ProxyConfig {
         }
         Ok(())
     }
+
+    pub fn set_plugin(&mut self, val: Option<&str>) -> ConfigResult<()> {
+        if val.is_some() {
+            self.plugin = val.map(|v| v.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn set_plugin_opts(&mut self, val: Option<&str>) -> ConfigResult<()> {
+        if val.is_some() {
+            self.plugin_opts = val.map(|v| v.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn set_tag(&mut self, val: Option<&str>) -> ConfigResult<()> {
+        if val.is_some() {
+            self.tag = val.map(|v| v.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn set_priority(&mut self, val: Option<i64>) -> ConfigResult<()> {
+        if let Some(v) = val {
+            if v < 0 || v > u32::max_value() as i64 {
+                return Err(ConfigError::OutOfRange(v));
+            } else {
+                self.priority = v as u32;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_weight(&mut self, val: Option<i64>) -> ConfigResult<()> {
+        if let Some(v) = val {
+            if v <= 0 || v > u32::max_value() as i64 {
+                return Err(ConfigError::OutOfRange(v));
+            } else {
+                self.weight = v as u32;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode_error(s: &str) -> ConfigError {
+    ConfigError::Other(format!("decode config failed: {}", s))
+}
+
+// split `s` at the last `sep`
+fn split_last(s: &str, sep: char) -> Option<(&str, &str)> {
+    s.rfind(sep).map(|i| (&s[..i], &s[i + 1..]))
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(s: &str) -> ConfigResult<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let b = s.get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or(decode_error(s))?;
+            decoded.push(b);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| decode_error(s))
 }
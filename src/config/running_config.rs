@@ -6,9 +6,21 @@ use std::path::PathBuf;
 use std::default::Default;
 
 use my_daemonize;
-use mode::Mode;
-use crypto::Method;
-use super::{ConfigError, ConfigResult, ProxyConfig};
+use mode::{Mode, StickyKey};
+use network::Address;
+use crypto::{Method, replay};
+use super::{ConfigError, ConfigResult, ProxyConfig, UserConfig};
+use super::toml::quote;
+
+const REDACTED: &'static str = "<redacted>";
+// seconds between two rounds of health checks
+const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 30;
+// seconds before failover mode switches back to a recovered server
+const DEFAULT_RECOVERY_PERIOD: u64 = 60;
+// consecutive failures before a server is ejected by circuit breaker
+const DEFAULT_BREAKER_FAILURES: u32 = 5;
+// seconds of the first ejection
+const DEFAULT_BREAKER_BACKOFF: u64 = 10;
 
 macro_rules! create_set_fn {
     ($name:ident, $t:ty) => {
@@ -19,6 +31,7 @@ macro_rules! create_set_fn {
     }
 }
 
+#[derive(Clone)]
 pub struct RunningConfig {
     pub daemon: my_daemonize::Cmd,
     pub log_level: i8,
@@ -26,27 +39,100 @@ pub struct RunningConfig {
     pub pid_file: PathBuf,
     pub prefer_ipv6: bool,
     pub mode: Mode,
+    pub replay_filter_capacity: usize,
     pub proxy_conf: Arc<ProxyConfig>,
     pub server_confs: Option<Vec<Arc<ProxyConfig>>>,
+    // SIP008 file which provides servers besides `server_confs`
+    pub servers_file: Option<PathBuf>,
+    // servers read from `servers_file` on load, replaced when the file changes
+    pub file_server_confs: Option<Vec<Arc<ProxyConfig>>>,
+    pub port_confs: Option<Vec<Arc<ProxyConfig>>>,
+    pub user_confs: Option<Vec<Arc<UserConfig>>>,
+    // only sslocal: servers are probed by connecting to the target through them
+    pub health_check_target: Option<Address>,
+    pub health_check_interval: u64,
+    pub recovery_period: u64,
+    pub sticky_key: StickyKey,
+    // only sslocal: 0 disables circuit breaker
+    pub breaker_failures: u32,
+    pub breaker_backoff: u64,
+    // the file which the config is read from, read again on reload
+    pub config_file: Option<PathBuf>,
 }
 
 impl fmt::Display for RunningConfig {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut s = format!("{}\nprefer_ipv6 = {}", self.proxy_conf, self.prefer_ipv6);
+        if self.log_level < 0 {
+            s = format!("{}\nquiet = {}", s, -self.log_level);
+        } else {
+            s = format!("{}\nverbose = {}", s, self.log_level);
+        }
+        // `stop` is a command rather than config
+        let is_daemon = self.daemon == my_daemonize::Cmd::Start ||
+                        self.daemon == my_daemonize::Cmd::Restart;
+        s = format!("{}\ndaemon = {}", s, is_daemon);
         match self.mode {
             Mode::None => {}
+            Mode::Failover => {
+                s = format!("{}\nmode = \"{}\"\nrecovery_period = {}",
+                            s,
+                            self.mode,
+                            self.recovery_period)
+            }
+            Mode::Sticky => {
+                s = format!("{}\nmode = \"{}\"\nsticky_key = \"{}\"",
+                            s,
+                            self.mode,
+                            self.sticky_key)
+            }
             _ => s = format!("{}\nmode = \"{}\"", s, self.mode),
         }
+        if cfg!(feature = "sslocal") {
+            s = format!("{}\nbreaker_failures = {}\nbreaker_backoff = {}",
+                        s,
+                        self.breaker_failures,
+                        self.breaker_backoff);
+        } else {
+            s = format!("{}\nreplay_filter_capacity = {}", s, self.replay_filter_capacity);
+        }
         if let Some(ref p) = self.log_file {
-            s = format!("{}\nlog_file = \"{}\"", s, p.display());
+            s = format!("{}\nlog_file = {}", s, quote(&p.to_string_lossy()));
+        }
+        s = format!("{}\npid_file = {}", s, quote(&self.pid_file.to_string_lossy()));
+        if let Some(ref p) = self.servers_file {
+            s = format!("{}\nservers_file = {}", s, quote(&p.to_string_lossy()));
+        }
+        if let Some(ref target) = self.health_check_target {
+            s = format!("{}\nhealth_check_target = {}\nhealth_check_interval = {}",
+                        s,
+                        quote(&target.to_string()),
+                        self.health_check_interval);
         }
-        s = format!("{}\npid_file = \"{}\"", s, self.pid_file.display());
 
         if let Some(ref servers) = self.server_confs {
             for server in servers {
                 s = format!("{}\n\n[[servers]]\n{}", s, server);
             }
         }
+        if let Some(ref ports) = self.port_confs {
+            s = format!("{}\n\n[port_password]", s);
+            for port in ports {
+                s = format!("{}\n{} = {{ method = \"{}\", password = {}, timeout = {}, \
+                             one_time_auth = {} }}",
+                            s,
+                            port.port,
+                            port.method,
+                            quote(&port.password),
+                            port.timeout,
+                            port.one_time_auth);
+            }
+        }
+        if let Some(ref users) = self.user_confs {
+            for user in users {
+                s = format!("{}\n\n[[users]]\n{}", s, user);
+            }
+        }
 
         write!(f, "{}", s)
     }
@@ -59,17 +145,41 @@ impl fmt::Debug for RunningConfig {
                          pid_file: {:?}\n\
                          prefer_ipv6: {}\n\
                          mode: {:?}\n\
+                         replay_filter_capacity: {}\n\
                          proxy_conf: {{\n\
                          {:?}\n\
                          }}\n\
-                         server_confs: {:?}",
+                         server_confs: {:?}\n\
+                         servers_file: {:?}\n\
+                         file_server_confs: {:?}\n\
+                         port_confs: {:?}\n\
+                         user_confs: {:?}\n\
+                         health_check_target: {:?}\n\
+                         health_check_interval: {}\n\
+                         recovery_period: {}\n\
+                         sticky_key: {:?}\n\
+                         breaker_failures: {}\n\
+                         breaker_backoff: {}\n\
+                         config_file: {:?}",
                         self.log_level,
                         self.log_file,
                         self.pid_file,
                         self.prefer_ipv6,
                         self.mode,
+                        self.replay_filter_capacity,
                         self.proxy_conf,
-                        self.server_confs);
+                        self.server_confs,
+                        self.servers_file,
+                        self.file_server_confs,
+                        self.port_confs,
+                        self.user_confs,
+                        self.health_check_target,
+                        self.health_check_interval,
+                        self.recovery_period,
+                        self.sticky_key,
+                        self.breaker_failures,
+                        self.breaker_backoff,
+                        self.config_file);
 
         write!(f, "{}", s)
     }
@@ -90,8 +200,20 @@ impl Default for RunningConfig {
             pid_file: Self::default_pid_path(),
             prefer_ipv6: false,
             mode: mode,
+            replay_filter_capacity: replay::DEFAULT_CAPACITY,
             proxy_conf: Arc::new(ProxyConfig::default()),
             server_confs: None,
+            servers_file: None,
+            file_server_confs: None,
+            port_confs: None,
+            user_confs: None,
+            health_check_target: None,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            recovery_period: DEFAULT_RECOVERY_PERIOD,
+            sticky_key: StickyKey::Destination,
+            breaker_failures: DEFAULT_BREAKER_FAILURES,
+            breaker_backoff: DEFAULT_BREAKER_BACKOFF,
+            config_file: None,
         }
     }
 }
@@ -137,6 +259,44 @@ impl RunningConfig {
         Self::default_file_path(pid_file)
     }
 
+    /// Configs of the ports to listen on, ssserver listens on every port of
+    /// `port_password` if it is given.
+    pub fn listen_confs(&self) -> Vec<Arc<ProxyConfig>> {
+        match self.port_confs {
+            Some(ref ports) if !cfg!(feature = "sslocal") => ports.clone(),
+            _ => vec![self.proxy_conf.clone()],
+        }
+    }
+
+    /// A copy with all the passwords redacted, for printing.
+    pub fn redacted(&self) -> RunningConfig {
+        fn redact(conf: &Arc<ProxyConfig>) -> Arc<ProxyConfig> {
+            let mut conf = conf.clone();
+            Arc::make_mut(&mut conf).password = REDACTED.to_string();
+            conf
+        }
+
+        let mut conf = self.clone();
+        conf.proxy_conf = redact(&self.proxy_conf);
+        conf.server_confs = self.server_confs
+            .as_ref()
+            .map(|servers| servers.iter().map(redact).collect());
+        conf.file_server_confs = self.file_server_confs
+            .as_ref()
+            .map(|servers| servers.iter().map(redact).collect());
+        conf.port_confs = self.port_confs.as_ref().map(|ports| ports.iter().map(redact).collect());
+        conf.user_confs = self.user_confs.as_ref().map(|users| {
+            users.iter()
+                .map(|user| {
+                    let mut user = (**user).clone();
+                    user.proxy_conf = redact(&user.proxy_conf);
+                    Arc::new(user)
+                })
+                .collect()
+        });
+        conf
+    }
+
     pub fn address(&self) -> &String {
         &self.proxy_conf.address
     }
@@ -211,21 +371,103 @@ impl RunningConfig {
         Ok(())
     }
 
+    pub fn set_replay_filter_capacity(&mut self, val: Option<i64>) -> ConfigResult<()> {
+        if let Some(v) = val {
+            if v < 0 {
+                return Err(ConfigError::OutOfRange(v));
+            } else {
+                self.replay_filter_capacity = v as usize;
+            }
+        }
+        Ok(())
+    }
+
     pub fn set_mode(&mut self, val: Option<&str>) -> ConfigResult<()> {
         if let Some(v) = val {
             match v {
                 "balance" => self.mode = Mode::Balance,
                 "fast" => self.mode = Mode::Fast,
+                "failover" => self.mode = Mode::Failover,
+                "sticky" => self.mode = Mode::Sticky,
+                "weighted" => self.mode = Mode::Weighted,
+                "least_conn" => self.mode = Mode::LeastConn,
                 _ => return Err(ConfigError::InvalidMode(v.to_string())),
             }
         }
         Ok(())
     }
 
+    pub fn set_health_check_target(&mut self, val: Option<&str>) -> ConfigResult<()> {
+        if let Some(v) = val {
+            let target = v.parse::<Address>().map_err(ConfigError::InvalidAddress)?;
+            self.health_check_target = Some(target);
+        }
+        Ok(())
+    }
+
+    pub fn set_health_check_interval(&mut self, val: Option<i64>) -> ConfigResult<()> {
+        if let Some(v) = val {
+            if v <= 0 {
+                return Err(ConfigError::OutOfRange(v));
+            } else {
+                self.health_check_interval = v as u64;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_recovery_period(&mut self, val: Option<i64>) -> ConfigResult<()> {
+        if let Some(v) = val {
+            if v < 0 {
+                return Err(ConfigError::OutOfRange(v));
+            } else {
+                self.recovery_period = v as u64;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_breaker_failures(&mut self, val: Option<i64>) -> ConfigResult<()> {
+        if let Some(v) = val {
+            if v < 0 || v > u32::max_value() as i64 {
+                return Err(ConfigError::OutOfRange(v));
+            } else {
+                self.breaker_failures = v as u32;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_breaker_backoff(&mut self, val: Option<i64>) -> ConfigResult<()> {
+        if let Some(v) = val {
+            if v <= 0 {
+                return Err(ConfigError::OutOfRange(v));
+            } else {
+                self.breaker_backoff = v as u64;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_sticky_key(&mut self, val: Option<&str>) -> ConfigResult<()> {
+        if let Some(v) = val {
+            match v {
+                "destination" => self.sticky_key = StickyKey::Destination,
+                "client" => self.sticky_key = StickyKey::Client,
+                _ => return Err(ConfigError::Other(format!("invalid sticky_key: {}", v))),
+            }
+        }
+        Ok(())
+    }
+
     create_set_fn!(set_address, &str);
     create_set_fn!(set_port, i64);
     create_set_fn!(set_method, &str);
     create_set_fn!(set_password, &str);
+    create_set_fn!(set_password_file, &str);
     create_set_fn!(set_timeout, i64);
     create_set_fn!(set_one_time_auth, bool);
+    create_set_fn!(set_plugin, &str);
+    create_set_fn!(set_plugin_opts, &str);
+    create_set_fn!(set_tag, &str);
 }
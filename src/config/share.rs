@@ -0,0 +1,222 @@
+// Print `ss://` URIs of servers along with their QR codes, so that they can be
+// imported by other clients.
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use qrcode::{QrCode, Color};
+use regex::Regex;
+
+use super::{Config, ProxyConfig, get_all_ips};
+
+// width of the light border around QR code, in modules
+const QUIET_ZONE: usize = 2;
+
+pub fn share_servers(conf: &Config) {
+    let servers = if cfg!(feature = "sslocal") {
+        // every server may be chosen, whatever the mode is
+        let mut servers = conf.server_confs.clone().unwrap_or_default();
+        servers.extend(conf.file_server_confs.iter().flat_map(|s| s).cloned());
+        servers
+    } else {
+        server_side_confs(conf)
+    };
+
+    if servers.is_empty() {
+        println!("no server to share");
+        return;
+    }
+
+    for server in servers {
+        let uri = server.sip002_encode();
+        match server.tag {
+            Some(ref tag) => println!("{} ({}:{})", tag, server.address, server.port),
+            None => println!("{}:{}", server.address, server.port),
+        }
+        println!("{}", uri);
+        match render_qrcode(&uri) {
+            Some(qrcode) => println!("{}", qrcode),
+            None => println!("URI is too long to be encoded as QR code\n"),
+        }
+    }
+}
+
+// The configs seen by clients of ssserver: one for every address, port and user.
+fn server_side_confs(conf: &Config) -> Vec<Arc<ProxyConfig>> {
+    let addresses = shared_addresses(conf.address());
+    let mut confs = vec![];
+
+    for port_conf in conf.listen_confs() {
+        let mut port_confs = vec![];
+        match conf.user_confs {
+            Some(ref users) => {
+                for user in users {
+                    let mut user_conf = (*user.proxy_conf).clone();
+                    user_conf.port = port_conf.port;
+                    user_conf.tag = Some(user.name.clone());
+                    port_confs.push(user_conf);
+                }
+            }
+            None => port_confs.push((*port_conf).clone()),
+        }
+
+        for port_conf in port_confs {
+            for address in &addresses {
+                let mut shared = port_conf.clone();
+                shared.address = address.clone();
+                confs.push(Arc::new(shared));
+            }
+        }
+    }
+
+    confs
+}
+
+// Loopback address is useless for others, and unspecified address
+// (`0.0.0.0` or `::`) is replaced by the addresses of network interfaces.
+fn shared_addresses(address: &str) -> Vec<String> {
+    match IpAddr::from_str(address) {
+        Ok(ip) if ip.is_loopback() => vec![],
+        Ok(ip) if ip.is_unspecified() => interface_addresses(),
+        _ => vec![address.to_string()],
+    }
+}
+
+fn interface_addresses() -> Vec<String> {
+    lazy_static! {
+        // `inet 1.2.3.4`, `inet addr:1.2.3.4` or `IPv4 Address. . . : 1.2.3.4`
+        static ref RE: Regex =
+            Regex::new(r"(?:inet6?\s+(?:addr:\s*)?|IPv[46] Address[.\s]*:\s*)([0-9A-Fa-f.:]+)")
+                .unwrap();
+    }
+
+    let output = get_all_ips().unwrap_or_default();
+    let mut ips: Vec<IpAddr> = vec![];
+    for caps in RE.captures_iter(&output) {
+        if let Some(ip) = caps.at(1).and_then(|ip| IpAddr::from_str(ip).ok()) {
+            if !ip.is_loopback() && !ip.is_unspecified() && !is_link_local(&ip) &&
+               !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+    ips.iter().map(|ip| ip.to_string()).collect()
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => ip.is_link_local(),
+        IpAddr::V6(ref ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+// Two rows of modules are drawn as one line of Unicode half blocks.
+// Light modules are drawn by blocks, which suits terminals with dark background.
+fn render_qrcode(data: &str) -> Option<String> {
+    let code = try_opt!(QrCode::new(data.as_bytes()).ok());
+    let width = code.width();
+    let colors = code.to_colors();
+    let is_dark = |x: usize, y: usize| {
+        if x < QUIET_ZONE || y < QUIET_ZONE {
+            return false;
+        }
+        let (x, y) = (x - QUIET_ZONE, y - QUIET_ZONE);
+        x < width && y < width && colors[y * width + x] == Color::Dark
+    };
+
+    let size = width + 2 * QUIET_ZONE;
+    let mut s = String::new();
+    for row in 0..(size + 1) / 2 {
+        let (top, bottom) = (row * 2, row * 2 + 1);
+        for x in 0..size {
+            s.push(match (is_dark(x, top), is_dark(x, bottom) || bottom >= size) {
+                (false, false) => '█',
+                (false, true) => '▀',
+                (true, false) => '▄',
+                (true, true) => ' ',
+            });
+        }
+        s.push('\n');
+    }
+    Some(s)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use config::{Config, UserConfig};
+    use super::{QUIET_ZONE, render_qrcode, shared_addresses, server_side_confs};
+
+    fn server_config(address: &str) -> Config {
+        let mut conf = Config::default();
+        conf.set_address(Some(address)).unwrap();
+        conf.set_port(Some(8388)).unwrap();
+        conf.set_method(Some("aes-256-gcm")).unwrap();
+        conf.set_password(Some("foo")).unwrap();
+        conf
+    }
+
+    #[test]
+    fn qrcode() {
+        // "hello" fits in a QR code of version 1, which is 21 modules wide
+        let qrcode = render_qrcode("hello").unwrap();
+        let size = 21 + 2 * QUIET_ZONE;
+        let rows: Vec<&str> = qrcode.lines().collect();
+        assert_eq!(rows.len(), (size + 1) / 2);
+        assert!(rows.iter().all(|row| row.chars().count() == size));
+
+        assert!(render_qrcode(&"x".repeat(4096)).is_none());
+    }
+
+    #[test]
+    fn skip_loopback() {
+        assert!(shared_addresses("127.0.0.1").is_empty());
+        assert!(shared_addresses("::1").is_empty());
+        assert_eq!(shared_addresses("1.2.3.4"), vec!["1.2.3.4"]);
+        assert_eq!(shared_addresses("example.com"), vec!["example.com"]);
+        for address in shared_addresses("0.0.0.0") {
+            assert!(address != "127.0.0.1" && address != "::1");
+        }
+
+        assert!(server_side_confs(&server_config("127.0.0.1")).is_empty());
+    }
+
+    #[test]
+    #[cfg(not(feature = "sslocal"))]
+    fn uri_of_every_port_and_user() {
+        let mut conf = server_config("1.2.3.4");
+        conf.port_confs = Some([8389, 8390]
+            .iter()
+            .map(|&port| {
+                let mut port_conf = (*conf.proxy_conf).clone();
+                port_conf.port = port;
+                Arc::new(port_conf)
+            })
+            .collect());
+        let uris: Vec<String> =
+            server_side_confs(&conf).iter().map(|c| c.sip002_encode()).collect();
+        assert_eq!(uris.len(), 2);
+        assert!(uris[0].ends_with("@1.2.3.4:8389"));
+        assert!(uris[1].ends_with("@1.2.3.4:8390"));
+
+        conf.user_confs = Some(["bob", "carol"]
+            .iter()
+            .map(|&name| {
+                let mut user = UserConfig::new(name, &conf.proxy_conf);
+                user.set_password(Some(name)).unwrap();
+                Arc::new(user)
+            })
+            .collect());
+        let servers = server_side_confs(&conf);
+        assert_eq!(servers.len(), 4);
+        for (server, &(port, name)) in servers.iter().zip(&[(8389, "bob"),
+                                                             (8389, "carol"),
+                                                             (8390, "bob"),
+                                                             (8390, "carol")]) {
+            assert_eq!(server.port, port);
+            assert_eq!(server.password, name);
+            assert!(server.sip002_encode().ends_with(&format!(":{}#{}", port, name)));
+        }
+    }
+}
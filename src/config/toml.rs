@@ -1,4 +1,5 @@
 use std::fmt;
+use std::fs;
 use std::fs::{File, OpenOptions};
 use std::sync::Arc;
 use std::path::Path;
@@ -6,7 +7,8 @@ use std::io::prelude::*;
 
 use toml::{Parser, Value, Table};
 
-use super::{ConfigError, ConfigResult, Config, ProxyConfig};
+use super::{ConfigError, ConfigErrors, ConfigResult, Config, ProxyConfig, UserConfig};
+use super::json::{is_json, parse_json, read_servers_file};
 
 #[macro_export]
 macro_rules! tbl_get {
@@ -14,84 +16,293 @@ macro_rules! tbl_get {
     ($t:expr, $name:expr, int) => { $t.get($name).and_then(Value::as_integer) };
     ($t:expr, $name:expr, bool) => { $t.get($name).and_then(Value::as_bool) };
     ($t:expr, $name:expr, slice) => { $t.get($name).and_then(Value::as_slice) };
+    ($t:expr, $name:expr, table) => { $t.get($name).and_then(Value::as_table) };
 }
 
+const KEYS: &'static [&'static str] = &["quiet",
+                                        "verbose",
+                                        "log_file",
+                                        "pid_file",
+                                        "prefer_ipv6",
+                                        "mode",
+                                        "replay_filter_capacity",
+                                        "daemon",
+                                        "address",
+                                        "port",
+                                        "method",
+                                        "password",
+                                        "password_file",
+                                        "timeout",
+                                        "one_time_auth",
+                                        "plugin",
+                                        "plugin_opts",
+                                        "tag",
+                                        "servers",
+                                        "servers_file",
+                                        "health_check_target",
+                                        "health_check_interval",
+                                        "recovery_period",
+                                        "sticky_key",
+                                        "breaker_failures",
+                                        "breaker_backoff",
+                                        "port_password",
+                                        "users"];
+const SERVER_KEYS: &'static [&'static str] = &["address",
+                                               "port",
+                                               "method",
+                                               "password",
+                                               "password_file",
+                                               "timeout",
+                                               "one_time_auth",
+                                               "plugin",
+                                               "plugin_opts",
+                                               "tag",
+                                               "priority",
+                                               "weight"];
+const USER_KEYS: &'static [&'static str] = &["name", "method", "password"];
+const PORT_KEYS: &'static [&'static str] = &["method", "password", "timeout", "one_time_auth"];
+
 pub fn check_and_set_from_toml(tbl: &Table, conf: &mut Config) -> ConfigResult<()> {
-    conf.set_quiet(tbl_get!(tbl, "quiet", int))?;
-    conf.set_verbose(tbl_get!(tbl, "verbose", int))?;
-    conf.set_log_file(tbl_get!(tbl, "log_file", str))?;
-    conf.set_pid_file(tbl_get!(tbl, "pid_file", str))?;
-    conf.set_prefer_ipv6(tbl_get!(tbl, "prefer_ipv6", bool))?;
-    conf.set_mode(tbl_get!(tbl, "mode", str))?;
+    let mut errors = ConfigErrors::default();
+    errors.check(check_keys(tbl, KEYS, "config"));
+
+    errors.check(conf.set_quiet(tbl_get!(tbl, "quiet", int)));
+    errors.check(conf.set_verbose(tbl_get!(tbl, "verbose", int)));
+    errors.check(conf.set_log_file(tbl_get!(tbl, "log_file", str)));
+    errors.check(conf.set_pid_file(tbl_get!(tbl, "pid_file", str)));
+    errors.check(conf.set_prefer_ipv6(tbl_get!(tbl, "prefer_ipv6", bool)));
+    errors.check(conf.set_mode(tbl_get!(tbl, "mode", str)));
+    errors.check(conf.set_replay_filter_capacity(tbl_get!(tbl, "replay_filter_capacity", int)));
+    errors.check(conf.set_health_check_target(tbl_get!(tbl, "health_check_target", str)));
+    errors.check(conf.set_health_check_interval(tbl_get!(tbl, "health_check_interval", int)));
+    errors.check(conf.set_recovery_period(tbl_get!(tbl, "recovery_period", int)));
+    errors.check(conf.set_sticky_key(tbl_get!(tbl, "sticky_key", str)));
+    errors.check(conf.set_breaker_failures(tbl_get!(tbl, "breaker_failures", int)));
+    errors.check(conf.set_breaker_backoff(tbl_get!(tbl, "breaker_backoff", int)));
     if let Some(true) = tbl_get!(tbl, "daemon", bool) {
-        conf.set_daemon(Some("start"))?;
+        errors.check(conf.set_daemon(Some("start")));
     }
 
-    conf.set_address(tbl_get!(tbl, "address", str))?;
-    conf.set_port(tbl_get!(tbl, "port", int))?;
-    conf.set_method(tbl_get!(tbl, "method", str))?;
-    conf.set_password(tbl_get!(tbl, "password", str))?;
-    conf.set_timeout(tbl_get!(tbl, "timeout", int))?;
-    conf.set_one_time_auth(tbl_get!(tbl, "one_time_auth", bool))?;
-    Ok(())
+    errors.check(conf.set_address(tbl_get!(tbl, "address", str)));
+    errors.check(conf.set_port(tbl_get!(tbl, "port", int)));
+    errors.check(conf.set_method(tbl_get!(tbl, "method", str)));
+    errors.check(conf.set_password(tbl_get!(tbl, "password", str)));
+    errors.check(check_exclusive(tbl, "password", "password_file"));
+    errors.check(conf.set_password_file(tbl_get!(tbl, "password_file", str)));
+    errors.check(conf.set_timeout(tbl_get!(tbl, "timeout", int)));
+    errors.check(conf.set_one_time_auth(tbl_get!(tbl, "one_time_auth", bool)));
+    errors.check(conf.set_plugin(tbl_get!(tbl, "plugin", str)));
+    errors.check(conf.set_plugin_opts(tbl_get!(tbl, "plugin_opts", str)));
+    errors.check(conf.set_tag(tbl_get!(tbl, "tag", str)));
+    errors.into_result()
 }
 
+// `servers` and the servers of SIP008 file `servers_file`, the latter are kept apart
+// since they are replaced when the file changes
 pub fn check_and_set_servers_from_toml(tbl: &Table, conf: &mut Config) -> ConfigResult<()> {
-    let servers = tbl_get!(tbl, "servers", slice).ok_or(ConfigError::MissServerAddress)?;
+    let servers_file = tbl_get!(tbl, "servers_file", str);
+    let servers = match tbl_get!(tbl, "servers", slice) {
+        Some(servers) => servers,
+        None if servers_file.is_some() => &[],
+        None => return Err(ConfigError::MissServerAddress),
+    };
     let mut server_confs = vec![];
+    let mut errors = ConfigErrors::default();
 
     for server in servers {
         match *server {
             Value::Table(ref tbl) => {
+                errors.check(check_keys(tbl, SERVER_KEYS, "[[servers]]"));
                 let mut server_conf = conf.proxy_conf.clone();
 
                 {
                     let mut tmp = Arc::make_mut(&mut server_conf);
 
                     if let Some(address) = tbl.get("address") {
-                        tmp.set_address(address.as_str())?;
+                        errors.check(tmp.set_address(address.as_str()));
                     } else {
-                        return Err(ConfigError::MissServerAddress);
+                        errors.add(ConfigError::MissServerAddress);
                     }
                     if let Some(port) = tbl.get("port") {
-                        tmp.set_port(port.as_integer())?;
+                        errors.check(tmp.set_port(port.as_integer()));
                     } else {
-                        return Err(ConfigError::MissServerPort);
+                        errors.add(ConfigError::MissServerPort);
                     }
 
-                    tmp.set_method(tbl_get!(tbl, "method", str))?;
-                    tmp.set_password(tbl_get!(tbl, "password", str))?;
-                    tmp.set_timeout(tbl_get!(tbl, "timeout", int))?;
-                    tmp.set_one_time_auth(tbl_get!(tbl, "one_time_auth", bool))?;
+                    errors.check(tmp.set_method(tbl_get!(tbl, "method", str)));
+                    errors.check(tmp.set_password(tbl_get!(tbl, "password", str)));
+                    errors.check(check_exclusive(tbl, "password", "password_file"));
+                    errors.check(tmp.set_password_file(tbl_get!(tbl, "password_file", str)));
+                    errors.check(tmp.set_timeout(tbl_get!(tbl, "timeout", int)));
+                    errors.check(tmp.set_one_time_auth(tbl_get!(tbl, "one_time_auth", bool)));
+                    errors.check(tmp.set_plugin(tbl_get!(tbl, "plugin", str)));
+                    errors.check(tmp.set_plugin_opts(tbl_get!(tbl, "plugin_opts", str)));
+                    errors.check(tmp.set_tag(tbl_get!(tbl, "tag", str)));
+                    errors.check(tmp.set_priority(tbl_get!(tbl, "priority", int)));
+                    errors.check(tmp.set_weight(tbl_get!(tbl, "weight", int)));
                 }
 
                 server_confs.push(server_conf);
             }
             _ => {
                 let errmsg = format!("server config should be table:\n{}", server);
-                return Err(ConfigError::ParseConfigFailed(errmsg));
+                errors.add(ConfigError::ParseConfigFailed(errmsg));
+            }
+        }
+    }
+
+    // the file is watched by `ServerChooser`, so make the path independent of working directory
+    if let Some(path) = servers_file {
+        match fs::canonicalize(path) {
+            Ok(path) => {
+                conf.file_server_confs = errors.check(read_servers_file(&path,
+                                                                        &conf.proxy_conf));
+                conf.servers_file = Some(path);
             }
+            Err(e) => errors.add(ConfigError::OpenFileFailed(format!("{} ({})", path, e))),
         }
     }
 
     conf.server_confs = Some(server_confs);
-    Ok(())
+    errors.into_result()
+}
+
+// users of ssserver, `method` is inherited from ssserver if missing
+pub fn check_and_set_users_from_toml(tbl: &Table, conf: &mut Config) -> ConfigResult<()> {
+    let users = match tbl_get!(tbl, "users", slice) {
+        Some(users) => users,
+        None => return Ok(()),
+    };
+    let mut user_confs: Vec<Arc<UserConfig>> = vec![];
+    let mut errors = ConfigErrors::default();
+
+    for user in users {
+        match *user {
+            Value::Table(ref tbl) => {
+                errors.check(check_keys(tbl, USER_KEYS, "[[users]]"));
+                let name = match tbl_get!(tbl, "name", str) {
+                    Some(name) => name,
+                    None => {
+                        errors.add(ConfigError::MissUserName);
+                        continue;
+                    }
+                };
+                if user_confs.iter().any(|u| u.name == name) {
+                    errors.add(ConfigError::DuplicateUser(name.to_string()));
+                    continue;
+                }
+
+                let mut user_conf = UserConfig::new(name, &conf.proxy_conf);
+                errors.check(user_conf.set_method(tbl_get!(tbl, "method", str)));
+                errors.check(user_conf.set_password(tbl_get!(tbl, "password", str)));
+                // users are told apart by trying their keys, which only AEAD can verify
+                let method = user_conf.proxy_conf.method;
+                if !method.is_aead() {
+                    let errmsg = format!("{} of user {} is not an AEAD method", method, name);
+                    errors.add(ConfigError::InvalidMethod(errmsg));
+                    continue;
+                }
+                user_confs.push(Arc::new(user_conf));
+            }
+            _ => {
+                let errmsg = format!("user config should be table:\n{}", user);
+                errors.add(ConfigError::ParseConfigFailed(errmsg));
+            }
+        }
+    }
+
+    if !user_confs.is_empty() {
+        conf.user_confs = Some(user_confs);
+    }
+    errors.into_result()
+}
+
+// ports of ssserver, the value of a port is either its password or a table
+// of `password`, `method`, `timeout` and `one_time_auth`, the missing fields
+// are inherited from ssserver
+pub fn check_and_set_port_password_from_toml(tbl: &Table, conf: &mut Config) -> ConfigResult<()> {
+    let ports = match tbl_get!(tbl, "port_password", table) {
+        Some(ports) => ports,
+        None => return Ok(()),
+    };
+    let mut port_confs = vec![];
+    let mut errors = ConfigErrors::default();
+
+    for (port, val) in ports {
+        let port = match port.parse::<i64>() {
+            Ok(port) => port,
+            Err(_) => {
+                errors.add(ConfigError::InvalidNumber(port.clone()));
+                continue;
+            }
+        };
+        let mut port_conf = conf.proxy_conf.clone();
+
+        {
+            let mut tmp = Arc::make_mut(&mut port_conf);
+            errors.check(tmp.set_port(Some(port)));
+
+            match *val {
+                Value::String(ref password) => {
+                    errors.check(tmp.set_password(Some(&password[..])));
+                }
+                Value::Table(ref tbl) => {
+                    errors.check(check_keys(tbl, PORT_KEYS, "[port_password]"));
+                    errors.check(tmp.set_method(tbl_get!(tbl, "method", str)));
+                    errors.check(tmp.set_password(tbl_get!(tbl, "password", str)));
+                    errors.check(tmp.set_timeout(tbl_get!(tbl, "timeout", int)));
+                    errors.check(tmp.set_one_time_auth(tbl_get!(tbl, "one_time_auth", bool)));
+                }
+                _ => {
+                    let errmsg = format!("password of port {} should be string or table:\n{}",
+                                         port,
+                                         val);
+                    errors.add(ConfigError::ParseConfigFailed(errmsg));
+                }
+            }
+        }
+
+        port_confs.push(port_conf);
+    }
+
+    if !port_confs.is_empty() {
+        conf.port_confs = Some(port_confs);
+    }
+    errors.into_result()
+}
+
+fn check_exclusive(tbl: &Table, key1: &str, key2: &str) -> ConfigResult<()> {
+    if tbl.contains_key(key1) && tbl.contains_key(key2) {
+        let errmsg = format!("`{}` and `{}` can't be used together", key1, key2);
+        Err(ConfigError::ParseConfigFailed(errmsg))
+    } else {
+        Ok(())
+    }
+}
+
+// keys out of `known_keys` are mostly typos
+fn check_keys(tbl: &Table, known_keys: &[&str], section: &str) -> ConfigResult<()> {
+    let mut errors = ConfigErrors::default();
+    for key in tbl.keys() {
+        if !known_keys.iter().any(|k| *k == &key[..]) {
+            let errmsg = format!("unknown key `{}` in {}", key, section);
+            errors.add(ConfigError::ParseConfigFailed(errmsg));
+        }
+    }
+    errors.into_result()
+}
+
+/// Quote `s` as a TOML string, `"` and `\` (e.g. of Windows paths) are escaped.
+pub fn quote(s: &str) -> String {
+    Value::String(s.to_string()).to_string()
 }
 
 pub fn read_config<P: AsRef<Path> + fmt::Debug>(config_path: P) -> Result<Table, ConfigError> {
-    let mut f = File::open(&config_path).map_err(|e| {
-            let errmsg = format!("{} ({})", config_path.as_ref().display(), e);
-            ConfigError::OpenFileFailed(errmsg)
-        })?;
+    let input = read_file(config_path.as_ref())?;
 
-    let mut input = String::new();
-    f.read_to_string(&mut input)
-        .map_err(|e| {
-            let errmsg = format!("{} is not valid UTF-8 file ({})",
-                                 config_path.as_ref().display(),
-                                 e);
-            ConfigError::OpenFileFailed(errmsg)
-        })?;
+    if is_json(config_path.as_ref(), &input) {
+        return parse_json(&input);
+    }
 
     let mut parser = Parser::new(&input);
     match parser.parse() {
@@ -106,6 +317,21 @@ pub fn read_config<P: AsRef<Path> + fmt::Debug>(config_path: P) -> Result<Table,
     }
 }
 
+pub fn read_file(path: &Path) -> ConfigResult<String> {
+    let mut f = File::open(path).map_err(|e| {
+            let errmsg = format!("{} ({})", path.display(), e);
+            ConfigError::OpenFileFailed(errmsg)
+        })?;
+
+    let mut input = String::new();
+    f.read_to_string(&mut input)
+        .map_err(|e| {
+            let errmsg = format!("{} is not valid UTF-8 file ({})", path.display(), e);
+            ConfigError::OpenFileFailed(errmsg)
+        })?;
+    Ok(input)
+}
+
 pub fn save_if_not_exists(conf: &Config) {
     let path = Config::default_config_path();
     if path.exists() {
@@ -125,3 +351,156 @@ pub fn append_to_default_config(server_conf: &ProxyConfig) {
         f.write_all(content.as_bytes())
     });
 }
+
+#[cfg(test)]
+mod test {
+    use toml::{Parser, Table};
+
+    use std::path::Path;
+
+    use my_daemonize;
+    use config::{Config, ConfigError};
+    use crypto::Method;
+    use super::{KEYS, check_keys, check_exclusive, check_and_set_from_toml,
+                check_and_set_servers_from_toml, check_and_set_users_from_toml,
+                check_and_set_port_password_from_toml};
+
+    fn parse(input: &str) -> Table {
+        Parser::new(input).parse().unwrap()
+    }
+
+    #[test]
+    fn port_password() {
+        let tbl = parse(r#"
+            method = "aes-256-gcm"
+            password = "foo"
+            timeout = 60
+
+            [port_password]
+            8388 = "bar"
+
+            [port_password.8389]
+            method = "chacha20-ietf-poly1305"
+            password = "baz"
+            timeout = 30
+        "#);
+        let mut conf = Config::default();
+        check_and_set_from_toml(&tbl, &mut conf).unwrap();
+        check_and_set_port_password_from_toml(&tbl, &mut conf).unwrap();
+
+        let ports = conf.port_confs.unwrap();
+        assert_eq!(ports.len(), 2);
+        let port = ports.iter().find(|p| p.port == 8388).unwrap();
+        assert_eq!(port.password, "bar");
+        assert_eq!(port.method, Method::aes_256_gcm);
+        assert_eq!(port.timeout, 60);
+        let port = ports.iter().find(|p| p.port == 8389).unwrap();
+        assert_eq!(port.password, "baz");
+        assert_eq!(port.method, Method::chacha20_ietf_poly1305);
+        assert_eq!(port.timeout, 30);
+    }
+
+    #[test]
+    fn invalid_port_password() {
+        let tbl = parse(r#"
+            [port_password]
+            foo = "bar"
+            70000 = "bar"
+            8388 = 1
+            8389 = "bar"
+        "#);
+        let mut conf = Config::default();
+        match check_and_set_port_password_from_toml(&tbl, &mut conf) {
+            Err(ConfigError::Multiple(errors)) => assert_eq!(errors.len(), 3),
+            _ => panic!("all the invalid ports should be reported"),
+        }
+    }
+
+    #[test]
+    fn users_need_aead() {
+        let tbl = parse(r#"
+            method = "aes-256-cfb"
+            password = "foo"
+
+            [[users]]
+            name = "alice"
+            method = "aes-256-gcm"
+            password = "bar"
+
+            [[users]]
+            name = "bob"
+            password = "baz"
+
+            [[users]]
+            name = "carol"
+            method = "rc4-md5"
+            password = "qux"
+        "#);
+        let mut conf = Config::default();
+        check_and_set_from_toml(&tbl, &mut conf).unwrap();
+        match check_and_set_users_from_toml(&tbl, &mut conf) {
+            Err(ConfigError::Multiple(errors)) => assert_eq!(errors.len(), 2),
+            _ => panic!("every user of a stream method should be reported"),
+        }
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let tbl = parse("address = \"::1\"\nadress = \"::1\"\npasswd = \"foo\"");
+        match check_keys(&tbl, KEYS, "config") {
+            Err(ConfigError::Multiple(errors)) => assert_eq!(errors.len(), 2),
+            _ => panic!("every unknown key should be reported"),
+        }
+        assert!(check_keys(&parse("address = \"::1\""), KEYS, "config").is_ok());
+    }
+
+    #[test]
+    fn report_all_errors() {
+        let tbl = parse(r#"
+            mode = "fastest"
+            timeout = -1
+            port = 65536
+            passwd = "foo"
+        "#);
+        let mut conf = Config::default();
+        match check_and_set_from_toml(&tbl, &mut conf) {
+            Err(ConfigError::Multiple(errors)) => assert_eq!(errors.len(), 4),
+            _ => panic!("all the errors should be reported"),
+        }
+    }
+
+    #[test]
+    fn print_config() {
+        let mut conf = Config::default();
+        conf.set_tag(Some("say \"hi\"")).unwrap();
+        conf.set_pid_file(Some(r"C:\Users\ss\ssserver.pid")).unwrap();
+        conf.set_quiet(Some(2)).unwrap();
+        conf.set_daemon(Some("start")).unwrap();
+
+        let tbl = parse(&conf.to_string());
+        check_keys(&tbl, KEYS, "config").unwrap();
+        let mut printed = Config::default();
+        check_and_set_from_toml(&tbl, &mut printed).unwrap();
+        assert_eq!(printed.proxy_conf, conf.proxy_conf);
+        assert_eq!(printed.pid_file, Path::new(r"C:\Users\ss\ssserver.pid"));
+        assert_eq!(printed.log_level, -2);
+        assert!(printed.daemon == my_daemonize::Cmd::Start);
+    }
+
+    #[test]
+    fn exclusive_keys() {
+        let tbl = parse("password = \"foo\"\npassword_file = \"/etc/ss/password\"");
+        assert!(check_exclusive(&tbl, "password", "password_file").is_err());
+        let tbl = parse("password_file = \"/etc/ss/password\"");
+        assert!(check_exclusive(&tbl, "password", "password_file").is_ok());
+
+        let tbl = parse(r#"
+            [[servers]]
+            address = "1.2.3.4"
+            port = 8388
+            password = "foo"
+            password_file = "/etc/ss/password"
+        "#);
+        assert!(check_and_set_servers_from_toml(&tbl, &mut Config::default()).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt;
+use std::sync::Arc;
+
+use super::{ConfigError, ConfigResult, ProxyConfig};
+use super::toml::quote;
+
+/// A user of ssserver, users share the address and port of ssserver
+/// but have their own password and method.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct UserConfig {
+    pub name: String,
+    pub proxy_conf: Arc<ProxyConfig>,
+}
+
+impl fmt::Display for UserConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "name = {}\n\
+                method = \"{}\"\n\
+                password = {}",
+               quote(&self.name),
+               self.proxy_conf.method,
+               quote(&self.proxy_conf.password))
+    }
+}
+
+impl fmt::Debug for UserConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl UserConfig {
+    /// Address, port and timeout are inherited from `server_conf`.
+    pub fn new(name: &str, server_conf: &Arc<ProxyConfig>) -> UserConfig {
+        UserConfig {
+            name: name.to_string(),
+            proxy_conf: server_conf.clone(),
+        }
+    }
+
+    pub fn set_method(&mut self, val: Option<&str>) -> ConfigResult<()> {
+        Arc::make_mut(&mut self.proxy_conf).set_method(val)
+    }
+
+    pub fn set_password(&mut self, val: Option<&str>) -> ConfigResult<()> {
+        match val {
+            Some(_) => Arc::make_mut(&mut self.proxy_conf).set_password(val),
+            None => Err(ConfigError::MissUserPassword(self.name.clone())),
+        }
+    }
+}
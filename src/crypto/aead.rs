@@ -0,0 +1,316 @@
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+use rust_crypto::aessafe::{AesSafe128Encryptor, AesSafe256Encryptor};
+use rust_crypto::chacha20::ChaCha20;
+use rust_crypto::ghash::Ghash;
+use rust_crypto::poly1305::Poly1305;
+use rust_crypto::mac::Mac;
+use rust_crypto::sha1::Sha1;
+use rust_crypto::hkdf::{hkdf_extract, hkdf_expand};
+use rust_crypto::symmetriccipher::{BlockEncryptor, SynchronousStreamCipher};
+use rust_crypto::util::fixed_time_eq;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use rust_crypto::aes::KeySize;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use rust_crypto::aesni::AesNiEncryptor;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use rust_crypto::util::supports_aesni;
+
+use super::{Method, aead2022};
+use super::cipher::AeadCipher;
+use super::error::{Error, CipherResult};
+
+pub const TAG_LEN: usize = 16;
+pub const NONCE_LEN: usize = 12;
+pub const XNONCE_LEN: usize = 24;
+const SHA1_LEN: usize = 20;
+const SUBKEY_INFO: &'static [u8] = b"ss-subkey";
+
+// Every session derives its own subkey from the master key and a random salt,
+// then seals each chunk with a little endian counter as nonce.
+pub struct CryptoAeadCipher {
+    method: Method,
+    subkey: Vec<u8>,
+    // the key is expanded once for the session
+    aes_gcm: Option<AesGcm>,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl CryptoAeadCipher {
+    pub fn new(method: Method, key: &[u8], salt: &[u8]) -> CipherResult<CryptoAeadCipher> {
+        if !method.is_aead() {
+            return Err(Error::UnsupportMethod(method));
+        }
+
+        let subkey = if method.is_aead_2022() {
+            aead2022::session_subkey(key, salt)
+        } else {
+            hkdf_sha1(key, salt)
+        };
+
+        let aes_gcm = if is_aes_gcm(method) {
+            Some(AesGcm::new(&subkey))
+        } else {
+            None
+        };
+
+        Ok(CryptoAeadCipher {
+            method: method,
+            subkey: subkey,
+            aes_gcm: aes_gcm,
+            nonce: [0u8; NONCE_LEN],
+        })
+    }
+
+    fn increase_nonce(&mut self) {
+        for b in self.nonce.iter_mut() {
+            *b = b.wrapping_add(1);
+            if *b != 0 {
+                break;
+            }
+        }
+    }
+}
+
+impl AeadCipher for CryptoAeadCipher {
+    fn seal(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()> {
+        match self.aes_gcm {
+            Some(ref c) => {
+                let nonce = &self.nonce;
+                seal_into(input, output, |input, data, tag| c.encrypt(nonce, input, data, tag));
+            }
+            None => seal_with(self.method, &self.subkey, &self.nonce, input, output),
+        }
+        self.increase_nonce();
+        Ok(())
+    }
+
+    fn open(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()> {
+        let res = match self.aes_gcm {
+            Some(ref c) => {
+                let nonce = &self.nonce;
+                open_into(input, output, |data, output, tag| c.decrypt(nonce, data, output, tag))
+            }
+            None => open_with(self.method, &self.subkey, &self.nonce, input, output),
+        };
+        self.increase_nonce();
+        res
+    }
+}
+
+fn is_aes_gcm(method: Method) -> bool {
+    match method {
+        Method::aes_128_gcm | Method::aes_256_gcm | Method::blake3_aes_128_gcm |
+        Method::blake3_aes_256_gcm => true,
+        _ => false,
+    }
+}
+
+/// Seal `input` by `key` and `nonce` directly, then append `encrypted data + tag` to `output`.
+/// A 24 bytes nonce means XChaCha20-Poly1305.
+pub fn seal_with(method: Method, key: &[u8], nonce: &[u8], input: &[u8], output: &mut Vec<u8>) {
+    seal_into(input, output, |input, data, tag| if is_aes_gcm(method) {
+        AesGcm::new(key).encrypt(nonce, input, data, tag)
+    } else {
+        ChaCha20Poly1305Ietf::new(key, nonce).encrypt(input, data, tag)
+    });
+}
+
+/// Verify `input` by `key` and `nonce` directly, then append decrypted data to `output`.
+pub fn open_with(method: Method,
+                 key: &[u8],
+                 nonce: &[u8],
+                 input: &[u8],
+                 output: &mut Vec<u8>)
+                 -> CipherResult<()> {
+    open_into(input, output, |data, output, tag| if is_aes_gcm(method) {
+        AesGcm::new(key).decrypt(nonce, data, output, tag)
+    } else {
+        ChaCha20Poly1305Ietf::new(key, nonce).decrypt(data, output, tag)
+    })
+}
+
+fn seal_into<F>(input: &[u8], output: &mut Vec<u8>, encrypt: F)
+    where F: FnOnce(&[u8], &mut [u8], &mut [u8])
+{
+    let offset = output.len();
+    output.resize(offset + input.len() + TAG_LEN, 0);
+
+    let (data, tag) = output[offset..].split_at_mut(input.len());
+    encrypt(input, data, tag);
+}
+
+fn open_into<F>(input: &[u8], output: &mut Vec<u8>, decrypt: F) -> CipherResult<()>
+    where F: FnOnce(&[u8], &mut [u8], &[u8]) -> bool
+{
+    if input.len() < TAG_LEN {
+        return Err(Error::AuthenticationFailed);
+    }
+
+    let (data, tag) = input.split_at(input.len() - TAG_LEN);
+    let offset = output.len();
+    output.resize(offset + data.len(), 0);
+
+    if decrypt(data, &mut output[offset..], tag) {
+        Ok(())
+    } else {
+        output.truncate(offset);
+        Err(Error::AuthenticationFailed)
+    }
+}
+
+// AES-GCM (96 bits nonce) on an expanded key. `AesGcm::new` of `rust-crypto` boxes two
+// CTR ciphers and expands the key again for every nonce, i.e. for every chunk.
+struct AesGcm {
+    aes: Box<BlockEncryptor + Send>,
+    ghash: Ghash,
+}
+
+impl AesGcm {
+    fn new(key: &[u8]) -> AesGcm {
+        let aes = new_aes_encryptor(key);
+        let mut hash_key = [0u8; 16];
+        aes.encrypt_block(&[0u8; 16], &mut hash_key);
+
+        AesGcm {
+            aes: aes,
+            ghash: Ghash::new(&hash_key),
+        }
+    }
+
+    // the first counter block `nonce | 1` is used to mask the tag,
+    // the data is encrypted from `nonce | 2`
+    fn counter_block(nonce: &[u8], counter: u32) -> [u8; 16] {
+        let mut block = [0u8; 16];
+        block[..NONCE_LEN].copy_from_slice(nonce);
+        BigEndian::write_u32(&mut block[NONCE_LEN..], counter);
+        block
+    }
+
+    fn apply_keystream(&self, nonce: &[u8], input: &[u8], output: &mut [u8]) {
+        let mut keystream = [0u8; 16];
+        for (i, (input, output)) in input.chunks(16).zip(output.chunks_mut(16)).enumerate() {
+            self.aes.encrypt_block(&AesGcm::counter_block(nonce, i as u32 + 2), &mut keystream);
+            for ((o, i), k) in output.iter_mut().zip(input).zip(&keystream) {
+                *o = i ^ k;
+            }
+        }
+    }
+
+    // associated data is always empty in shadowsocks
+    fn compute_tag(&self, nonce: &[u8], ciphertext: &[u8], tag: &mut [u8]) {
+        let mut mask = [0u8; 16];
+        self.aes.encrypt_block(&AesGcm::counter_block(nonce, 1), &mut mask);
+        let hash = self.ghash.input_c(ciphertext).result();
+        for ((t, h), m) in tag.iter_mut().zip(&hash).zip(&mask) {
+            *t = h ^ m;
+        }
+    }
+
+    fn encrypt(&self, nonce: &[u8], input: &[u8], output: &mut [u8], tag: &mut [u8]) {
+        self.apply_keystream(nonce, input, output);
+        self.compute_tag(nonce, output, tag);
+    }
+
+    fn decrypt(&self, nonce: &[u8], input: &[u8], output: &mut [u8], tag: &[u8]) -> bool {
+        let mut expected = [0u8; TAG_LEN];
+        self.compute_tag(nonce, input, &mut expected);
+        if fixed_time_eq(&expected, tag) {
+            self.apply_keystream(nonce, input, output);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn new_aes_encryptor(key: &[u8]) -> Box<BlockEncryptor + Send> {
+    if supports_aesni() {
+        let key_size = if key.len() == 16 {
+            KeySize::KeySize128
+        } else {
+            KeySize::KeySize256
+        };
+        Box::new(AesNiEncryptor::new(key_size, key))
+    } else {
+        new_aes_safe_encryptor(key)
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn new_aes_encryptor(key: &[u8]) -> Box<BlockEncryptor + Send> {
+    new_aes_safe_encryptor(key)
+}
+
+fn new_aes_safe_encryptor(key: &[u8]) -> Box<BlockEncryptor + Send> {
+    if key.len() == 16 {
+        Box::new(AesSafe128Encryptor::new(key))
+    } else {
+        Box::new(AesSafe256Encryptor::new(key))
+    }
+}
+
+// ChaCha20-Poly1305 as defined in RFC 7539 (96 bits nonce). `rust-crypto` only
+// ships the original construction which uses a 64 bits nonce.
+//
+// With a 24 bytes nonce it's XChaCha20-Poly1305, the keystream of `rust-crypto`'s
+// XChaCha20 is the same as the IETF one as long as the counter is less than 2^32.
+struct ChaCha20Poly1305Ietf {
+    cipher: ChaCha20,
+    mac: Poly1305,
+}
+
+impl ChaCha20Poly1305Ietf {
+    fn new(key: &[u8], nonce: &[u8]) -> ChaCha20Poly1305Ietf {
+        let mut cipher = if nonce.len() == XNONCE_LEN {
+            ChaCha20::new_xchacha20(key, nonce)
+        } else {
+            ChaCha20::new(key, nonce)
+        };
+        // the first block is used to generate poly1305 key
+        let mut mac_key = [0u8; 64];
+        cipher.process(&[0u8; 64], &mut mac_key);
+
+        ChaCha20Poly1305Ietf {
+            cipher: cipher,
+            mac: Poly1305::new(&mac_key[..32]),
+        }
+    }
+
+    // associated data is always empty in shadowsocks
+    fn compute_tag(&mut self, ciphertext: &[u8], tag: &mut [u8]) {
+        let padding = (16 - ciphertext.len() % 16) % 16;
+        self.mac.input(ciphertext);
+        self.mac.input(&[0u8; 16][..padding]);
+
+        let mut lens = [0u8; 16];
+        LittleEndian::write_u64(&mut lens[8..], ciphertext.len() as u64);
+        self.mac.input(&lens);
+        self.mac.raw_result(tag);
+    }
+
+    fn encrypt(&mut self, input: &[u8], output: &mut [u8], tag: &mut [u8]) {
+        self.cipher.process(input, output);
+        self.compute_tag(output, tag);
+    }
+
+    fn decrypt(&mut self, input: &[u8], output: &mut [u8], tag: &[u8]) -> bool {
+        let mut expected = [0u8; TAG_LEN];
+        self.compute_tag(input, &mut expected);
+        if fixed_time_eq(&expected, tag) {
+            self.cipher.process(input, output);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// HKDF_SHA1(key, salt, "ss-subkey")
+fn hkdf_sha1(key: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut prk = [0u8; SHA1_LEN];
+    hkdf_extract(Sha1::new(), salt, key, &mut prk);
+    let mut subkey = vec![0u8; key.len()];
+    hkdf_expand(Sha1::new(), &prk, SUBKEY_INFO, &mut subkey);
+    subkey
+}
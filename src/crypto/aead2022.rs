@@ -0,0 +1,156 @@
+// Shadowsocks 2022 Edition (SIP022).
+//
+// TCP request stream:
+//
+// +------+------------------------+---------------------------+--------------------+-----+
+// | salt | encrypted fixed header | encrypted variable header | encrypted chunks   | ... |
+// +------+------------------------+---------------------------+--------------------+-----+
+//
+//  fixed header (request):  type(0) | timestamp(u64) | length of variable header(u16)
+//  fixed header (response): type(1) | timestamp(u64) | request salt | length of first chunk(u16)
+//  variable header:         socks5 address | padding length(u16) | padding | initial payload
+//
+// UDP packet (AES):
+//
+// +-------------------------------------+---------------------------------+
+// | AES-ECB(PSK, session id | packet id) | AEAD(session subkey, body)      |
+// +-------------------------------------+---------------------------------+
+//
+// UDP packet (ChaCha20):
+//
+// +-------+--------------------------------------------------------------+
+// | nonce | XChaCha20-Poly1305(PSK, session id | packet id | body)        |
+// +-------+--------------------------------------------------------------+
+//
+//  body (request):  type(0) | timestamp(u64) | padding length(u16) | padding |
+//                    socks5 address | payload
+//  body (response): type(1) | timestamp(u64) | client session id | padding length(u16) | padding |
+//                    socks5 address | payload
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use blake3;
+use rand::{Rng, OsRng, thread_rng};
+use rustc_serialize::base64::FromBase64;
+use rust_crypto::aessafe::{AesSafe128Encryptor, AesSafe128Decryptor, AesSafe256Encryptor,
+                           AesSafe256Decryptor};
+use rust_crypto::symmetriccipher::{BlockEncryptor, BlockDecryptor};
+
+use super::error::{Error, CipherResult};
+
+pub const HEADER_TYPE_CLIENT: u8 = 0;
+pub const HEADER_TYPE_SERVER: u8 = 1;
+pub const MAX_CHUNK_LEN: usize = 0xFFFF;
+pub const MAX_PADDING_LEN: usize = 900;
+pub const SESSION_ID_LEN: usize = 8;
+pub const PACKET_ID_LEN: usize = 8;
+const MAX_TIME_DIFF: u64 = 30;
+// seconds to remember the salts and the UDP sessions for replay protection
+pub const SALT_TTL: u64 = 60;
+const SESSION_SUBKEY_CONTEXT: &'static str = "shadowsocks 2022 session subkey";
+
+/// Decode the base64 encoded PSK, the length must be equal to key length.
+pub fn decode_psk(password: &str, key_len: usize) -> CipherResult<Vec<u8>> {
+    let psk = password.from_base64()
+        .map_err(|_| Error::InvalidPsk(format!("{} is not valid base64", password)))?;
+    if psk.len() == key_len {
+        Ok(psk)
+    } else {
+        Err(Error::InvalidPsk(format!("PSK should be {} bytes, but got {}", key_len, psk.len())))
+    }
+}
+
+// BLAKE3_DERIVE_KEY("shadowsocks 2022 session subkey", PSK | salt)
+pub fn session_subkey(psk: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut hasher = blake3::Hasher::new_derive_key(SESSION_SUBKEY_CONTEXT);
+    hasher.update(psk);
+    hasher.update(salt);
+    let mut subkey = vec![0u8; psk.len()];
+    hasher.finalize_xof().fill(&mut subkey);
+    subkey
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn check_timestamp(timestamp: u64) -> bool {
+    let now = now();
+    let diff = if now > timestamp {
+        now - timestamp
+    } else {
+        timestamp - now
+    };
+
+    if diff > MAX_TIME_DIFF {
+        warn!("timestamp of shadowsocks 2022 header is {}s away from now", diff);
+        false
+    } else {
+        true
+    }
+}
+
+/// A sliding window of the last 64 packet ids of a UDP session.
+pub struct ReplayWindow {
+    max_id: u64,
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        ReplayWindow {
+            max_id: 0,
+            bitmap: 0,
+        }
+    }
+
+    /// Return `false` if `id` has been seen or is too old.
+    pub fn check_and_insert(&mut self, id: u64) -> bool {
+        if id > self.max_id || self.bitmap == 0 {
+            let shift = id - self.max_id;
+            self.bitmap = if shift >= 64 { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.max_id = id;
+            true
+        } else {
+            let offset = self.max_id - id;
+            if offset >= 64 || self.bitmap & (1 << offset) != 0 {
+                false
+            } else {
+                self.bitmap |= 1 << offset;
+                true
+            }
+        }
+    }
+}
+
+// padding is only necessary if there is no initial payload
+pub fn gen_padding_len(payload_len: usize) -> usize {
+    if payload_len == 0 {
+        thread_rng().gen_range(1, MAX_PADDING_LEN + 1)
+    } else {
+        0
+    }
+}
+
+pub fn gen_random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    let _ = OsRng::new().map(|mut rng| rng.fill_bytes(&mut bytes));
+    bytes
+}
+
+// encrypt `session id | packet id` by AES-ECB with PSK
+pub fn encrypt_separate_header(psk: &[u8], header: &[u8], output: &mut [u8]) {
+    if psk.len() == 16 {
+        AesSafe128Encryptor::new(psk).encrypt_block(header, output);
+    } else {
+        AesSafe256Encryptor::new(psk).encrypt_block(header, output);
+    }
+}
+
+pub fn decrypt_separate_header(psk: &[u8], header: &[u8], output: &mut [u8]) {
+    if psk.len() == 16 {
+        AesSafe128Decryptor::new(psk).decrypt_block(header, output);
+    } else {
+        AesSafe256Decryptor::new(psk).decrypt_block(header, output);
+    }
+}
@@ -1,30 +1,46 @@
 use std::sync::Arc;
 
-use super::error::CipherResult;
+use super::error::{Error, CipherResult};
 use super::{Method, Mode};
 use super::methods::BelongLib;
+use super::registry;
 use super::crypto_lib::CryptoCipher;
+use super::aead::CryptoAeadCipher;
 #[cfg(feature = "openssl")]
 use super::openssl_lib::OpensslCipher;
 
+enum Inner {
+    Stream(Box<StreamCipher + 'static>),
+    Aead(Box<AeadCipher + 'static>),
+}
+
 pub struct Cipher {
+    method: Method,
     key: Arc<Vec<u8>>,
     iv: Vec<u8>,
-    inner: Box<StreamCipher + 'static>,
+    inner: Inner,
 }
 
 impl Cipher {
+    /// For AEAD methods `iv` is the salt used to derive the session subkey.
     pub fn new(method: Method, mode: Mode, key: Arc<Vec<u8>>, iv: Vec<u8>) -> CipherResult<Cipher> {
-        let cipher: Box<StreamCipher> = match method.belong_lib() {
-            BelongLib::Crypto => Box::new(CryptoCipher::new(method, mode, &key, &iv)?),
-            #[cfg(feature = "openssl")]
-            BelongLib::Openssl => Box::new(OpensslCipher::new(method, mode, &key, &iv)?),
+        let inner = if method.is_aead() {
+            Inner::Aead(Box::new(CryptoAeadCipher::new(method, &key, &iv)?))
+        } else {
+            let cipher: Box<StreamCipher> = match method.belong_lib() {
+                BelongLib::Crypto => Box::new(CryptoCipher::new(method, mode, &key, &iv)?),
+                #[cfg(feature = "openssl")]
+                BelongLib::Openssl => Box::new(OpensslCipher::new(method, mode, &key, &iv)?),
+                BelongLib::Registry => registry::new_cipher(method, mode, &key, &iv)?,
+            };
+            Inner::Stream(cipher)
         };
 
         Ok(Cipher {
+            method: method,
             key: key,
             iv: iv,
-            inner: cipher,
+            inner: inner,
         })
     }
 
@@ -47,14 +63,54 @@ impl Cipher {
     pub fn iv_len(&self) -> usize {
         self.iv.len()
     }
+
+    pub fn is_aead(&self) -> bool {
+        self.method.is_aead()
+    }
 }
 
 impl StreamCipher for Cipher {
-    fn update(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()> {
-        self.inner.update(input, output)
+    fn apply_keystream(&mut self, data: &mut [u8]) -> CipherResult<()> {
+        match self.inner {
+            Inner::Stream(ref mut c) => c.apply_keystream(data),
+            Inner::Aead(_) => Err(Error::UnsupportMethod(self.method)),
+        }
+    }
+}
+
+impl AeadCipher for Cipher {
+    fn seal(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()> {
+        match self.inner {
+            Inner::Aead(ref mut c) => c.seal(input, output),
+            Inner::Stream(_) => Err(Error::UnsupportMethod(self.method)),
+        }
+    }
+
+    fn open(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()> {
+        match self.inner {
+            Inner::Aead(ref mut c) => c.open(input, output),
+            Inner::Stream(_) => Err(Error::UnsupportMethod(self.method)),
+        }
     }
 }
 
 pub trait StreamCipher {
-    fn update(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()>;
+    /// Encrypt or decrypt `data` in place.
+    fn apply_keystream(&mut self, data: &mut [u8]) -> CipherResult<()>;
+
+    /// Same as `apply_keystream`, but the result is stored in `output`.
+    fn update(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()> {
+        output.clear();
+        output.extend_from_slice(input);
+        self.apply_keystream(output)
+    }
+}
+
+/// Unlike `StreamCipher`, the result is appended to `output`
+/// and every call consumes a nonce.
+pub trait AeadCipher {
+    /// Append `encrypted data + tag` to `output`.
+    fn seal(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()>;
+    /// Verify and append decrypted data to `output`.
+    fn open(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()>;
 }
@@ -1,35 +1,47 @@
 use rust_crypto::aes;
+use rust_crypto::aessafe::{AesSafe128Encryptor, AesSafe192Encryptor, AesSafe256Encryptor};
 use rust_crypto::rc4::Rc4;
+use rust_crypto::md5::Md5;
+use rust_crypto::digest::Digest;
 use rust_crypto::hc128::Hc128;
 use rust_crypto::salsa20::Salsa20;
 use rust_crypto::chacha20::ChaCha20;
 use rust_crypto::sosemanuk::Sosemanuk;
-use rust_crypto::symmetriccipher::SynchronousStreamCipher;
+use rust_crypto::symmetriccipher::{BlockEncryptor, SynchronousStreamCipher};
 
 use super::{Method, Mode};
 use super::cipher::StreamCipher;
-use super::error::CipherResult;
-#[cfg(feature = "openssl")]
-use super::error::Error;
+use super::error::{Error, CipherResult};
 
 pub struct CryptoCipher {
     inner: Box<SynchronousStreamCipher + 'static>,
 }
 
 impl CryptoCipher {
-    pub fn new(method: Method, _mode: Mode, key: &[u8], iv: &[u8]) -> CipherResult<CryptoCipher> {
+    pub fn new(method: Method, mode: Mode, key: &[u8], iv: &[u8]) -> CipherResult<CryptoCipher> {
         let cipher = match method {
+            Method::none => Box::new(Plain),
             Method::aes_128_ctr => aes::ctr(aes::KeySize::KeySize128, key, iv),
             Method::aes_192_ctr => aes::ctr(aes::KeySize::KeySize192, key, iv),
             Method::aes_256_ctr => aes::ctr(aes::KeySize::KeySize256, key, iv),
             Method::rc4 => Box::new(Rc4::new(key)),
+            Method::rc4_md5 => Box::new(Rc4::new(&md5_key(key, iv))),
             Method::hc128 => Box::new(Hc128::new(key, iv)),
             Method::salsa20 => Box::new(Salsa20::new(key, iv)),
             Method::xsalsa20 => Box::new(Salsa20::new_xsalsa20(key, iv)),
-            Method::chacha20 => Box::new(ChaCha20::new(key, iv)),
+            Method::chacha20 |
+            Method::chacha20_ietf => Box::new(ChaCha20::new(key, iv)),
             Method::xchacha20 => Box::new(ChaCha20::new_xchacha20(key, iv)),
             Method::sosemanuk => Box::new(Sosemanuk::new(key, iv)),
-            #[cfg(feature = "openssl")]
+            Method::aes_128_cfb |
+            Method::aes_192_cfb |
+            Method::aes_256_cfb => Box::new(AesCfb::new(128, mode, key, iv)?),
+            Method::aes_128_cfb1 |
+            Method::aes_192_cfb1 |
+            Method::aes_256_cfb1 => Box::new(AesCfb::new(1, mode, key, iv)?),
+            Method::aes_128_cfb8 |
+            Method::aes_192_cfb8 |
+            Method::aes_256_cfb8 => Box::new(AesCfb::new(8, mode, key, iv)?),
             m => return Err(Error::UnsupportMethod(m)),
         };
 
@@ -37,10 +49,142 @@ impl CryptoCipher {
     }
 }
 
+// `process` can't take the same slice as input and output
+const BUF_SIZE: usize = 4096;
+
 impl StreamCipher for CryptoCipher {
+    fn apply_keystream(&mut self, data: &mut [u8]) -> CipherResult<()> {
+        let mut buf = [0u8; BUF_SIZE];
+        for chunk in data.chunks_mut(BUF_SIZE) {
+            let buf = &mut buf[..chunk.len()];
+            self.inner.process(chunk, buf);
+            chunk.copy_from_slice(buf);
+        }
+        Ok(())
+    }
+
     fn update(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()> {
         output.resize(input.len(), 0);
         self.inner.process(input, output);
         Ok(())
     }
 }
+
+// pass data through without encryption
+struct Plain;
+
+impl SynchronousStreamCipher for Plain {
+    fn process(&mut self, input: &[u8], output: &mut [u8]) {
+        output.copy_from_slice(input);
+    }
+}
+
+// MD5(key | iv)
+fn md5_key(key: &[u8], iv: &[u8]) -> [u8; 16] {
+    let mut md5 = Md5::new();
+    md5.input(key);
+    md5.input(iv);
+    let mut res = [0u8; 16];
+    md5.result(&mut res);
+    res
+}
+
+const AES_BLOCK_SIZE: usize = 16;
+
+// AES in CFB mode with 1, 8 or 128 bits segment, compatible with OpenSSL.
+struct AesCfb {
+    cipher: Box<BlockEncryptor + 'static>,
+    segment_bits: usize,
+    is_encrypt: bool,
+    register: [u8; AES_BLOCK_SIZE],
+    keystream: [u8; AES_BLOCK_SIZE],
+    offset: usize,
+}
+
+impl AesCfb {
+    fn new(segment_bits: usize, mode: Mode, key: &[u8], iv: &[u8]) -> CipherResult<AesCfb> {
+        if iv.len() != AES_BLOCK_SIZE {
+            let errmsg = format!("AES-CFB needs {} bytes, but got {}", AES_BLOCK_SIZE, iv.len());
+            return Err(Error::InvalidIv(errmsg));
+        }
+        let cipher: Box<BlockEncryptor> = match key.len() {
+            16 => Box::new(AesSafe128Encryptor::new(key)),
+            24 => Box::new(AesSafe192Encryptor::new(key)),
+            _ => Box::new(AesSafe256Encryptor::new(key)),
+        };
+        let is_encrypt = match mode {
+            Mode::Encrypt => true,
+            Mode::Decrypt => false,
+        };
+
+        let mut cfb = AesCfb {
+            cipher: cipher,
+            segment_bits: segment_bits,
+            is_encrypt: is_encrypt,
+            register: [0u8; AES_BLOCK_SIZE],
+            keystream: [0u8; AES_BLOCK_SIZE],
+            offset: 0,
+        };
+        cfb.register.copy_from_slice(iv);
+        cfb.encrypt_register();
+        Ok(cfb)
+    }
+
+    fn encrypt_register(&mut self) {
+        self.cipher.encrypt_block(&self.register, &mut self.keystream);
+    }
+
+    // the register is the ciphertext block, it's encrypted when full
+    fn process_cfb128(&mut self, input: u8) -> u8 {
+        let output = input ^ self.keystream[self.offset];
+        self.register[self.offset] = if self.is_encrypt { output } else { input };
+        self.offset += 1;
+        if self.offset == AES_BLOCK_SIZE {
+            self.encrypt_register();
+            self.offset = 0;
+        }
+        output
+    }
+
+    // shift one ciphertext byte into the register
+    fn process_cfb8(&mut self, input: u8) -> u8 {
+        self.encrypt_register();
+        let output = input ^ self.keystream[0];
+        for i in 0..AES_BLOCK_SIZE - 1 {
+            self.register[i] = self.register[i + 1];
+        }
+        self.register[AES_BLOCK_SIZE - 1] = if self.is_encrypt { output } else { input };
+        output
+    }
+
+    // shift one ciphertext bit into the register, from the most significant bit
+    fn process_cfb1(&mut self, input: u8) -> u8 {
+        let mut output = 0;
+        for bit in (0..8).rev() {
+            self.encrypt_register();
+            let input_bit = (input >> bit) & 1;
+            let output_bit = input_bit ^ (self.keystream[0] >> 7);
+            output |= output_bit << bit;
+
+            for i in 0..AES_BLOCK_SIZE - 1 {
+                self.register[i] = (self.register[i] << 1) | (self.register[i + 1] >> 7);
+            }
+            let last = AES_BLOCK_SIZE - 1;
+            let cipher_bit = if self.is_encrypt { output_bit } else { input_bit };
+            self.register[last] = (self.register[last] << 1) | cipher_bit;
+        }
+        output
+    }
+}
+
+impl SynchronousStreamCipher for AesCfb {
+    fn process(&mut self, input: &[u8], output: &mut [u8]) {
+        for (i, o) in input.iter().zip(output.iter_mut()) {
+            *o = match self.segment_bits {
+                1 => self.process_cfb1(*i),
+                8 => self.process_cfb8(*i),
+                _ => self.process_cfb128(*i),
+            };
+        }
+    }
+}
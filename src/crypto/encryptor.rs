@@ -1,26 +1,45 @@
+use std::cmp;
+use std::mem;
 use std::sync::Arc;
 use std::sync::Mutex;
 use lru_time_cache::LruCache;
 
-use rand::{Rng, OsRng};
+use rand::{self, Rng, OsRng};
 use rust_crypto::util::fixed_time_eq;
 use rust_crypto::mac::Mac;
 use rust_crypto::md5::Md5;
 use rust_crypto::sha1::Sha1;
 use rust_crypto::hmac::Hmac;
 use rust_crypto::digest::Digest;
+use byteorder::{ByteOrder, NetworkEndian};
 use network::{NetworkReadBytes, NetworkWriteBytes};
+use socks5::parse_header;
 
 use super::error::CipherResult;
 use super::{Method, Cipher, Mode};
-use super::cipher::StreamCipher;
+use super::cipher::{StreamCipher, AeadCipher};
+use super::aead::{self, TAG_LEN, XNONCE_LEN};
+use super::aead2022::{self, ReplayWindow};
+use super::replay::ReplayFilter;
 
 const BUF_SIZE: usize = 64 * 1024;
 const KEY_CACHE_SIZE: usize = 1024;
 const HMAC_SHA1_LEN: usize = 10;
+const AEAD_MAX_CHUNK_LEN: usize = 0x3FFF;
+
+/// The side an `Encryptor` works for, the headers of shadowsocks 2022 depend on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Client,
+    Server,
+}
 
 pub struct Encryptor {
+    role: Role,
     ota_helper: Option<OtaHelper>,
+    // the data sent (or received) after the OTA request header is packed as OTA chunks
+    is_ota_sender: bool,
+    is_ota_receiver: bool,
     is_iv_sent: bool,
     key: Arc<Vec<u8>>,
     iv_len: usize,
@@ -28,6 +47,19 @@ pub struct Encryptor {
     method: Method,
     cipher: Cipher,
     decipher: Option<Cipher>,
+    aead_buf: Vec<u8>,
+    aead_chunk_len: Option<usize>,
+    spare_buf: Vec<u8>,
+    is_probing: bool,
+    // only ssserver
+    replay_filter: Option<ReplayFilter>,
+    is_header_received: bool,
+    is_padding_pending: bool,
+    udp_session_id: u64,
+    udp_packet_id: u64,
+    udp_peer_session_id: Option<u64>,
+    // only client: packet ids received from the session of server
+    udp_peer_window: Option<(u64, ReplayWindow)>,
 }
 
 // First packet format:
@@ -35,14 +67,34 @@ pub struct Encryptor {
 // +-----------+----------------+
 // | cipher iv | encrypted data |
 // +-----------+----------------+
+//
+// AEAD stream format:
+//
+// +------+---------------------------+---------------------------+-----+
+// | salt | encrypted length + tag    | encrypted payload + tag   | ... |
+// +------+---------------------------+---------------------------+-----+
+// |  Var |          2 + 16           |        Var + 16           | ... |
+// +------+---------------------------+---------------------------+-----+
+//
+// Shadowsocks 2022 format is described in `aead2022.rs`.
 impl Encryptor {
+    /// For shadowsocks 2022 methods `password` is the base64 encoded PSK.
     pub fn new(password: &str, method: Method) -> CipherResult<Encryptor> {
-        let (key, iv) = gen_key_iv(password, method);
+        let (key, iv) = if method.is_aead_2022() {
+            let (key_len, iv_len) = method.info();
+            (Arc::new(aead2022::decode_psk(password, key_len)?),
+             aead2022::gen_random_bytes(iv_len))
+        } else {
+            gen_key_iv(password, method)
+        };
         let iv_len = iv.len();
         let cipher = Cipher::new(method, Mode::Encrypt, key.clone(), iv)?;
 
         Ok(Encryptor {
+            role: Role::Client,
             ota_helper: None,
+            is_ota_sender: false,
+            is_ota_receiver: false,
             is_iv_sent: false,
             key: key,
             iv_len: iv_len,
@@ -50,10 +102,44 @@ impl Encryptor {
             method: method,
             cipher: cipher,
             decipher: None,
+            aead_buf: Vec::new(),
+            aead_chunk_len: None,
+            spare_buf: Vec::new(),
+            is_probing: false,
+            replay_filter: None,
+            is_header_received: false,
+            is_padding_pending: false,
+            udp_session_id: rand::random(),
+            udp_packet_id: 0,
+            udp_peer_session_id: None,
+            udp_peer_window: None,
         })
     }
 
-    #[cfg(feature = "sslocal")]
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// `Role::Client` by default, the server receives requests and sends responses.
+    pub fn set_role(&mut self, role: Role) {
+        self.role = role;
+    }
+
+    /// Session id of the client who sent the last shadowsocks 2022 UDP request.
+    pub fn udp_peer_session_id(&self) -> Option<u64> {
+        self.udp_peer_session_id
+    }
+
+    pub fn set_udp_peer_session_id(&mut self, session_id: Option<u64>) {
+        self.udp_peer_session_id = session_id;
+    }
+
+    /// Reject the first packet whose IV (or salt, or the packet id of shadowsocks 2022 UDP)
+    /// is in `filter`, then remember it.
+    pub fn set_replay_filter(&mut self, filter: ReplayFilter) {
+        self.replay_filter = Some(filter);
+    }
+
     fn cipher_iv(&self) -> &[u8] {
         self.cipher.iv()
     }
@@ -68,6 +154,25 @@ impl Encryptor {
                       header_length: usize,
                       data: &[u8])
                       -> Option<Vec<u8>> {
+        self.pack_ota_request(addr_type, header_length, data)
+    }
+
+    #[cfg(not(feature = "sslocal"))]
+    pub fn enable_ota(&mut self,
+                      addr_type: u8,
+                      header_length: usize,
+                      data: &[u8])
+                      -> Option<Vec<u8>> {
+        self.unpack_ota_request(addr_type, header_length, data)
+    }
+
+    /// The client side of OTA: sign the request header (`data[..header_length]`) and pack
+    /// the following data, then the data encrypted later is packed as OTA chunks.
+    pub fn pack_ota_request(&mut self,
+                            addr_type: u8,
+                            header_length: usize,
+                            data: &[u8])
+                            -> Option<Vec<u8>> {
         let mut ota = OtaHelper::new();
         // OTA header
         let mut header = vec![];
@@ -82,6 +187,7 @@ impl Encryptor {
         let sha1 = ota.hmac_sha1(&header, &key);
         header.extend_from_slice(&sha1);
 
+        self.is_ota_sender = true;
         let data = &data[header_length..];
         if data.is_empty() {
             self.ota_helper = Some(ota);
@@ -100,12 +206,16 @@ impl Encryptor {
         }
     }
 
-    #[cfg(not(feature = "sslocal"))]
-    pub fn enable_ota(&mut self,
-                      _addr_type: u8,
-                      header_length: usize,
-                      data: &[u8])
-                      -> Option<Vec<u8>> {
+    /// The server side of OTA: verify the request header (`data[..header_length]`) and
+    /// unpack the following data, then the data decrypted later is unpacked as OTA chunks.
+    pub fn unpack_ota_request(&mut self,
+                              _addr_type: u8,
+                              header_length: usize,
+                              data: &[u8])
+                              -> Option<Vec<u8>> {
+        if data.len() < header_length + HMAC_SHA1_LEN {
+            return None;
+        }
         let mut ota = OtaHelper::new();
         // verify OTA header
         let header = &data[..header_length];
@@ -121,6 +231,7 @@ impl Encryptor {
         let res = ota.unpack_chunk(&data[header_length + HMAC_SHA1_LEN..],
                                    try_opt!(self.decipher_iv()));
         self.ota_helper = Some(ota);
+        self.is_ota_receiver = true;
         res
     }
 
@@ -134,6 +245,16 @@ impl Encryptor {
         Some(data.to_vec())
     }
 
+    #[cfg(feature = "disable-encrypt")]
+    fn raw_encrypt_in_place(&mut self, _data: &mut [u8]) -> Option<()> {
+        Some(())
+    }
+
+    #[cfg(feature = "disable-encrypt")]
+    fn raw_decrypt_in_place(&mut self, _data: &mut [u8]) -> Option<()> {
+        Some(())
+    }
+
     #[cfg(not(feature = "disable-encrypt"))]
     pub fn raw_encrypt(&mut self, data: &[u8]) -> Option<Vec<u8>> {
         let mut output = vec![];
@@ -148,64 +269,354 @@ impl Encryptor {
         })
     }
 
+    #[cfg(not(feature = "disable-encrypt"))]
+    fn raw_encrypt_in_place(&mut self, data: &mut [u8]) -> Option<()> {
+        self.cipher.apply_keystream(data).ok()
+    }
+
+    #[cfg(not(feature = "disable-encrypt"))]
+    fn raw_decrypt_in_place(&mut self, data: &mut [u8]) -> Option<()> {
+        self.decipher.as_mut().and_then(|decipher| decipher.apply_keystream(data).ok())
+    }
+
     pub fn encrypt(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut data = data.to_vec();
+        self.encrypt_in_place(&mut data).map(|_| data)
+    }
+
+    /// An empty result means more data is needed to complete a AEAD chunk.
+    pub fn decrypt(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut data = data.to_vec();
+        self.decrypt_in_place(&mut data).map(|_| data)
+    }
+
+    /// Same as `encrypt`, but the result is stored in `data` to reuse its memory.
+    pub fn encrypt_in_place(&mut self, data: &mut Vec<u8>) -> Option<()> {
+        if self.method.is_aead() {
+            let mut output = self.take_spare_buf();
+            let res = self.aead_encrypt(data, &mut output);
+            self.put_spare_buf(data, output);
+            return res;
+        }
+
+        // if this is a OTA request
+        if self.is_iv_sent && self.is_ota_sender {
+            let mut ota = try_opt!(self.ota_helper.take());
+            *data = try_opt!(ota.pack_chunk(data, self.cipher.iv()));
+            self.ota_helper = Some(ota);
+        }
+        try_opt!(self.raw_encrypt_in_place(data));
+
         // if first request
         if !self.is_iv_sent {
             self.is_iv_sent = true;
-            match self.raw_encrypt(data) {
-                Some(ref mut encrypted) => {
-                    let mut result = vec![];
-                    result.extend_from_slice(self.cipher.iv());
-                    result.append(encrypted);
+            let mut output = self.take_spare_buf();
+            output.extend_from_slice(self.cipher.iv());
+            output.extend_from_slice(data);
+            self.put_spare_buf(data, output);
+        }
+        Some(())
+    }
 
-                    Some(result)
-                }
-                _ => None,
+    /// Same as `decrypt`, but the result is stored in `data` to reuse its memory.
+    pub fn decrypt_in_place(&mut self, data: &mut Vec<u8>) -> Option<()> {
+        if self.method.is_aead() {
+            self.aead_buf.extend_from_slice(data);
+            data.clear();
+            return self.aead_decrypt(data);
+        }
+
+        // if first request
+        if self.decipher.is_none() {
+            let iv_len = self.iv_len;
+            if data.len() <= iv_len || self.is_replayed(&data[..iv_len]) {
+                return None;
             }
+            let iv = Vec::from(&data[..iv_len]);
+            self.decipher = Cipher::new(self.method, Mode::Decrypt, self.key.clone(), iv).ok();
+            data.drain(..iv_len);
+            self.raw_decrypt_in_place(data)
         } else {
+            try_opt!(self.raw_decrypt_in_place(data));
             // if this is a OTA request
-            if cfg!(feature = "sslocal") && self.ota_helper.is_some() {
-                let mut ota = self.ota_helper.take().unwrap();
-                let data = &try_opt!(ota.pack_chunk(data, &self.cipher.iv()));
+            if self.is_ota_receiver {
+                let mut ota = try_opt!(self.ota_helper.take());
+                *data = try_opt!(ota.unpack_chunk(data, try_opt!(self.decipher_iv())));
                 self.ota_helper = Some(ota);
-                self.raw_encrypt(data)
-            } else {
-                self.raw_encrypt(data)
             }
+            Some(())
         }
     }
 
-    pub fn decrypt(&mut self, data: &[u8]) -> Option<Vec<u8>> {
-        // if first request
+    // The spare buffer is used when the result can't be produced in place,
+    // it's swapped with `data` after that, so no allocation after warming up.
+    fn take_spare_buf(&mut self) -> Vec<u8> {
+        let mut buf = mem::replace(&mut self.spare_buf, Vec::new());
+        buf.clear();
+        buf
+    }
+
+    fn put_spare_buf(&mut self, data: &mut Vec<u8>, mut output: Vec<u8>) {
+        mem::swap(data, &mut output);
+        self.spare_buf = output;
+    }
+
+    fn aead_encrypt(&mut self, data: &[u8], output: &mut Vec<u8>) -> Option<()> {
+        let max_chunk_len = self.aead_max_chunk_len();
+        let nchunks = data.len() / max_chunk_len + 1;
+        output.reserve(self.iv_len + data.len() + nchunks * (2 + 2 * TAG_LEN));
+        let mut data = data;
+        if !self.is_iv_sent {
+            self.is_iv_sent = true;
+            output.extend_from_slice(self.cipher.iv());
+            if self.method.is_aead_2022() {
+                data = try_opt!(self.aead2022_seal_header(data, output));
+            }
+        }
+
+        for chunk in data.chunks(max_chunk_len) {
+            let mut len = [0u8; 2];
+            NetworkEndian::write_u16(&mut len, chunk.len() as u16);
+            try_opt!(self.cipher.seal(&len, output).ok());
+            try_opt!(self.cipher.seal(chunk, output).ok());
+        }
+
+        Some(())
+    }
+
+    /// Whether the data received so far ends in the middle of a AEAD chunk (or header).
+    pub fn is_chunk_pending(&self) -> bool {
+        !self.aead_buf.is_empty() || self.aead_chunk_len.is_some() ||
+        (self.method.is_aead_2022() && self.decipher.is_some() && !self.is_header_received)
+    }
+
+    // ssserver rejects the IV (or salt) which has been used recently,
+    // methods without IV have nothing to check.
+    fn is_replayed(&self, iv: &[u8]) -> bool {
+        if self.is_probing || iv.is_empty() {
+            return false;
+        }
+        match self.replay_filter {
+            Some(ref filter) if self.method.is_aead_2022() => !filter.check_and_insert_salt(iv),
+            Some(ref filter) => !filter.check_and_insert(iv),
+            None => false,
+        }
+    }
+
+    /// Same as `decrypt`, but the IV (or salt) is not remembered. It's used to find out
+    /// the key of the first packet by trying, `check_replay` should be called on the match.
+    pub(crate) fn probe(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        self.is_probing = true;
+        let res = self.decrypt(data);
+        self.is_probing = false;
+        res
+    }
+
+    /// Same as `probe`, but for UDP packets.
+    pub(crate) fn probe_udp(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        self.is_probing = true;
+        let res = self.decrypt_udp(data);
+        self.is_probing = false;
+        res
+    }
+
+    /// Remember the IV (or salt) of the first packet `data` which has been probed,
+    /// return `false` if it has been seen recently.
+    pub(crate) fn check_replay(&self, data: &[u8], is_udp: bool) -> bool {
+        // UDP packets of shadowsocks 2022 have no salt, the packet id is checked on decryption
+        if is_udp && self.method.is_aead_2022() {
+            return true;
+        }
+        let iv = &data[..cmp::min(self.iv_len, data.len())];
+        !self.is_replayed(iv)
+    }
+
+    fn aead_max_chunk_len(&self) -> usize {
+        if self.method.is_aead_2022() {
+            aead2022::MAX_CHUNK_LEN
+        } else {
+            AEAD_MAX_CHUNK_LEN
+        }
+    }
+
+    // Seal the fixed header and the first chunk, then return the rest of data.
+    fn aead2022_seal_header<'a>(&mut self,
+                                data: &'a [u8],
+                                result: &mut Vec<u8>)
+                                -> Option<&'a [u8]> {
+        let mut fixed_header = Vec::with_capacity(1 + 8 + self.iv_len + 2);
+        let mut first_chunk = Vec::with_capacity(cmp::min(data.len(), aead2022::MAX_CHUNK_LEN));
+        let rest = match self.role {
+            // the response refers to the salt of request
+            Role::Server => {
+                let decipher = try_opt!(self.decipher.as_ref());
+                let len = cmp::min(data.len(), aead2022::MAX_CHUNK_LEN);
+                first_chunk.extend_from_slice(&data[..len]);
+
+                fixed_header.push(aead2022::HEADER_TYPE_SERVER);
+                pack!(u64, fixed_header, aead2022::now());
+                fixed_header.extend_from_slice(decipher.iv());
+                pack!(u16, fixed_header, len as u16);
+                &data[len..]
+            }
+            Role::Client => {
+                if data.is_empty() {
+                    return None;
+                }
+                let header_len = try_opt!(parse_header(data)).3;
+                let payload_len = data.len() - header_len;
+                let padding_len = aead2022::gen_padding_len(payload_len);
+                let max_payload_len = aead2022::MAX_CHUNK_LEN - header_len - 2 - padding_len;
+                let payload_len = cmp::min(payload_len, max_payload_len);
+
+                first_chunk.extend_from_slice(&data[..header_len]);
+                pack!(u16, first_chunk, padding_len as u16);
+                first_chunk.extend_from_slice(&aead2022::gen_random_bytes(padding_len));
+                first_chunk.extend_from_slice(&data[header_len..header_len + payload_len]);
+
+                fixed_header.push(aead2022::HEADER_TYPE_CLIENT);
+                pack!(u64, fixed_header, aead2022::now());
+                pack!(u16, fixed_header, first_chunk.len() as u16);
+                &data[header_len + payload_len..]
+            }
+        };
+
+        try_opt!(self.cipher.seal(&fixed_header, result).ok());
+        try_opt!(self.cipher.seal(&first_chunk, result).ok());
+        Some(rest)
+    }
+
+    // Verify the fixed header and return the length of the first chunk.
+    fn aead2022_check_header(&self, header: &[u8], is_response: bool) -> Option<usize> {
+        let header_type = if is_response {
+            aead2022::HEADER_TYPE_SERVER
+        } else {
+            aead2022::HEADER_TYPE_CLIENT
+        };
+        if header[0] != header_type {
+            warn!("unexpected shadowsocks 2022 header type {}", header[0]);
+            return None;
+        }
+        if !aead2022::check_timestamp(unpack!(u64, &header[1..9])) {
+            return None;
+        }
+        if is_response && !fixed_time_eq(&header[9..9 + self.iv_len], self.cipher.iv()) {
+            warn!("request salt of shadowsocks 2022 response mismatch");
+            return None;
+        }
+
+        Some(unpack!(u16, &header[header.len() - 2..]) as usize)
+    }
+
+    // decrypt `aead_buf` and append the result to `output`
+    fn aead_decrypt(&mut self, output: &mut Vec<u8>) -> Option<()> {
+        // wait a complete salt
         if self.decipher.is_none() {
-            let iv_len = self.iv_len;
-            if data.len() > iv_len {
-                let iv = Vec::from(&data[..iv_len]);
-                self.decipher = Cipher::new(self.method, Mode::Decrypt, self.key.clone(), iv).ok();
-                self.raw_decrypt(&data[iv_len..])
+            if self.aead_buf.len() < self.iv_len {
+                return Some(());
+            }
+            let salt = Vec::from(&self.aead_buf[..self.iv_len]);
+            if self.is_replayed(&salt) {
+                return None;
+            }
+            let decipher = Cipher::new(self.method, Mode::Decrypt, self.key.clone(), salt);
+            self.decipher = Some(try_opt!(decipher.ok()));
+            self.aead_buf.drain(..self.iv_len);
+        }
+
+        // wait a complete fixed header
+        if self.method.is_aead_2022() && !self.is_header_received {
+            let is_response = self.role == Role::Client;
+            let header_len = if is_response {
+                1 + 8 + self.iv_len + 2
             } else {
-                None
+                1 + 8 + 2
+            };
+            if self.aead_buf.len() < header_len + TAG_LEN {
+                return Some(());
             }
-        } else {
-            let mut decrypted = try_opt!(self.raw_decrypt(data));
-            // if this is a OTA request
-            if !cfg!(feature = "sslocal") && self.ota_helper.is_some() {
-                let mut ota = self.ota_helper.take().unwrap();
-                decrypted = try_opt!(ota.unpack_chunk(&decrypted, try_opt!(self.decipher_iv())));
-                self.ota_helper = Some(ota);
+
+            let mut header = Vec::with_capacity(header_len);
+            {
+                let decipher = self.decipher.as_mut().unwrap();
+                let encrypted_header = &self.aead_buf[..header_len + TAG_LEN];
+                try_opt!(decipher.open(encrypted_header, &mut header).ok());
+            }
+            self.aead_buf.drain(..header_len + TAG_LEN);
+
+            self.aead_chunk_len = Some(try_opt!(self.aead2022_check_header(&header, is_response)));
+            self.is_header_received = true;
+            self.is_padding_pending = !is_response;
+        }
+
+        let max_chunk_len = self.aead_max_chunk_len();
+        output.reserve(self.aead_buf.len());
+        let mut offset = 0;
+
+        // to avoid the stupid borrow error
+        {
+            let decipher = self.decipher.as_mut().unwrap();
+            let buf = &self.aead_buf;
+            loop {
+                let chunk_len = match self.aead_chunk_len {
+                    Some(chunk_len) => chunk_len,
+                    None => {
+                        if buf.len() - offset < 2 + TAG_LEN {
+                            break;
+                        }
+                        // decrypt the length to `output` temporarily
+                        let start = output.len();
+                        let encrypted_len = &buf[offset..offset + 2 + TAG_LEN];
+                        try_opt!(decipher.open(encrypted_len, output).ok());
+                        offset += 2 + TAG_LEN;
+
+                        let chunk_len = NetworkEndian::read_u16(&output[start..]) as usize;
+                        output.truncate(start);
+                        if chunk_len > max_chunk_len {
+                            return None;
+                        }
+                        self.aead_chunk_len = Some(chunk_len);
+                        chunk_len
+                    }
+                };
+
+                if buf.len() - offset < chunk_len + TAG_LEN {
+                    break;
+                }
+                let chunk = &buf[offset..offset + chunk_len + TAG_LEN];
+                let start = output.len();
+                try_opt!(decipher.open(chunk, output).ok());
+                offset += chunk_len + TAG_LEN;
+                self.aead_chunk_len = None;
+
+                if self.is_padding_pending {
+                    self.is_padding_pending = false;
+                    try_opt!(strip_padding(output, start));
+                }
             }
-            Some(decrypted)
         }
+
+        self.aead_buf.drain(..offset);
+        // less than a chunk is left, so the memory of a large read is released
+        let max_pending_len = 2 + TAG_LEN + max_chunk_len + TAG_LEN;
+        if self.aead_buf.capacity() > 2 * max_pending_len {
+            self.aead_buf.shrink_to_fit();
+        }
+        Some(())
     }
 
     fn raw_encrypt_udp(&self, key: Arc<Vec<u8>>, iv: &[u8], data: &[u8]) -> Option<Vec<u8>> {
         let mut cipher = try_opt!(Cipher::new(self.method, Mode::Encrypt, key, Vec::from(iv)).ok());
-        let mut encrypted = vec![0u8; data.len()];
-        try_opt!(cipher.update(data, &mut encrypted).ok());
-
-        let mut res = vec![];
+        let mut res = Vec::with_capacity(iv.len() + data.len() + TAG_LEN);
         res.extend_from_slice(iv);
-        res.extend_from_slice(&encrypted);
+
+        if cipher.is_aead() {
+            try_opt!(cipher.seal(data, &mut res).ok());
+        } else {
+            let mut encrypted = vec![0u8; data.len()];
+            try_opt!(cipher.update(data, &mut encrypted).ok());
+            res.extend_from_slice(&encrypted);
+        }
         Some(res)
     }
 
@@ -214,28 +625,189 @@ impl Encryptor {
                        key: Arc<Vec<u8>>,
                        data: &[u8])
                        -> Option<(Cipher, Vec<u8>)> {
+        if data.len() < iv_len {
+            return None;
+        }
+
         let iv = &data[..iv_len];
         let mut decipher = try_opt!(Cipher::new(self.method, Mode::Decrypt, key, Vec::from(iv))
             .ok());
-        let mut decrypted = vec![0u8; data.len() - iv_len];
-        try_opt!(decipher.update(&data[iv_len..], &mut decrypted).ok());
+        let mut decrypted = Vec::with_capacity(data.len() - iv_len);
+        if decipher.is_aead() {
+            try_opt!(decipher.open(&data[iv_len..], &mut decrypted).ok());
+        } else {
+            try_opt!(decipher.update(&data[iv_len..], &mut decrypted).ok());
+        }
 
         Some((decipher, decrypted))
     }
 
     pub fn encrypt_udp(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        if self.method.is_aead_2022() {
+            return self.aead2022_encrypt_udp(data);
+        }
+
         let (key, iv) = gen_key_iv(&self.password, self.method);
         self.raw_encrypt_udp(key, &iv, data)
     }
 
     pub fn decrypt_udp(&mut self, data: &[u8]) -> Option<Vec<u8>> {
-        let (key, _iv) = gen_key_iv(&self.password, self.method);
-        self.raw_decrypt_udp(_iv.len(), key, data).and_then(|(decipher, data)| {
+        if self.method.is_aead_2022() {
+            return self.aead2022_decrypt_udp(data);
+        }
+
+        let (key, iv) = gen_key_iv(&self.password, self.method);
+        if data.len() < iv.len() || self.is_replayed(&data[..iv.len()]) {
+            return None;
+        }
+        self.raw_decrypt_udp(iv.len(), key, data).and_then(|(decipher, data)| {
             self.decipher = Some(decipher);
             Some(data)
         })
     }
 
+    fn aead2022_encrypt_udp(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut header = Vec::with_capacity(aead2022::SESSION_ID_LEN + aead2022::PACKET_ID_LEN);
+        pack!(u64, header, self.udp_session_id);
+        pack!(u64, header, self.udp_packet_id);
+
+        let mut body = Vec::with_capacity(1 + 8 + 8 + 2 + data.len());
+        match self.role {
+            // the response belongs to the session of client
+            Role::Server => {
+                let client_session_id = try_opt!(self.udp_peer_session_id);
+                body.push(aead2022::HEADER_TYPE_SERVER);
+                pack!(u64, body, aead2022::now());
+                pack!(u64, body, client_session_id);
+            }
+            Role::Client => {
+                body.push(aead2022::HEADER_TYPE_CLIENT);
+                pack!(u64, body, aead2022::now());
+            }
+        }
+        // no padding
+        pack!(u16, body, 0);
+        body.extend_from_slice(data);
+        self.udp_packet_id += 1;
+
+        let nonce = if self.method == Method::blake3_chacha20_poly1305 {
+            aead2022::gen_random_bytes(XNONCE_LEN)
+        } else {
+            vec![]
+        };
+        Some(self.aead2022_seal_udp(&header, &body, &nonce))
+    }
+
+    // AES methods encrypt `session id | packet id` by AES-ECB, and seal the body with the
+    // subkey of session. ChaCha20 seals all of them with the PSK and a random `nonce`.
+    fn aead2022_seal_udp(&self, header: &[u8], body: &[u8], nonce: &[u8]) -> Vec<u8> {
+        let mut res = Vec::with_capacity(XNONCE_LEN + header.len() + body.len() + TAG_LEN);
+        if self.method == Method::blake3_chacha20_poly1305 {
+            res.extend_from_slice(nonce);
+            let mut packet = Vec::with_capacity(header.len() + body.len());
+            packet.extend_from_slice(header);
+            packet.extend_from_slice(body);
+            aead::seal_with(self.method, &self.key, nonce, &packet, &mut res);
+        } else {
+            let mut encrypted_header = [0u8; 16];
+            aead2022::encrypt_separate_header(&self.key, header, &mut encrypted_header);
+            res.extend_from_slice(&encrypted_header);
+            let subkey = aead2022::session_subkey(&self.key, &header[..8]);
+            aead::seal_with(self.method, &subkey, &header[4..], body, &mut res);
+        }
+        res
+    }
+
+    // return `session id | packet id` and the body
+    fn aead2022_open_udp(&self, data: &[u8]) -> Option<([u8; 16], Vec<u8>)> {
+        let mut header = [0u8; 16];
+        let mut body = Vec::with_capacity(data.len());
+        if self.method == Method::blake3_chacha20_poly1305 {
+            if data.len() < XNONCE_LEN {
+                return None;
+            }
+            let (nonce, data) = data.split_at(XNONCE_LEN);
+            let mut decrypted = Vec::with_capacity(data.len());
+            try_opt!(aead::open_with(self.method, &self.key, nonce, data, &mut decrypted).ok());
+            if decrypted.len() < header.len() {
+                return None;
+            }
+            header.copy_from_slice(&decrypted[..16]);
+            body.extend_from_slice(&decrypted[16..]);
+        } else {
+            if data.len() < header.len() {
+                return None;
+            }
+            aead2022::decrypt_separate_header(&self.key, &data[..16], &mut header);
+            let subkey = aead2022::session_subkey(&self.key, &header[..8]);
+            try_opt!(aead::open_with(self.method, &subkey, &header[4..], &data[16..], &mut body)
+                .ok());
+        }
+        Some((header, body))
+    }
+
+    fn aead2022_decrypt_udp(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let (header, mut body) = try_opt!(self.aead2022_open_udp(data));
+        let session_id = unpack!(u64, &header[..8]);
+        let packet_id = unpack!(u64, &header[8..]);
+
+        let is_response = self.role == Role::Client;
+        let (header_type, header_len) = if is_response {
+            (aead2022::HEADER_TYPE_SERVER, 1 + 8 + 8 + 2)
+        } else {
+            (aead2022::HEADER_TYPE_CLIENT, 1 + 8 + 2)
+        };
+        if body.len() < header_len || body[0] != header_type {
+            return None;
+        }
+        if !aead2022::check_timestamp(unpack!(u64, &body[1..9])) {
+            return None;
+        }
+        if is_response && unpack!(u64, &body[9..17]) != self.udp_session_id {
+            warn!("client session id of shadowsocks 2022 response mismatch");
+            return None;
+        }
+        let padding_len = unpack!(u16, &body[header_len - 2..header_len]) as usize;
+        if body.len() < header_len + padding_len {
+            return None;
+        }
+
+        let is_fresh = match self.role {
+            // the sessions of all the clients are checked by the relay
+            Role::Server => {
+                self.replay_filter
+                    .as_ref()
+                    .map_or(true, |f| f.check_and_insert_packet_id(session_id, packet_id))
+            }
+            Role::Client => self.check_server_packet_id(session_id, packet_id),
+        };
+        if !is_fresh {
+            return None;
+        }
+
+        if !is_response {
+            self.udp_peer_session_id = Some(session_id);
+        }
+        Some(body.split_off(header_len + padding_len))
+    }
+
+    // A client talks to a single session of server, which changes after the server restarts.
+    fn check_server_packet_id(&mut self, session_id: u64, packet_id: u64) -> bool {
+        if let Some((id, ref mut window)) = self.udp_peer_window {
+            if id == session_id {
+                let is_fresh = window.check_and_insert(packet_id);
+                if !is_fresh {
+                    warn!("got a replayed shadowsocks 2022 packet");
+                }
+                return is_fresh;
+            }
+        }
+        let mut window = ReplayWindow::new();
+        window.check_and_insert(packet_id);
+        self.udp_peer_window = Some((session_id, window));
+        true
+    }
+
     pub fn encrypt_udp_ota(&mut self, addr_type: u8, data: &[u8]) -> Option<Vec<u8>> {
         if self.ota_helper.is_none() {
             self.ota_helper = Some(OtaHelper::new());
@@ -391,6 +963,27 @@ impl OtaHelper {
     }
 }
 
+// variable header: socks5 address | padding length | padding | initial payload
+fn strip_padding(data: &mut Vec<u8>, offset: usize) -> Option<()> {
+    let var_header = data.split_off(offset);
+    if var_header.is_empty() {
+        return None;
+    }
+    let header_len = try_opt!(parse_header(&var_header)).3;
+    if var_header.len() < header_len + 2 {
+        return None;
+    }
+    let padding_len = unpack!(u16, &var_header[header_len..header_len + 2]) as usize;
+    let payload_offset = header_len + 2 + padding_len;
+    if var_header.len() < payload_offset {
+        return None;
+    }
+
+    data.extend_from_slice(&var_header[..header_len]);
+    data.extend_from_slice(&var_header[payload_offset..]);
+    Some(())
+}
+
 fn gen_key_iv(password: &str, method: Method) -> (Arc<Vec<u8>>, Vec<u8>) {
     lazy_static! {
         static ref CACHE: Mutex<LruCache<(String, Method), Arc<Vec<u8>>>> =
@@ -447,3 +1040,94 @@ fn evp_bytes_to_key(password: &str, key_len: usize, iv_len: usize) -> (Vec<u8>,
 
     (key, iv)
 }
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::hex::FromHex;
+
+    use super::Encryptor;
+    use super::super::{Method, Cipher, Mode};
+    use super::super::cipher::AeadCipher;
+    use super::super::replay::ReplayFilter;
+
+    const PSK_128: &'static str = "AAECAwQFBgcICQoLDA0ODw==";
+    const PSK_256: &'static str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    // session id 0x0102030405060708, packet id 1
+    const HEADER: &'static str = "01020304050607080000000000000001";
+    // request, timestamp 1700000000, no padding, 127.0.0.1:80, "hello"
+    const BODY: &'static str = "00000000006553f1000000017f000001005068656c6c6f";
+
+    // produced by shadowsocks-crypto, the nonce of XChaCha20 is `e0 e1 ... f7`
+    const UDP_VECTORS: &'static [(Method, &'static str, &'static str)] =
+        &[(Method::blake3_aes_128_gcm,
+           PSK_128,
+           "cf91ad9eddf9ce645b515e2382d1c8cc\
+            b6fe3ffcfbe0522b29bf8cc27ae7c389ee2f02369eec9349d8ee67a979395b828c28dff9a81344"),
+          (Method::blake3_aes_256_gcm,
+           PSK_256,
+           "e4a143bc51277a003d85d6825ad10fb2\
+            28ac454ff1c07422424d7e3207e68eed506883e53e9f2e8a4278bddd92c56e4182a07268ac5e44"),
+          (Method::blake3_chacha20_poly1305,
+           PSK_256,
+           "e0e1e2e3e4e5e6e7e8e9eaebecedeeeff0f1f2f3f4f5f6f7\
+            45f9fe6691e01907e9cd317a29da9bcf2e3008e09b0cc1863b3cf3daef8db1df\
+            5c491ff2249686692b8ad75ce458fe634bdbf306b7e562")];
+
+    #[test]
+    fn aead2022_udp_known_answer() {
+        let header = HEADER.from_hex().unwrap();
+        let body = BODY.from_hex().unwrap();
+        let nonce: Vec<u8> = (0xe0..0xf8).collect();
+        for &(method, psk, expected) in UDP_VECTORS {
+            let expected = expected.from_hex().unwrap();
+            let encryptor = Encryptor::new(psk, method).unwrap();
+            assert_eq!(expected, encryptor.aead2022_seal_udp(&header, &body, &nonce), "{}", method);
+
+            let (opened_header, opened_body) = encryptor.aead2022_open_udp(&expected).unwrap();
+            assert_eq!(&header[..], &opened_header[..], "{}", method);
+            assert_eq!(body, opened_body, "{}", method);
+        }
+    }
+
+    #[test]
+    fn aead_chunk_too_long() {
+        let mut server = Encryptor::new("foo", Method::aes_256_gcm).unwrap();
+        let salt = vec![0u8; server.iv_len];
+        let mut cipher =
+            Cipher::new(Method::aes_256_gcm, Mode::Encrypt, server.key.clone(), salt.clone())
+                .unwrap();
+        let mut data = salt;
+        cipher.seal(&[0x40, 0x00], &mut data).unwrap();
+        // the length is rejected before the chunk is received
+        assert!(server.decrypt(&data).is_none());
+    }
+
+    #[test]
+    fn probe_users() {
+        // 127.0.0.1:80 and "hello"
+        let request = [1, 127, 0, 0, 1, 0, 80, b'h', b'e', b'l', b'l', b'o'];
+        let users = [("foo", Method::aes_256_ctr),
+                     ("bar", Method::aes_256_gcm),
+                     ("baz", Method::chacha20_ietf_poly1305)];
+        for &(password, method) in &users[1..] {
+            let mut client = Encryptor::new(password, method).unwrap();
+            let encrypted = client.encrypt(&request).unwrap();
+
+            // only the key of the right user can decrypt the request
+            let matched: Vec<_> = users.iter()
+                .filter(|&&(password, method)| {
+                    let mut server = Encryptor::new(password, method).unwrap();
+                    server.probe(&encrypted).map(|d| d == request).unwrap_or(false)
+                })
+                .collect();
+            assert_eq!(matched, vec![&(password, method)]);
+
+            // probing doesn't remember the salt
+            let mut server = Encryptor::new(password, method).unwrap();
+            server.set_replay_filter(ReplayFilter::new(1000));
+            assert!(server.probe(&encrypted).is_some());
+            assert!(server.check_replay(&encrypted, false));
+            assert!(!server.check_replay(&encrypted, false));
+        }
+    }
+}
@@ -11,6 +11,9 @@ pub type CipherResult<T> = Result<T, Error>;
 pub enum Error {
     UnknownMethod(String),
     UnsupportMethod(Method),
+    AuthenticationFailed,
+    InvalidPsk(String),
+    InvalidIv(String),
     #[cfg(feature = "openssl")]
     OpensslError(ErrorStack),
     IoError(io::Error),
@@ -28,6 +31,9 @@ impl fmt::Debug for Error {
         match *self {
             Error::UnknownMethod(ref s) => write!(f, "unknown method {}", s),
             Error::UnsupportMethod(m) => write!(f, "unsupport method {:?}", m),
+            Error::AuthenticationFailed => write!(f, "authentication failed"),
+            Error::InvalidPsk(ref s) => write!(f, "invalid PSK: {}", s),
+            Error::InvalidIv(ref s) => write!(f, "invalid IV: {}", s),
             #[cfg(feature = "openssl")]
             Error::OpensslError(ref err) => write!(f, "{:?}", err),
             Error::IoError(ref err) => write!(f, "{:?}", err),
@@ -40,6 +46,9 @@ impl fmt::Display for Error {
         match *self {
             Error::UnknownMethod(ref s) => write!(f, "unknown method {}", s),
             Error::UnsupportMethod(m) => write!(f, "unsupport method {:?}", m),
+            Error::AuthenticationFailed => write!(f, "authentication failed"),
+            Error::InvalidPsk(ref s) => write!(f, "invalid PSK: {}", s),
+            Error::InvalidIv(ref s) => write!(f, "invalid IV: {}", s),
             #[cfg(feature = "openssl")]
             Error::OpensslError(ref err) => write!(f, "{}", err),
             Error::IoError(ref err) => write!(f, "{}", err),
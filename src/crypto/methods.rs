@@ -1,20 +1,32 @@
 use std::fmt;
 use std::str::FromStr;
 
+use super::registry;
+
 pub enum BelongLib {
     Crypto,
     #[cfg(feature = "openssl")]
     Openssl,
+    Registry,
+}
+
+// Method name is generated from its identifier (`_` replaced by `-`),
+// unless an explicit name is given as the last element.
+macro_rules! method_name {
+    ($method:tt) => ( stringify!($method).replace("_", "-") );
+    ($method:tt, $name:expr) => ( String::from($name) );
 }
 
 macro_rules! define_methods {
-    [$($method:tt => ($key_len:expr, $iv_len:expr, $lib:tt),)*] => (
+    [$($method:tt => ($key_len:expr, $iv_len:expr, $lib:tt $(, $name:expr)*),)*] => (
         #[allow(non_camel_case_types)]
         #[derive(Debug, Clone, Copy, Hash, Eq, Ord, PartialEq, PartialOrd)]
         pub enum Method {
             $(
                 $method,
             )*
+            // index of a cipher added by `registry::register`
+            registered(usize),
         }
 
         impl Method {
@@ -23,6 +35,7 @@ macro_rules! define_methods {
                     $(
                         Method::$method => ($key_len, $iv_len),
                     )*
+                    Method::registered(idx) => registry::info(idx).unwrap_or((0, 0)),
                 }
             }
 
@@ -31,28 +44,33 @@ macro_rules! define_methods {
                     $(
                         Method::$method => BelongLib::$lib,
                     )*
+                    Method::registered(_) => BelongLib::Registry,
                 }
             }
 
             pub fn all() -> Vec<Method> {
-                vec![
+                let mut methods: Vec<_> = vec![
                     $(
                         Method::$method,
                     )*
                 ]
+                    .into_iter()
+                    // replaced by the registered cipher of the same name
+                    .filter(|m| registry::find(&m.to_string()).is_none())
+                    .collect();
+                methods.extend((0..registry::len()).map(Method::registered));
+                methods
             }
         }
 
         impl FromStr for Method {
             type Err = ();
             fn from_str(s: &str) -> Result<Self, Self::Err> {
-                let s = s.replace("-", "_");
-                match s.as_str() {
-                    $(
-                        stringify!($method) => Ok(Method::$method),
-                    )*
-                    _ => Err(()),
-                }
+                // "plain" is an alias of "none"
+                let s = if s == "plain" { "none" } else { s }.replace("_", "-");
+                registry::find(&s)
+                    .or_else(|| Method::all().into_iter().find(|m| m.to_string() == s))
+                    .ok_or(())
             }
         }
 
@@ -60,9 +78,12 @@ macro_rules! define_methods {
             fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
                 match *self {
                     $(
-                        Method::$method => write!(f, "{}",
-                                                  stringify!($method).replace("_", "-").as_str()),
+                        Method::$method => write!(f, "{}", method_name!($method $(, $name)*)),
                     )*
+                    Method::registered(idx) => {
+                        let name = registry::name(idx);
+                        write!(f, "{}", name.as_ref().map(|s| &s[..]).unwrap_or("unknown"))
+                    }
                 }
             }
         }
@@ -71,6 +92,7 @@ macro_rules! define_methods {
 
 #[cfg(not(feature = "openssl"))]
 define_methods!(
+    none => (0, 0, Crypto),
     aes_128_ctr => (16, 16, Crypto),
     aes_192_ctr => (24, 16, Crypto),
     aes_256_ctr => (32, 16, Crypto),
@@ -81,10 +103,31 @@ define_methods!(
     chacha20 => (32, 8, Crypto),
     xchacha20 => (32, 24, Crypto),
     sosemanuk => (32, 16, Crypto),
+    chacha20_ietf => (32, 12, Crypto),
+    rc4_md5 => (16, 16, Crypto),
+
+    aes_128_gcm => (16, 16, Crypto),
+    aes_256_gcm => (32, 32, Crypto),
+    chacha20_ietf_poly1305 => (32, 32, Crypto),
+
+    blake3_aes_128_gcm => (16, 16, Crypto, "2022-blake3-aes-128-gcm"),
+    blake3_aes_256_gcm => (32, 32, Crypto, "2022-blake3-aes-256-gcm"),
+    blake3_chacha20_poly1305 => (32, 32, Crypto, "2022-blake3-chacha20-poly1305"),
+
+    aes_128_cfb => (16, 16, Crypto),
+    aes_192_cfb => (24, 16, Crypto),
+    aes_256_cfb => (32, 16, Crypto),
+    aes_128_cfb1 => (16, 16, Crypto),
+    aes_192_cfb1 => (24, 16, Crypto),
+    aes_256_cfb1 => (32, 16, Crypto),
+    aes_128_cfb8 => (16, 16, Crypto),
+    aes_192_cfb8 => (24, 16, Crypto),
+    aes_256_cfb8 => (32, 16, Crypto),
 );
 
 #[cfg(feature = "openssl")]
 define_methods!(
+    none => (0, 0, Crypto),
     aes_128_ctr => (16, 16, Crypto),
     aes_192_ctr => (24, 16, Crypto),
     aes_256_ctr => (32, 16, Crypto),
@@ -95,6 +138,16 @@ define_methods!(
     chacha20 => (32, 8, Crypto),
     xchacha20 => (32, 24, Crypto),
     sosemanuk => (32, 16, Crypto),
+    chacha20_ietf => (32, 12, Crypto),
+    rc4_md5 => (16, 16, Crypto),
+
+    aes_128_gcm => (16, 16, Crypto),
+    aes_256_gcm => (32, 32, Crypto),
+    chacha20_ietf_poly1305 => (32, 32, Crypto),
+
+    blake3_aes_128_gcm => (16, 16, Crypto, "2022-blake3-aes-128-gcm"),
+    blake3_aes_256_gcm => (32, 32, Crypto, "2022-blake3-aes-256-gcm"),
+    blake3_chacha20_poly1305 => (32, 32, Crypto, "2022-blake3-chacha20-poly1305"),
 
     aes_128_cfb => (16, 16, Openssl),
     aes_256_cfb => (32, 16, Openssl),
@@ -102,4 +155,32 @@ define_methods!(
     aes_256_cfb1 => (32, 16, Openssl),
     aes_128_cfb8 => (16, 16, Openssl),
     aes_256_cfb8 => (32, 16, Openssl),
+    // not provided by `openssl` crate
+    aes_192_cfb => (24, 16, Crypto),
+    aes_192_cfb1 => (24, 16, Crypto),
+    aes_192_cfb8 => (24, 16, Crypto),
+
+    bf_cfb => (16, 8, Openssl),
 );
+
+impl Method {
+    // For AEAD methods, the second element of `info` is the salt length.
+    pub fn is_aead(self) -> bool {
+        match self {
+            Method::aes_128_gcm |
+            Method::aes_256_gcm |
+            Method::chacha20_ietf_poly1305 => true,
+            m => m.is_aead_2022(),
+        }
+    }
+
+    // Shadowsocks 2022 methods take a base64 encoded PSK as password.
+    pub fn is_aead_2022(self) -> bool {
+        match self {
+            Method::blake3_aes_128_gcm |
+            Method::blake3_aes_256_gcm |
+            Method::blake3_chacha20_poly1305 => true,
+            _ => false,
+        }
+    }
+}
@@ -4,15 +4,21 @@ use super::rust_openssl;
 mod methods;
 mod cipher;
 mod encryptor;
+mod stream;
 pub mod error;
+pub mod replay;
+pub mod registry;
 
+mod aead;
+mod aead2022;
 mod crypto_lib;
 #[cfg(feature = "openssl")]
 mod openssl_lib;
 
 pub use self::methods::Method;
-pub use self::cipher::Cipher;
-pub use self::encryptor::Encryptor;
+pub use self::cipher::{Cipher, StreamCipher, AeadCipher};
+pub use self::encryptor::{Encryptor, Role};
+pub use self::stream::EncryptedStream;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Mode {
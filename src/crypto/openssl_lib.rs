@@ -7,6 +7,8 @@ use super::cipher::StreamCipher;
 pub struct OpensslCipher {
     block_size: usize,
     inner: Crypter,
+    // `Crypter::update` can't work in place, reuse this buffer instead
+    buf: Vec<u8>,
 }
 
 impl OpensslCipher {
@@ -18,6 +20,7 @@ impl OpensslCipher {
             Method::aes_256_cfb1 => Cipher::aes_256_cfb1(),
             Method::aes_128_cfb8 => Cipher::aes_128_cfb8(),
             Method::aes_256_cfb8 => Cipher::aes_256_cfb8(),
+            Method::bf_cfb => Cipher::bf_cfb64(),
             m => return Err(Error::UnsupportMethod(m)),
         };
         let block_size = cipher.block_size();
@@ -26,11 +29,22 @@ impl OpensslCipher {
         Ok(OpensslCipher {
             block_size: block_size,
             inner: inner,
+            buf: Vec::new(),
         })
     }
 }
 
 impl StreamCipher for OpensslCipher {
+    fn apply_keystream(&mut self, data: &mut [u8]) -> CipherResult<()> {
+        let cap = data.len() + self.block_size;
+        self.buf.resize(cap, 0);
+        let length = self.inner.update(data, &mut self.buf)?;
+        // CFB is a stream mode, nothing is kept inside `Crypter`
+        debug_assert_eq!(length, data.len());
+        data.copy_from_slice(&self.buf[..length]);
+        Ok(())
+    }
+
     fn update(&mut self, input: &[u8], output: &mut Vec<u8>) -> CipherResult<()> {
         let cap = input.len() + self.block_size;
         output.resize(cap, 0);
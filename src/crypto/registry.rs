@@ -0,0 +1,76 @@
+// Stream ciphers provided by the application at runtime.
+//
+// A registered cipher gets a `Method::registered(index)`, so it can be used anywhere
+// a built-in method is used, e.g. `"name".parse::<Method>()` or `Encryptor::new`.
+use std::sync::{Arc, RwLock};
+
+use super::{Method, Mode};
+use super::cipher::StreamCipher;
+use super::error::{Error, CipherResult};
+
+pub trait CipherFactory: Send + Sync {
+    fn name(&self) -> &str;
+    fn key_len(&self) -> usize;
+    fn iv_len(&self) -> usize;
+    fn new_cipher(&self, mode: Mode, key: &[u8], iv: &[u8]) -> CipherResult<Box<StreamCipher>>;
+}
+
+lazy_static! {
+    static ref FACTORIES: RwLock<Vec<Arc<CipherFactory>>> = RwLock::new(vec![]);
+}
+
+/// Register a stream cipher. A cipher of the same name as a registered one or a built-in
+/// stream cipher replaces it.
+pub fn register<F: CipherFactory + 'static>(factory: F) -> CipherResult<Method> {
+    // AEAD methods can't be replaced
+    let name = factory.name().to_string();
+    if let Some(m) = Method::all().into_iter().find(|m| m.is_aead() && m.to_string() == name) {
+        return Err(Error::UnsupportMethod(m));
+    }
+
+    let mut factories = FACTORIES.write().unwrap();
+    let factory = Arc::new(factory);
+    match factories.iter().position(|f| f.name() == name) {
+        Some(idx) => {
+            factories[idx] = factory;
+            Ok(Method::registered(idx))
+        }
+        None => {
+            factories.push(factory);
+            Ok(Method::registered(factories.len() - 1))
+        }
+    }
+}
+
+pub fn find(name: &str) -> Option<Method> {
+    FACTORIES.read().unwrap().iter().position(|f| f.name() == name).map(Method::registered)
+}
+
+pub fn len() -> usize {
+    FACTORIES.read().unwrap().len()
+}
+
+pub fn name(idx: usize) -> Option<String> {
+    get(idx).map(|f| f.name().to_string())
+}
+
+pub fn info(idx: usize) -> Option<(usize, usize)> {
+    get(idx).map(|f| (f.key_len(), f.iv_len()))
+}
+
+pub fn new_cipher(method: Method,
+                  mode: Mode,
+                  key: &[u8],
+                  iv: &[u8])
+                  -> CipherResult<Box<StreamCipher>> {
+    match method {
+        Method::registered(idx) => {
+            get(idx).ok_or(Error::UnsupportMethod(method))?.new_cipher(mode, key, iv)
+        }
+        m => Err(Error::UnsupportMethod(m)),
+    }
+}
+
+fn get(idx: usize) -> Option<Arc<CipherFactory>> {
+    FACTORIES.read().unwrap().get(idx).cloned()
+}
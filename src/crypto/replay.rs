@@ -0,0 +1,119 @@
+// ssserver rejects a request whose IV (or salt) has been seen recently,
+// otherwise a captured request can be replayed to probe the server.
+//
+// Two Bloom filters are used in turn. When the current one is full, the other one
+// is cleared and becomes the current one, so at least the last `capacity` IVs
+// are remembered while the memory usage is bounded.
+//
+// Shadowsocks 2022 requests carry a timestamp, so only the salts (and the UDP
+// packet ids) of the last `SALT_TTL` seconds are remembered exactly.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lru_time_cache::LruCache;
+
+use collections::BloomFilter;
+use super::aead2022::{ReplayWindow, SALT_TTL};
+
+pub const DEFAULT_CAPACITY: usize = 1000000;
+const FALSE_POSITIVE_RATE: f64 = 1e-6;
+
+/// The IVs seen recently by a relay, clones share the same IVs.
+#[derive(Clone)]
+pub struct ReplayFilter(Arc<Mutex<Filters>>);
+
+impl ReplayFilter {
+    /// Remember the last `capacity` IVs at least, 0 disables the filter of IVs
+    /// but shadowsocks 2022 requests are still checked.
+    pub fn new(capacity: usize) -> ReplayFilter {
+        let ttl = Duration::from_secs(SALT_TTL);
+        ReplayFilter(Arc::new(Mutex::new(Filters {
+            capacity: capacity,
+            current: 0,
+            filters: None,
+            salts: LruCache::with_expiry_duration(ttl),
+            sessions: LruCache::with_expiry_duration(ttl),
+            rejected: 0,
+        })))
+    }
+
+    /// Return `false` if `iv` has been seen recently.
+    pub fn check_and_insert(&self, iv: &[u8]) -> bool {
+        self.check(|filters| filters.check_and_insert(iv))
+    }
+
+    /// Return `false` if the salt of a shadowsocks 2022 request has been seen
+    /// in the last `SALT_TTL` seconds.
+    pub fn check_and_insert_salt(&self, salt: &[u8]) -> bool {
+        self.check(|filters| {
+            let salt = salt.to_vec();
+            if filters.salts.get(&salt).is_some() {
+                false
+            } else {
+                filters.salts.insert(salt, ());
+                true
+            }
+        })
+    }
+
+    /// Return `false` if a shadowsocks 2022 UDP packet has been seen or is too old
+    /// for its session.
+    pub fn check_and_insert_packet_id(&self, session_id: u64, packet_id: u64) -> bool {
+        self.check(|filters| {
+            filters.sessions
+                .entry(session_id)
+                .or_insert_with(ReplayWindow::new)
+                .check_and_insert(packet_id)
+        })
+    }
+
+    /// Number of rejected replays since created.
+    pub fn rejected_count(&self) -> usize {
+        self.0.lock().unwrap().rejected
+    }
+
+    fn check<F: FnOnce(&mut Filters) -> bool>(&self, f: F) -> bool {
+        let mut filters = self.0.lock().unwrap();
+        if f(&mut filters) {
+            true
+        } else {
+            filters.rejected += 1;
+            warn!("rejected a replayed request ({} in total)", filters.rejected);
+            false
+        }
+    }
+}
+
+struct Filters {
+    capacity: usize,
+    current: usize,
+    // allocated on the first request
+    filters: Option<[BloomFilter; 2]>,
+    // only shadowsocks 2022
+    salts: LruCache<Vec<u8>, ()>,
+    sessions: LruCache<u64, ReplayWindow>,
+    rejected: usize,
+}
+
+impl Filters {
+    fn check_and_insert(&mut self, iv: &[u8]) -> bool {
+        let capacity = self.capacity;
+        if capacity == 0 {
+            return true;
+        }
+        let filters = self.filters.get_or_insert_with(|| {
+            [BloomFilter::new(capacity, FALSE_POSITIVE_RATE),
+             BloomFilter::new(capacity, FALSE_POSITIVE_RATE)]
+        });
+        if filters.iter().any(|f| f.contains(iv)) {
+            return false;
+        }
+
+        if filters[self.current].len() >= capacity {
+            self.current = 1 - self.current;
+            filters[self.current].clear();
+        }
+        filters[self.current].insert(iv);
+        true
+    }
+}
@@ -0,0 +1,168 @@
+use std::cmp;
+use std::io;
+use std::io::{Read, Write};
+
+use super::{Encryptor, Method};
+use super::error::CipherResult;
+
+const BUF_SIZE: usize = 32 * 1024;
+
+/// Encrypt the data written to `inner` and decrypt the data read from it.
+pub struct EncryptedStream<T> {
+    inner: T,
+    encryptor: Encryptor,
+    iv_len: usize,
+    is_aead: bool,
+    is_iv_received: bool,
+    // decrypted data which is not read yet
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_buf: Vec<u8>,
+}
+
+impl<T: Read + Write> EncryptedStream<T> {
+    pub fn new(inner: T, password: &str, method: Method) -> CipherResult<EncryptedStream<T>> {
+        Ok(EncryptedStream::with_encryptor(inner, Encryptor::new(password, method)?))
+    }
+
+    pub fn with_encryptor(inner: T, encryptor: Encryptor) -> EncryptedStream<T> {
+        let method = encryptor.method();
+        EncryptedStream {
+            inner: inner,
+            encryptor: encryptor,
+            iv_len: method.info().1,
+            is_aead: method.is_aead(),
+            is_iv_received: false,
+            read_buf: Vec::with_capacity(BUF_SIZE),
+            read_pos: 0,
+            write_buf: Vec::with_capacity(BUF_SIZE),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Send the request header (`data[..header_length]`) and the following data
+    /// with one time auth, then the data written later is sent as OTA chunks.
+    pub fn enable_ota(&mut self,
+                      addr_type: u8,
+                      header_length: usize,
+                      data: &[u8])
+                      -> io::Result<()> {
+        let mut data = self.encryptor
+            .pack_ota_request(addr_type, header_length, data)
+            .ok_or_else(|| invalid_data("enable one time auth failed"))?;
+        self.encryptor.encrypt_in_place(&mut data).ok_or_else(|| invalid_data("encrypt failed"))?;
+        self.inner.write_all(&data)
+    }
+
+    /// Verify the request header (`data[..header_length]`) received by `read`,
+    /// `data` should contain all the data read so far. After that the data following
+    /// the header can be read as usual.
+    pub fn verify_ota(&mut self,
+                      addr_type: u8,
+                      header_length: usize,
+                      data: &[u8])
+                      -> io::Result<()> {
+        let mut received = data.to_vec();
+        received.extend_from_slice(&self.read_buf[self.read_pos..]);
+        self.read_buf = self.encryptor
+            .unpack_ota_request(addr_type, header_length, &received)
+            .ok_or_else(|| invalid_data("verify one time auth failed"))?;
+        self.read_pos = 0;
+        Ok(())
+    }
+
+    // read and decrypt until some data is available or EOF
+    fn fill_buf(&mut self) -> io::Result<()> {
+        self.read_buf.clear();
+        self.read_pos = 0;
+
+        while self.read_buf.is_empty() {
+            // the IV of stream ciphers must be received with some data at once
+            let min_len = if self.is_aead || self.is_iv_received {
+                1
+            } else {
+                self.iv_len + 1
+            };
+            let mut received = 0;
+            while received < min_len {
+                self.read_buf.resize(received + BUF_SIZE, 0);
+                let nread = self.inner.read(&mut self.read_buf[received..])?;
+                if nread == 0 {
+                    self.read_buf.clear();
+                    // a truncated stream must not look like a clean EOF
+                    return if received > 0 {
+                        Err(unexpected_eof("incomplete IV"))
+                    } else if self.encryptor.is_chunk_pending() {
+                        Err(unexpected_eof("incomplete chunk"))
+                    } else {
+                        Ok(())
+                    };
+                }
+                received += nread;
+            }
+            self.read_buf.truncate(received);
+            self.is_iv_received = true;
+
+            self.encryptor
+                .decrypt_in_place(&mut self.read_buf)
+                .ok_or_else(|| invalid_data("decrypt failed"))?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read + Write> Read for EncryptedStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.read_pos == self.read_buf.len() {
+            self.fill_buf()?;
+        }
+
+        let n = cmp::min(buf.len(), self.read_buf.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl<T: Read + Write> Write for EncryptedStream<T> {
+    // the encrypted data is always written entirely, since the cipher state can't go back
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.write_buf.clear();
+        self.write_buf.extend_from_slice(buf);
+        self.encryptor
+            .encrypt_in_place(&mut self.write_buf)
+            .ok_or_else(|| invalid_data("encrypt failed"))?;
+        self.inner.write_all(&self.write_buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn invalid_data(desc: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, desc)
+}
+
+fn unexpected_eof(desc: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, desc)
+}
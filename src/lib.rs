@@ -31,6 +31,8 @@ extern crate byteorder;
 extern crate lru_time_cache;
 extern crate rustc_serialize;
 extern crate crypto as rust_crypto;
+extern crate blake3;
+extern crate qrcode;
 #[cfg(feature = "openssl")]
 extern crate openssl as rust_openssl;
 
@@ -46,6 +48,7 @@ pub mod mode;
 pub mod config;
 pub mod socks5;
 pub mod crypto;
+pub mod client;
 pub mod asyncdns;
 pub mod my_logger;
 pub mod my_daemonize;
@@ -0,0 +1,253 @@
+// Circuit breaker of a server: after `threshold` consecutive failures, the
+// server is ejected for a backoff period which doubles on every repeat, then
+// a single trial connection decides whether it is readmitted.
+use std::fmt;
+use std::cmp;
+use std::time::{Duration, SystemTime};
+
+// seconds to wait for the result of a trial connection before another trial
+const TRIAL_TIMEOUT: u64 = 30;
+// upper bound of backoff in seconds
+const MAX_BACKOFF: u64 = 3600;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum BreakerState {
+    Closed,
+    // ejected until the time
+    Open(SystemTime),
+    // the start time of the trial connection, if any
+    HalfOpen(Option<SystemTime>),
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BreakerState::Closed => write!(f, "closed"),
+            BreakerState::Open(_) => write!(f, "open"),
+            BreakerState::HalfOpen(_) => write!(f, "half-open"),
+        }
+    }
+}
+
+impl fmt::Debug for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+pub struct CircuitBreaker {
+    state: BreakerState,
+    // consecutive failures
+    failures: u32,
+    // 0 means never ejected
+    threshold: u32,
+    // backoff of the first ejection and the next ejection in seconds
+    base_backoff: u64,
+    backoff: u64,
+    // times of ejection
+    trips: u32,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, backoff: u64) -> CircuitBreaker {
+        let backoff = cmp::min(backoff, MAX_BACKOFF);
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            failures: 0,
+            threshold: threshold,
+            base_backoff: backoff,
+            backoff: backoff,
+            trips: 0,
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    pub fn trips(&self) -> u32 {
+        self.trips
+    }
+
+    /// Whether a connection can be sent to the server now.
+    pub fn is_allowed(&self) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::Open(until) => SystemTime::now() >= until,
+            BreakerState::HalfOpen(None) => true,
+            BreakerState::HalfOpen(Some(start)) => elapsed_secs(&start) >= TRIAL_TIMEOUT,
+        }
+    }
+
+    /// A connection is sent to the server, which is the trial if not closed and allowed.
+    /// Return true if the state is changed.
+    pub fn on_chosen(&mut self) -> bool {
+        // chosen while not allowed, e.g. all the servers are ejected
+        if self.state == BreakerState::Closed || !self.is_allowed() {
+            return false;
+        }
+        self.state = BreakerState::HalfOpen(Some(SystemTime::now()));
+        true
+    }
+
+    /// Return true if the state is changed.
+    pub fn on_success(&mut self) -> bool {
+        self.failures = 0;
+        match self.state {
+            BreakerState::Closed => false,
+            _ => {
+                self.state = BreakerState::Closed;
+                self.backoff = self.base_backoff;
+                true
+            }
+        }
+    }
+
+    /// Return true if the state is changed.
+    pub fn on_failure(&mut self) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        let is_tripped = match self.state {
+            BreakerState::Closed => self.threshold > 0 && self.failures >= self.threshold,
+            BreakerState::HalfOpen(_) => true,
+            // failures of the connections sent before ejection
+            BreakerState::Open(_) => false,
+        };
+
+        if is_tripped {
+            self.state = BreakerState::Open(SystemTime::now() + Duration::from_secs(self.backoff));
+            self.backoff = cmp::min(self.backoff * 2, MAX_BACKOFF);
+            self.trips += 1;
+        }
+        is_tripped
+    }
+}
+
+fn elapsed_secs(time: &SystemTime) -> u64 {
+    time.elapsed().map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::{CircuitBreaker, BreakerState, TRIAL_TIMEOUT, MAX_BACKOFF};
+
+    // the backoff passed
+    fn expire(breaker: &mut CircuitBreaker) {
+        breaker.state = BreakerState::Open(SystemTime::now() - Duration::from_secs(1));
+        assert!(breaker.is_allowed());
+    }
+
+    // seconds left of the ejection, rounded up
+    fn ejected_secs(breaker: &CircuitBreaker) -> u64 {
+        match breaker.state() {
+            BreakerState::Open(until) => {
+                let left = until.duration_since(SystemTime::now()).unwrap();
+                left.as_secs() + if left.subsec_nanos() > 0 { 1 } else { 0 }
+            }
+            state => panic!("breaker is {}", state),
+        }
+    }
+
+    fn trip_again(breaker: &mut CircuitBreaker) {
+        expire(breaker);
+        assert!(breaker.on_chosen());
+        assert!(breaker.on_failure());
+    }
+
+    #[test]
+    fn trip_at_threshold() {
+        let mut breaker = CircuitBreaker::new(3, 10);
+        assert!(!breaker.on_failure());
+        assert!(!breaker.on_failure());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.is_allowed());
+        assert!(breaker.on_failure());
+        assert!(!breaker.is_allowed());
+        assert_eq!(ejected_secs(&breaker), 10);
+        assert_eq!(breaker.trips(), 1);
+
+        // connections sent before ejection don't extend it
+        assert!(!breaker.on_failure());
+        assert_eq!(breaker.trips(), 1);
+
+        let mut breaker = CircuitBreaker::new(0, 10);
+        for _ in 0..100 {
+            assert!(!breaker.on_failure());
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn double_backoff() {
+        let mut breaker = CircuitBreaker::new(1, 1000);
+        assert!(breaker.on_failure());
+        assert_eq!(ejected_secs(&breaker), 1000);
+        trip_again(&mut breaker);
+        assert_eq!(ejected_secs(&breaker), 2000);
+        trip_again(&mut breaker);
+        assert_eq!(ejected_secs(&breaker), MAX_BACKOFF);
+        trip_again(&mut breaker);
+        assert_eq!(ejected_secs(&breaker), MAX_BACKOFF);
+        assert_eq!(breaker.trips(), 4);
+
+        let mut breaker = CircuitBreaker::new(1, MAX_BACKOFF * 2);
+        assert!(breaker.on_failure());
+        assert_eq!(ejected_secs(&breaker), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn single_trial() {
+        let mut breaker = CircuitBreaker::new(1, 10);
+        assert!(breaker.on_failure());
+        expire(&mut breaker);
+        assert!(breaker.on_chosen());
+        assert!(!breaker.is_allowed());
+
+        // the trial got no result
+        let start = SystemTime::now() - Duration::from_secs(TRIAL_TIMEOUT);
+        breaker.state = BreakerState::HalfOpen(Some(start));
+        assert!(breaker.is_allowed());
+        assert!(breaker.on_chosen());
+        assert!(!breaker.is_allowed());
+    }
+
+    #[test]
+    fn chosen_before_expiry() {
+        let mut breaker = CircuitBreaker::new(1, 10);
+        assert!(breaker.on_failure());
+        assert!(!breaker.on_chosen());
+        assert_eq!(ejected_secs(&breaker), 10);
+
+        // neither does it restart the trial in progress
+        expire(&mut breaker);
+        assert!(breaker.on_chosen());
+        let state = breaker.state();
+        assert!(!breaker.on_chosen());
+        assert_eq!(breaker.state(), state);
+    }
+
+    #[test]
+    fn reset_on_success() {
+        let mut breaker = CircuitBreaker::new(2, 10);
+        assert!(!breaker.on_failure());
+        assert!(!breaker.on_success());
+        assert!(!breaker.on_failure());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        assert!(breaker.on_failure());
+        trip_again(&mut breaker);
+        assert_eq!(ejected_secs(&breaker), 20);
+        expire(&mut breaker);
+        assert!(breaker.on_chosen());
+        assert!(breaker.on_success());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.is_allowed());
+
+        // the threshold and backoff start over
+        assert!(!breaker.on_failure());
+        assert!(breaker.on_failure());
+        assert_eq!(ejected_secs(&breaker), 10);
+        assert_eq!(breaker.trips(), 3);
+    }
+}
@@ -0,0 +1,137 @@
+// Probe servers in background, so that dead servers are found out without
+// waiting for real traffic to fail on them.
+use std::thread;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::time::{Duration, Instant};
+
+use client::connect_timeout;
+use config::ProxyConfig;
+use network::Address;
+use error::{Result, ProcessError};
+
+// seconds to wait for connecting to server, and for the response of target
+const PROBE_TIMEOUT: u64 = 5;
+
+/// Result of probing a server.
+#[derive(Clone)]
+pub struct Probe {
+    pub server: Arc<ProxyConfig>,
+    /// round trip time in milliseconds, `None` if the server is unhealthy
+    pub rtt: Option<u32>,
+}
+
+pub struct HealthChecker {
+    target: Address,
+    interval: u64,
+    servers: Arc<Mutex<Vec<Arc<ProxyConfig>>>>,
+    subscribers: Arc<Mutex<Vec<Sender<Probe>>>>,
+}
+
+impl HealthChecker {
+    /// Probe every server in a thread every `interval` seconds, the thread stops
+    /// after the checker is dropped.
+    pub fn new(target: Address, interval: u64) -> HealthChecker {
+        let servers = Arc::new(Mutex::new(vec![]));
+        let subscribers = Arc::new(Mutex::new(vec![]));
+        let servers_ref = servers.clone();
+        let subscribers_ref = subscribers.clone();
+        let target_ref = target.clone();
+        thread::spawn(move || run(servers_ref, target_ref, interval, subscribers_ref));
+
+        HealthChecker {
+            target: target,
+            interval: interval,
+            servers: servers,
+            subscribers: subscribers,
+        }
+    }
+
+    pub fn target(&self) -> &Address {
+        &self.target
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// Servers to be probed from the next round.
+    pub fn set_servers(&self, servers: Vec<Arc<ProxyConfig>>) {
+        *self.servers.lock().unwrap() = servers;
+    }
+
+    /// Receive the results of the probes finished from now on,
+    /// every subscriber receives all of them.
+    pub fn subscribe(&self) -> Receiver<Probe> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+fn run(servers: Arc<Mutex<Vec<Arc<ProxyConfig>>>>,
+       target: Address,
+       interval: u64,
+       subscribers: Arc<Mutex<Vec<Sender<Probe>>>>) {
+    // servers whose probe is not finished yet
+    let probing = Arc::new(Mutex::new(Vec::new()));
+
+    // nobody receives the probes if the checker is dropped
+    while Arc::strong_count(&servers) > 1 {
+        // a server which can't be connected may block for a long time,
+        // so every server is probed in its own thread
+        for server in servers.lock().unwrap().clone() {
+            {
+                let mut probing = probing.lock().unwrap();
+                if probing.contains(&server) {
+                    continue;
+                }
+                probing.push(server.clone());
+            }
+
+            let target = target.clone();
+            let probing = probing.clone();
+            let subscribers = subscribers.clone();
+            thread::spawn(move || {
+                let rtt = match probe(&server, &target) {
+                    Ok(rtt) => Some(rtt),
+                    Err(e) => {
+                        debug!("probe {}:{} failed: {:?}", server.address, server.port, e);
+                        None
+                    }
+                };
+                probing.lock().unwrap().retain(|s| *s != server);
+                let probe = Probe {
+                    server: server,
+                    rtt: rtt,
+                };
+                // the dropped subscribers are removed
+                subscribers.lock().unwrap().retain(|tx| tx.send(probe.clone()).is_ok());
+            });
+        }
+
+        thread::sleep(Duration::from_secs(interval));
+    }
+}
+
+// Send a HTTP request to `target` through `server`, and wait for the response.
+fn probe(server: &ProxyConfig, target: &Address) -> Result<u32> {
+    let start = Instant::now();
+    let mut stream = connect_timeout(server, target.clone(), Duration::from_secs(PROBE_TIMEOUT))?;
+    let timeout = Some(Duration::from_secs(PROBE_TIMEOUT));
+    stream.get_ref().set_read_timeout(timeout)?;
+    stream.get_ref().set_write_timeout(timeout)?;
+
+    let request = format!("HEAD / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                          target.0);
+    stream.write_all(request.as_bytes())?;
+    let mut buf = [0u8; 1024];
+    if stream.read(&mut buf)? == 0 {
+        let errmsg = format!("{} closed the connection", target);
+        return err_from!(ProcessError::ConnectFailed(errmsg));
+    }
+
+    let elapsed = start.elapsed();
+    Ok(elapsed.as_secs() as u32 * 1000 + elapsed.subsec_nanos() / 1000000)
+}
@@ -1,19 +1,28 @@
+use std::fs;
 use std::fmt;
 use std::sync::Arc;
 use std::time::SystemTime;
-use std::cmp::{Ord, Ordering};
+use std::cmp::{Ord, Ordering, Reverse};
 use std::collections::VecDeque;
+use std::sync::mpsc::Receiver;
 
 use mio::Token;
 use rand::{thread_rng, ThreadRng, Rng};
 
-use config::{CONFIG, ProxyConfig};
-use collections::Dict;
+use config::{Config, SharedConfig, ProxyConfig, read_servers_file};
+use collections::{Dict, HashRing};
+
+pub use self::health::{HealthChecker, Probe};
+pub use self::breaker::{CircuitBreaker, BreakerState};
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum Mode {
     Fast,
     Balance,
+    Failover,
+    Sticky,
+    Weighted,
+    LeastConn,
     None,
 }
 
@@ -22,6 +31,10 @@ impl fmt::Display for Mode {
         match *self {
             Mode::Fast => write!(f, "fast"),
             Mode::Balance => write!(f, "balance"),
+            Mode::Failover => write!(f, "failover"),
+            Mode::Sticky => write!(f, "sticky"),
+            Mode::Weighted => write!(f, "weighted"),
+            Mode::LeastConn => write!(f, "least_conn"),
             Mode::None => write!(f, "none"),
         }
     }
@@ -33,42 +46,358 @@ impl fmt::Debug for Mode {
     }
 }
 
+/// What sticky mode maps onto servers.
+#[derive(PartialEq, Clone, Copy)]
+pub enum StickyKey {
+    Destination,
+    Client,
+}
+
+impl fmt::Display for StickyKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StickyKey::Destination => write!(f, "destination"),
+            StickyKey::Client => write!(f, "client"),
+        }
+    }
+}
+
+impl fmt::Debug for StickyKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// A snapshot of the state of a server.
+pub struct ServerStats {
+    pub server: Arc<ProxyConfig>,
+    pub is_healthy: bool,
+    pub connections: usize,
+    pub breaker: BreakerState,
+    // times of ejection by circuit breaker
+    pub trips: u32,
+}
+
+impl fmt::Display for ServerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}:{} {}, {} connections, circuit breaker {} ({} trips)",
+               self.server.address,
+               self.server.port,
+               if self.is_healthy { "healthy" } else { "unhealthy" },
+               self.connections,
+               self.breaker,
+               self.trips)
+    }
+}
+
+// seconds between two logs of stats
+const STATS_INTERVAL: u64 = 600;
+// points of every server on the hash ring of sticky mode
+const RING_REPLICAS: usize = 100;
+// seconds between two checks of `servers_file`
+const RELOAD_INTERVAL: u64 = 10;
+
 pub struct ServerChooser {
     rng: ThreadRng,
     rtts: Dict<Arc<ProxyConfig>, RttRecord>,
     activities: Dict<Token, VecDeque<SystemTime>>,
+    // servers of the active TCP connections, and the number of connections of every server
+    connections: Dict<Token, Arc<ProxyConfig>>,
+    conn_counts: Dict<Arc<ProxyConfig>, usize>,
+    // only weighted mode: current weights of smooth weighted round-robin
+    current_weights: Dict<Arc<ProxyConfig>, i64>,
+    shared_config: Arc<SharedConfig>,
+    // config which `rtts` is built from, replaced on reload
+    config: Arc<Config>,
+    // servers loaded from `servers_file` and its last modified time
+    file_servers: Vec<Arc<ProxyConfig>>,
+    file_modified: Option<SystemTime>,
+    file_checked: SystemTime,
+    // health of the probed servers, the others are regarded as healthy
+    health: Dict<Arc<ProxyConfig>, bool>,
+    // the checker is shared with the other relays, and the probes are received here
+    health_checker: Option<(Arc<HealthChecker>, Receiver<Probe>)>,
+    // the last time a server failed, by probes or real connections
+    failures: Dict<Arc<ProxyConfig>, SystemTime>,
+    // only failover mode: the server chosen last time
+    active: Option<Arc<ProxyConfig>>,
+    // only sticky mode
+    ring: HashRing<Arc<ProxyConfig>>,
+    breakers: Dict<Arc<ProxyConfig>, CircuitBreaker>,
+    stats_logged: SystemTime,
 }
 
 impl ServerChooser {
-    pub fn new() -> ServerChooser {
-        let mut rtts = Dict::default();
-
+    pub fn new(shared_config: Arc<SharedConfig>) -> ServerChooser {
+        let mut chooser = ServerChooser {
+            rng: thread_rng(),
+            rtts: Dict::default(),
+            activities: Dict::default(),
+            connections: Dict::default(),
+            conn_counts: Dict::default(),
+            current_weights: Dict::default(),
+            config: shared_config.get(),
+            shared_config: shared_config,
+            file_servers: vec![],
+            file_modified: None,
+            file_checked: SystemTime::now(),
+            health: Dict::default(),
+            health_checker: None,
+            failures: Dict::default(),
+            active: None,
+            ring: HashRing::new(RING_REPLICAS),
+            breakers: Dict::default(),
+            stats_logged: SystemTime::now(),
+        };
         // reduce some compute...
         if cfg!(feature = "sslocal") {
-            for server_conf in CONFIG.server_confs.as_ref().unwrap() {
-                rtts.insert(server_conf.clone(), RttRecord::new());
+            chooser.sync_config();
+        }
+        chooser
+    }
+
+    // Rebuild the servers from `self.config`, servers which remain
+    // keep their RTT records.
+    fn sync_config(&mut self) {
+        let config = self.config.clone();
+        let mut servers = config.server_confs.clone().unwrap_or_default();
+        // the file has been read along with the config
+        self.file_servers = config.file_server_confs.clone().unwrap_or_default();
+        self.file_modified = config.servers_file
+            .as_ref()
+            .and_then(|path| fs::metadata(path).and_then(|m| m.modified()).ok());
+        self.file_checked = SystemTime::now();
+        servers.extend(self.file_servers.iter().cloned());
+
+        self.rtts.retain(|server, _| servers.contains(server));
+        for server in servers {
+            self.rtts.entry(server).or_insert_with(RttRecord::new);
+        }
+
+        self.health_checker = self.shared_config.health_checker(&config).map(|checker| {
+            let probes = checker.subscribe();
+            (checker, probes)
+        });
+        self.on_servers_changed();
+    }
+
+    // forget the states of removed servers, and probe the current servers
+    fn on_servers_changed(&mut self) {
+        let rtts = &self.rtts;
+        self.health.retain(|server, _| rtts.contains_key(server));
+        self.failures.retain(|server, _| rtts.contains_key(server));
+        self.current_weights.retain(|server, _| rtts.contains_key(server));
+        self.breakers.retain(|server, _| rtts.contains_key(server));
+        if let Some((ref checker, _)) = self.health_checker {
+            checker.set_servers(self.rtts.keys().cloned().collect());
+        }
+        if Mode::Sticky == self.config.mode {
+            // the points of a server are independent of others,
+            // so the remained servers keep their keys
+            self.ring = HashRing::new(RING_REPLICAS);
+            for server in self.rtts.keys() {
+                let id = format!("{}:{}", server.address, server.port);
+                self.ring.insert(&id, server.clone());
             }
         }
+    }
 
-        ServerChooser {
-            rng: thread_rng(),
-            rtts: rtts,
-            activities: Dict::default(),
+    // mark servers healthy or unhealthy by the probes, and take their RTT as samples
+    fn handle_probes(&mut self) {
+        let probes: Vec<Probe> = match self.health_checker {
+            Some((_, ref probes)) => probes.try_iter().collect(),
+            None => return,
+        };
+
+        for probe in probes {
+            // the server may be removed after probed
+            let rtt = match self.rtts.get_mut(&probe.server) {
+                Some(rtt) => rtt,
+                None => continue,
+            };
+            match probe.rtt {
+                Some(elapsed_ms) => rtt.add_sample(elapsed_ms),
+                None => {
+                    rtt.punish();
+                    self.failures.insert(probe.server.clone(), SystemTime::now());
+                }
+            }
+
+            let is_healthy = probe.rtt.is_some();
+            let was_healthy = self.health.insert(probe.server.clone(), is_healthy).unwrap_or(true);
+            if is_healthy != was_healthy {
+                let server = &probe.server;
+                if is_healthy {
+                    info!("server {}:{} is healthy now", server.address, server.port);
+                } else {
+                    warn!("server {}:{} is unhealthy", server.address, server.port);
+                }
+            }
+        }
+    }
+
+    fn is_healthy(&self, server: &Arc<ProxyConfig>) -> bool {
+        self.health.get(server).cloned().unwrap_or(true)
+    }
+
+    // healthy and not ejected by circuit breaker
+    fn is_available(&self, server: &Arc<ProxyConfig>) -> bool {
+        self.is_healthy(server) && self.breakers.get(server).map_or(true, |b| b.is_allowed())
+    }
+
+    // available servers, or all the servers if none of them is available
+    fn available_servers(&self) -> Vec<Arc<ProxyConfig>> {
+        let servers: Vec<Arc<ProxyConfig>> =
+            self.rtts.keys().filter(|s| self.is_available(s)).cloned().collect();
+        if servers.is_empty() {
+            self.rtts.keys().cloned().collect()
+        } else {
+            servers
+        }
+    }
+
+    // available servers which have not failed in `recovery_period`,
+    // or all the available servers if all of them failed
+    fn usable_servers(&self) -> Vec<Arc<ProxyConfig>> {
+        let servers = self.available_servers();
+        let recovered: Vec<Arc<ProxyConfig>> =
+            servers.iter().filter(|s| self.is_recovered(s)).cloned().collect();
+        if recovered.is_empty() {
+            servers
+        } else {
+            recovered
         }
     }
 
+    // replace the servers of `servers_file` if it is modified
+    fn reload_servers_file(&mut self) {
+        let config = self.config.clone();
+        let path = match config.servers_file {
+            Some(ref path) => path,
+            None => return,
+        };
+        let is_checked = self.file_checked
+            .elapsed()
+            .map(|d| d.as_secs() < RELOAD_INTERVAL)
+            .unwrap_or(false);
+        if is_checked {
+            return;
+        }
+        self.file_checked = SystemTime::now();
+
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified == self.file_modified {
+            return;
+        }
+        self.file_modified = modified;
+
+        match read_servers_file(path, &config.proxy_conf) {
+            Ok(servers) => {
+                // a server also in `servers` of config remains
+                let config_servers = config.server_confs.clone().unwrap_or_default();
+                for server in &self.file_servers {
+                    if !servers.contains(server) && !config_servers.contains(server) {
+                        self.rtts.remove(server);
+                    }
+                }
+                for server in &servers {
+                    self.rtts.entry(server.clone()).or_insert_with(RttRecord::new);
+                }
+                info!("reload {} servers from {}", servers.len(), path.display());
+                self.file_servers = servers;
+                self.on_servers_changed();
+            }
+            // keep the servers loaded before
+            Err(e) => error!("reload servers failed: {:?}", e),
+        }
+    }
+
+    // follow the reloaded config and the changes of servers
+    fn refresh(&mut self) {
+        let config = self.shared_config.get();
+        if !Arc::ptr_eq(&config, &self.config) {
+            // breakers are built with the old parameters
+            if config.breaker_failures != self.config.breaker_failures ||
+               config.breaker_backoff != self.config.breaker_backoff {
+                self.breakers.clear();
+            }
+            self.config = config;
+            self.sync_config();
+        }
+        self.reload_servers_file();
+        self.handle_probes();
+
+        let is_logged = self.stats_logged
+            .elapsed()
+            .map(|d| d.as_secs() < STATS_INTERVAL)
+            .unwrap_or(false);
+        if !is_logged {
+            self.stats_logged = SystemTime::now();
+            for stats in self.stats() {
+                info!("server {}", stats);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Vec<ServerStats> {
+        self.rtts
+            .keys()
+            .map(|server| {
+                let breaker = self.breakers.get(server);
+                ServerStats {
+                    server: server.clone(),
+                    is_healthy: self.is_healthy(server),
+                    connections: self.conn_counts.get(server).cloned().unwrap_or(0),
+                    breaker: breaker.map_or(BreakerState::Closed, |b| b.state()),
+                    trips: breaker.map_or(0, |b| b.trips()),
+                }
+            })
+            .collect()
+    }
+
     pub fn choose(&mut self) -> Option<Arc<ProxyConfig>> {
-        match CONFIG.mode {
+        self.refresh();
+        let server = match self.config.mode {
             Mode::Fast => self.choose_by_weight(),
             Mode::Balance => self.random_choose(),
+            Mode::Failover => self.choose_by_priority(),
+            // the key is unknown yet, see `choose_sticky`
+            Mode::Sticky => return self.random_choose(),
+            Mode::Weighted => self.choose_by_round_robin(),
+            Mode::LeastConn => self.choose_least_conn(),
             _ => unreachable!(),
+        };
+        self.on_chosen(server)
+    }
+
+    // an ejected server is half-open after its backoff, and the chosen connection is the trial
+    fn on_chosen(&mut self, server: Option<Arc<ProxyConfig>>) -> Option<Arc<ProxyConfig>> {
+        if let Some(ref server) = server {
+            let is_changed = self.breakers.get_mut(server).map_or(false, |b| b.on_chosen());
+            if is_changed {
+                info!("circuit breaker of server {}:{} is half-open, try it",
+                      server.address,
+                      server.port);
+            }
         }
+        server
+    }
+
+    /// Choose a server by `key` (destination host or client IP) in sticky mode,
+    /// a key is always mapped to the same server unless the server is unhealthy.
+    pub fn choose_sticky(&mut self, key: &str) -> Option<Arc<ProxyConfig>> {
+        self.refresh();
+        let server = self.ring
+            .find(key, |s| self.is_available(s))
+            .or_else(|| self.ring.get(key))
+            .cloned();
+        self.on_chosen(server)
     }
 
     fn random_choose(&mut self) -> Option<Arc<ProxyConfig>> {
-        let server_confs: Vec<&Arc<ProxyConfig>> = self.rtts.keys().collect();
-        let &server_conf = try_opt!(self.rng.choose(&server_confs));
-        Some(server_conf.clone())
+        let server_confs = self.available_servers();
+        self.rng.choose(&server_confs).cloned()
     }
 
     // This method will choose the last latency server with 80% probability,
@@ -76,31 +405,133 @@ impl ServerChooser {
     fn choose_by_weight(&mut self) -> Option<Arc<ProxyConfig>> {
         let is_choose_min = self.rng.gen::<u8>() < (0.8 * u8::max_value() as f32) as u8;
         if is_choose_min {
-            let mut min_conf = None;
-            let mut min_rtt = None;
+            let rtts = &self.rtts;
+            self.available_servers().into_iter().min_by_key(|conf| rtts[conf])
+        } else {
+            self.random_choose()
+        }
+    }
+
+    // Choose the server with the highest priority among the servers which have
+    // not failed in `recovery_period`, the fastest one is chosen if more than one.
+    fn choose_by_priority(&mut self) -> Option<Arc<ProxyConfig>> {
+        let servers = self.usable_servers();
+        let server = {
+            let rtts = &self.rtts;
+            servers.into_iter().min_by_key(|s| (Reverse(s.priority), rtts[s]))
+        };
+
+        if server != self.active {
+            if let Some(ref s) = server {
+                info!("switch to server {}:{} (priority {})", s.address, s.port, s.priority);
+            }
+            self.active = server.clone();
+        }
+        server
+    }
+
+    // Smooth weighted round-robin of nginx: every time the current weight of
+    // every server grows by its weight, and the server with the largest current
+    // weight is chosen, whose current weight is reduced by the total weight.
+    fn choose_by_round_robin(&mut self) -> Option<Arc<ProxyConfig>> {
+        let servers = self.usable_servers();
+        let mut total = 0;
+        let mut chosen: Option<(Arc<ProxyConfig>, i64)> = None;
+        for server in servers {
+            let weight = server.weight as i64;
+            let current = self.current_weights.entry(server.clone()).or_insert(0);
+            *current += weight;
+            total += weight;
+            if chosen.as_ref().map_or(true, |&(_, max)| *current > max) {
+                chosen = Some((server, *current));
+            }
+        }
+
+        let (server, _) = try_opt!(chosen);
+        self.current_weights.get_mut(&server).map(|current| *current -= total);
+        Some(server)
+    }
 
-            for (conf, rtt) in &self.rtts {
-                if min_rtt.is_none() || min_rtt > Some(rtt) {
-                    min_rtt = Some(rtt);
-                    min_conf = Some(conf);
+    // Choose the server with the fewest TCP connections relative to its weight.
+    fn choose_least_conn(&mut self) -> Option<Arc<ProxyConfig>> {
+        let servers = self.usable_servers();
+        let conn_counts = &self.conn_counts;
+        let count = |s: &Arc<ProxyConfig>| conn_counts.get(s).cloned().unwrap_or(0) as u64;
+        // a / wa < b / wb <=> a * wb < b * wa
+        servers.into_iter().min_by(|a, b| {
+            (count(a) * b.weight as u64).cmp(&(count(b) * a.weight as u64))
+        })
+    }
+
+    /// TCP connection `token` is relayed by `server_conf` from now on.
+    pub fn add_connection(&mut self, token: Token, server_conf: &Arc<ProxyConfig>) {
+        self.remove_connection(token);
+        *self.conn_counts.entry(server_conf.clone()).or_insert(0) += 1;
+        self.connections.insert(token, server_conf.clone());
+    }
+
+    pub fn remove_connection(&mut self, token: Token) {
+        if let Some(server) = self.connections.remove(&token) {
+            let is_idle = match self.conn_counts.get_mut(&server) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
                 }
+                None => false,
+            };
+            if is_idle {
+                self.conn_counts.remove(&server);
             }
+        }
+    }
 
-            min_conf.cloned()
-        } else {
-            self.random_choose()
+    fn is_recovered(&self, server: &Arc<ProxyConfig>) -> bool {
+        let period = self.config.recovery_period;
+        self.failures
+            .get(server)
+            .map_or(true, |t| t.elapsed().map(|d| d.as_secs() >= period).unwrap_or(true))
+    }
+
+    /// A connection to `server_conf` failed, which is not chosen by failover,
+    /// weighted and least_conn mode until `recovery_period` passed.
+    /// Consecutive failures of a server trip its circuit breaker.
+    pub fn fail(&mut self, server_conf: &Arc<ProxyConfig>) {
+        if !self.rtts.contains_key(server_conf) {
+            return;
+        }
+        self.failures.insert(server_conf.clone(), SystemTime::now());
+
+        let config = &self.config;
+        let is_tripped = self.breakers
+            .entry(server_conf.clone())
+            .or_insert_with(|| CircuitBreaker::new(config.breaker_failures, config.breaker_backoff))
+            .on_failure();
+        if is_tripped {
+            warn!("circuit breaker of server {}:{} is open, eject it",
+                  server_conf.address,
+                  server_conf.port);
+        }
+    }
+
+    /// Data is received from `server_conf` successfully.
+    pub fn succeed(&mut self, server_conf: &Arc<ProxyConfig>) {
+        let is_closed = self.breakers.get_mut(server_conf).map_or(false, |b| b.on_success());
+        if is_closed {
+            info!("circuit breaker of server {}:{} is closed, readmit it",
+                  server_conf.address,
+                  server_conf.port);
         }
     }
 
     pub fn record(&mut self, token: Token) {
-        if Mode::Fast == CONFIG.mode {
+        if Mode::Fast == self.config.mode {
             let times = self.activities.entry(token).or_insert_with(VecDeque::new);
             times.push_back(SystemTime::now());
         }
     }
 
     pub fn update(&mut self, token: Token, server_conf: &Arc<ProxyConfig>) {
-        if Mode::Fast == CONFIG.mode {
+        if Mode::Fast == self.config.mode {
             let time = self.activities.get_mut(&token).and_then(|times| times.pop_front());
             match time {
                 Some(time) => {
@@ -114,7 +545,7 @@ impl ServerChooser {
     }
 
     pub fn punish(&mut self, token: Token, server_conf: &Arc<ProxyConfig>) {
-        if Mode::Fast == CONFIG.mode {
+        if Mode::Fast == self.config.mode {
             self.activities.remove(&token);
             self.rtts.get_mut(server_conf).map(|rtt| rtt.punish());
         }
@@ -149,16 +580,20 @@ impl RttRecord {
             .map(|d| d.as_secs() as u32 * 1000 + d.subsec_nanos() / 1000000);
 
         if let Ok(elapsed_ms) = dt {
-            let mut rtt = self.rtt as f32;
-            let mut dev = self.dev as f32;
+            self.add_sample(elapsed_ms);
+        }
+    }
 
-            rtt = 0.875 * rtt + 0.125 * elapsed_ms as f32;
-            dev = 0.75 * dev + 0.25 * (elapsed_ms as f32 - rtt).abs();
+    fn add_sample(&mut self, elapsed_ms: u32) {
+        let mut rtt = self.rtt as f32;
+        let mut dev = self.dev as f32;
 
-            self.rtt = rtt as u32;
-            self.dev = dev as u32;
-            self.update_rto();
-        }
+        rtt = 0.875 * rtt + 0.125 * elapsed_ms as f32;
+        dev = 0.75 * dev + 0.25 * (elapsed_ms as f32 - rtt).abs();
+
+        self.rtt = rtt as u32;
+        self.dev = dev as u32;
+        self.update_rto();
     }
 
     fn punish(&mut self) {
@@ -198,3 +633,261 @@ impl PartialEq for RttRecord {
         self.rto == other.rto
     }
 }
+
+mod health;
+mod breaker;
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+    use std::process;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use mio::Token;
+
+    use config::{Config, SharedConfig, ProxyConfig, read_servers_file};
+    use super::{ServerChooser, Mode};
+
+    fn server(port: u16) -> Arc<ProxyConfig> {
+        let mut conf = ProxyConfig::default();
+        conf.address = "127.0.0.1".to_string();
+        conf.port = port;
+        Arc::new(conf)
+    }
+
+    fn prioritized(port: u16, priority: u32) -> Arc<ProxyConfig> {
+        let mut conf = (*server(port)).clone();
+        conf.priority = priority;
+        Arc::new(conf)
+    }
+
+    fn weighted(port: u16, weight: u32) -> Arc<ProxyConfig> {
+        let mut conf = (*server(port)).clone();
+        conf.weight = weight;
+        Arc::new(conf)
+    }
+
+    fn config(servers: &[&Arc<ProxyConfig>]) -> Config {
+        let mut conf = Config::default();
+        conf.server_confs = Some(servers.iter().map(|&s| s.clone()).collect());
+        conf
+    }
+
+    fn chooser(shared_config: &Arc<SharedConfig>) -> ServerChooser {
+        let mut chooser = ServerChooser::new(shared_config.clone());
+        // ssserver skips it
+        chooser.sync_config();
+        chooser
+    }
+
+    #[test]
+    fn keep_rtt_of_remained_servers() {
+        let (a, b, c) = (server(1), server(2), server(3));
+        let shared_config = Arc::new(SharedConfig::new(config(&[&a, &b])));
+        let mut chooser = chooser(&shared_config);
+        chooser.rtts.get_mut(&a).unwrap().add_sample(800);
+        let rtt = chooser.rtts[&a];
+
+        shared_config.set(config(&[&a, &c]));
+        chooser.refresh();
+        assert_eq!(chooser.rtts.len(), 2);
+        assert_eq!((chooser.rtts[&a].rtt, chooser.rtts[&a].dev), (rtt.rtt, rtt.dev));
+        assert_eq!(chooser.rtts[&c].rtt, 0);
+        assert!(!chooser.rtts.contains_key(&b));
+    }
+
+    #[test]
+    fn reload_servers_file() {
+        fn write_servers(path: &Path, ports: &[u16]) {
+            let servers: Vec<String> = ports.iter()
+                .map(|port| format!("{{\"server\": \"127.0.0.1\", \"server_port\": {}}}", port))
+                .collect();
+            let json = format!("{{\"version\": 1, \"servers\": [{}]}}", servers.join(", "));
+            File::create(path).unwrap().write_all(json.as_bytes()).unwrap();
+        }
+
+        let path = env::temp_dir().join(format!("shadowsocks_reload_{}.json", process::id()));
+        write_servers(&path, &[1, 2, 3]);
+        let mut conf = Config::default();
+        let file_servers = read_servers_file(&path, &conf.proxy_conf).unwrap();
+        // the server of port 1 is in `servers` too
+        conf.server_confs = Some(vec![file_servers[0].clone()]);
+        conf.file_server_confs = Some(file_servers);
+        conf.servers_file = Some(path.clone());
+        let mut chooser = chooser(&Arc::new(SharedConfig::new(conf)));
+        assert_eq!(chooser.rtts.len(), 3);
+
+        // but only the file is reloaded
+        write_servers(&path, &[2]);
+        // the modified time may be the same within the resolution of file system
+        chooser.file_modified = None;
+        chooser.file_checked = UNIX_EPOCH;
+        chooser.refresh();
+        let mut ports: Vec<u16> = chooser.rtts.keys().map(|s| s.port).collect();
+        ports.sort();
+        assert_eq!(ports, vec![1, 2]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failover_by_priority() {
+        let (a, b, c) = (prioritized(1, 1), prioritized(2, 3), prioritized(3, 2));
+        let mut conf = config(&[&a, &b, &c]);
+        conf.mode = Mode::Failover;
+        let recovery_period = conf.recovery_period;
+        let mut chooser = chooser(&Arc::new(SharedConfig::new(conf)));
+        assert_eq!(chooser.choose().map(|s| s.port), Some(2));
+        assert_eq!(chooser.choose().map(|s| s.port), Some(2));
+
+        chooser.fail(&b);
+        assert_eq!(chooser.choose().map(|s| s.port), Some(3));
+        chooser.fail(&c);
+        assert_eq!(chooser.choose().map(|s| s.port), Some(1));
+        assert_eq!(chooser.active, Some(a.clone()));
+
+        // b failed `recovery_period` ago
+        let failed = SystemTime::now() - Duration::from_secs(recovery_period);
+        chooser.failures.insert(b.clone(), failed);
+        assert_eq!(chooser.choose().map(|s| s.port), Some(2));
+        assert_eq!(chooser.active, Some(b.clone()));
+    }
+
+    #[test]
+    fn failover_when_all_failed() {
+        let (a, b) = (prioritized(1, 1), prioritized(2, 2));
+        let mut conf = config(&[&a, &b]);
+        conf.mode = Mode::Failover;
+        let mut chooser = chooser(&Arc::new(SharedConfig::new(conf)));
+        chooser.fail(&a);
+        chooser.fail(&b);
+        // the highest priority is still preferred
+        assert_eq!(chooser.choose().map(|s| s.port), Some(2));
+    }
+
+    #[test]
+    fn sticky_destination() {
+        let (a, b, c) = (server(1), server(2), server(3));
+        let mut conf = config(&[&a, &b, &c]);
+        conf.mode = Mode::Sticky;
+        let mut chooser = chooser(&Arc::new(SharedConfig::new(conf)));
+        let hosts: Vec<String> = (0..100).map(|i| format!("host{}.example.com", i)).collect();
+        let chosen: Vec<_> = hosts.iter().map(|h| chooser.choose_sticky(h).unwrap()).collect();
+        assert!(chosen.contains(&a));
+        for _ in 0..3 {
+            for (host, server) in hosts.iter().zip(&chosen) {
+                assert_eq!(chooser.choose_sticky(host).as_ref(), Some(server));
+            }
+        }
+
+        // only the destinations of the unhealthy server move, until it is healthy again
+        chooser.health.insert(a.clone(), false);
+        for (host, server) in hosts.iter().zip(&chosen) {
+            let moved = chooser.choose_sticky(host).unwrap();
+            if *server == a {
+                assert!(moved != a);
+            } else {
+                assert_eq!(moved, *server);
+            }
+        }
+        chooser.health.insert(a.clone(), true);
+        for (host, server) in hosts.iter().zip(&chosen) {
+            assert_eq!(chooser.choose_sticky(host).as_ref(), Some(server));
+        }
+    }
+
+    #[test]
+    fn sticky_after_password_changed() {
+        let (a, b, c) = (server(1), server(2), server(3));
+        let mut conf = config(&[&a, &b, &c]);
+        conf.mode = Mode::Sticky;
+        let shared_config = Arc::new(SharedConfig::new(conf));
+        let mut chooser = chooser(&shared_config);
+        let hosts: Vec<String> = (0..100).map(|i| format!("host{}.example.com", i)).collect();
+        let chosen: Vec<_> = hosts.iter().map(|h| chooser.choose_sticky(h).unwrap()).collect();
+
+        // servers are placed on the ring by their addresses and ports
+        let changed: Vec<_> = [&a, &b, &c]
+            .iter()
+            .map(|s| {
+                let mut s = (***s).clone();
+                s.password = "changed".to_string();
+                Arc::new(s)
+            })
+            .collect();
+        let mut new_conf = config(&changed.iter().collect::<Vec<_>>());
+        new_conf.mode = Mode::Sticky;
+        shared_config.set(new_conf);
+        for (host, server) in hosts.iter().zip(&chosen) {
+            let moved = chooser.choose_sticky(host).unwrap();
+            assert_eq!(moved.password, "changed");
+            assert_eq!(moved.port, server.port);
+        }
+    }
+
+    #[test]
+    fn smooth_weighted_round_robin() {
+        let (a, b, c) = (weighted(1, 5), weighted(2, 1), weighted(3, 1));
+        let mut conf = config(&[&a, &b, &c]);
+        conf.mode = Mode::Weighted;
+        let mut chooser = chooser(&Arc::new(SharedConfig::new(conf)));
+        for _ in 0..3 {
+            // a a b a c a a, b and c have the same weight, so either of them is the first
+            let round: Vec<_> = (0..7).map(|_| chooser.choose().unwrap()).collect();
+            let weights: Vec<_> = round.iter().map(|s| s.weight).collect();
+            assert_eq!(weights, [5, 5, 1, 5, 1, 5, 5]);
+            assert!(round[2] != round[4]);
+        }
+    }
+
+    #[test]
+    fn least_conn_by_weight() {
+        let (a, b) = (weighted(1, 2), weighted(2, 1));
+        let mut conf = config(&[&a, &b]);
+        conf.mode = Mode::LeastConn;
+        let mut chooser = chooser(&Arc::new(SharedConfig::new(conf)));
+        let mut tokens = vec![];
+        for i in 0..6 {
+            let server = chooser.choose().unwrap();
+            chooser.add_connection(Token(i), &server);
+            tokens.push((Token(i), server));
+        }
+        assert_eq!(chooser.conn_counts[&a], 4);
+        assert_eq!(chooser.conn_counts[&b], 2);
+
+        // a has 2 connections left
+        for &(token, ref server) in tokens.iter().filter(|&&(_, ref s)| *s == a).take(2) {
+            assert_eq!(chooser.connections[&token], *server);
+            chooser.remove_connection(token);
+        }
+        assert_eq!(chooser.conn_counts[&a], 2);
+        assert_eq!(chooser.choose(), Some(a.clone()));
+    }
+
+    #[test]
+    fn skip_unhealthy_and_ejected() {
+        let (a, b, c) = (weighted(1, 5), weighted(2, 3), weighted(3, 1));
+        for &mode in &[Mode::Weighted, Mode::LeastConn] {
+            let mut conf = config(&[&a, &b, &c]);
+            conf.mode = mode;
+            // only the circuit breaker keeps b away
+            conf.recovery_period = 0;
+            let threshold = conf.breaker_failures;
+            let mut chooser = chooser(&Arc::new(SharedConfig::new(conf)));
+            chooser.health.insert(a.clone(), false);
+            for _ in 0..threshold {
+                chooser.fail(&b);
+            }
+            assert!(!chooser.breakers[&b].is_allowed());
+
+            for i in 0..10 {
+                let server = chooser.choose().unwrap();
+                assert_eq!(server, c);
+                chooser.add_connection(Token(i), &server);
+            }
+        }
+    }
+}
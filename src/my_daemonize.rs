@@ -22,7 +22,7 @@ impl FromStr for Cmd {
     }
 }
 
-pub use self::_daemonize::init;
+pub use self::_daemonize::{init, on_sighup};
 
 #[cfg(target_family = "unix")]
 mod _daemonize {
@@ -33,6 +33,7 @@ mod _daemonize {
     use std::str::FromStr;
     use std::process::exit;
     use std::{thread, time};
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::fs::{File, remove_file};
     use std::path::PathBuf;
 
@@ -53,6 +54,23 @@ mod _daemonize {
         }
     }
 
+    static IS_SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn handle_sighup(_: i32) {
+        IS_SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+    }
+
+    /// Call `f` in a background thread after SIGHUP is received.
+    pub fn on_sighup<F: Fn() + Send + 'static>(f: F) {
+        signal!(sig::ffi::Sig::HUP, handle_sighup);
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_secs(1));
+            if IS_SIGHUP_RECEIVED.swap(false, Ordering::SeqCst) {
+                f();
+            }
+        });
+    }
+
     fn daemon_start(pid_file: &PathBuf) {
         let d = daemonize::Daemonize::new().pid_file(pid_file);
         if let Err(e) = d.start() {
@@ -123,4 +141,8 @@ mod _daemonize {
 
     pub fn init(daemon: Cmd, pid_file: &PathBuf) {
     }
+
+    // no SIGHUP on Windows
+    pub fn on_sighup<F: Fn() + Send + 'static>(_f: F) {
+    }
 }
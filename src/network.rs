@@ -1,5 +1,7 @@
 use std::io;
+use std::fmt;
 use std::io::Cursor;
+use std::result;
 use std::convert::From;
 use std::str::FromStr;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
@@ -26,6 +28,32 @@ macro_rules! slice2sized {
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub struct Address(pub String, pub u16);
 
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if is_ipv6(&self.0) {
+            write!(f, "[{}]:{}", self.0, self.1)
+        } else {
+            write!(f, "{}:{}", self.0, self.1)
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    // `host:port` or `[ipv6]:port`
+    fn from_str(s: &str) -> result::Result<Address, String> {
+        let i = s.rfind(':').ok_or_else(|| format!("port of {} is missing", s))?;
+        let host = s[..i].trim_left_matches('[').trim_right_matches(']');
+        let port = s[i + 1..].parse::<u16>().map_err(|_| format!("invalid port of {}", s))?;
+        if is_ip(host) || is_hostname(host) {
+            Ok(Address(host.to_string(), port))
+        } else {
+            Err(format!("invalid host of {}", s))
+        }
+    }
+}
+
 #[allow(non_camel_case_types)]
 pub enum AddressFamily {
     AF_INET,
@@ -107,6 +135,10 @@ pub trait NetworkWriteBytes: WriteBytesExt {
     fn put_i32(&mut self, num: i32) -> io::Result<()> {
         self.write_i32::<NetworkEndian>(num)
     }
+
+    fn put_u64(&mut self, num: u64) -> io::Result<()> {
+        self.write_u64::<NetworkEndian>(num)
+    }
 }
 
 impl NetworkWriteBytes for Vec<u8> {}
@@ -123,6 +155,10 @@ pub trait NetworkReadBytes: ReadBytesExt {
     fn get_u32(&mut self) -> io::Result<u32> {
         self.read_u32::<NetworkEndian>()
     }
+
+    fn get_u64(&mut self) -> io::Result<u64> {
+        self.read_u64::<NetworkEndian>()
+    }
 }
 
 impl<'a> NetworkReadBytes for Cursor<&'a [u8]> {}
@@ -140,10 +176,15 @@ impl<'a> NetworkReadBytes for &'a [u8] {
     fn get_u32(&mut self) -> io::Result<u32> {
         Cursor::new(self).read_u32::<NetworkEndian>()
     }
+
+    fn get_u64(&mut self) -> io::Result<u64> {
+        Cursor::new(self).read_u64::<NetworkEndian>()
+    }
 }
 
 #[macro_export]
 macro_rules! pack {
+    (u64, $r:expr, $v:expr) => ( try_opt!($r.put_u64($v).ok()) );
     (i32, $r:expr, $v:expr) => ( try_opt!($r.put_i32($v).ok()) );
     (u16, $r:expr, $v:expr) => ( try_opt!($r.put_u16($v).ok()) );
     (u8, $r:expr, $v:expr) => ( try_opt!($r.put_u8($v).ok()) );
@@ -151,6 +192,7 @@ macro_rules! pack {
 
 #[macro_export]
 macro_rules! unpack {
+    (u64, $r:expr) => ( try_opt!($r.get_u64().ok()) );
     (u32, $r:expr) => ( try_opt!($r.get_u32().ok()) );
     (u16, $r:expr) => ( try_opt!($r.get_u16().ok()) );
     (u8, $r:expr) => ( try_opt!($r.get_u8().ok()) );
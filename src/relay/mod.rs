@@ -1,15 +1,18 @@
 use std::fmt;
+use std::sync::Arc;
 use std::net::SocketAddr;
 
 use mio::{Handler, Token, EventSet, EventLoop};
 
 use mode::ServerChooser;
-use config::CONFIG;
+use config::{Config, ProxyConfig, SharedConfig};
 use network::pair2addr;
 use collections::Holder;
 use asyncdns::{DnsResolver, Caller, HostIpPair};
 use util::{RcCell, new_rc_cell};
 use error::{DnsError, SocketError, Result};
+use crypto::Role;
+use crypto::replay::ReplayFilter;
 use crypto::error::Error as CryptoError;
 
 pub use self::tcp_relay::TcpRelay;
@@ -24,6 +27,7 @@ pub enum Error {
     EncryptFailed,
     DecryptFailed,
     NoServerAvailable,
+    UnknownUser,
     InitEncryptorFailed(CryptoError),
 }
 
@@ -38,6 +42,7 @@ impl fmt::Debug for Error {
             Error::EncryptFailed => write!(f, "encrypt data failed"),
             Error::DecryptFailed => write!(f, "decrypt data failed"),
             Error::NoServerAvailable => write!(f, "no ssserver available"),
+            Error::UnknownUser => write!(f, "no user matches the request"),
             Error::InitEncryptorFailed(ref e) => write!(f, "init encryptor failed ({:?})", e),
         }
     }
@@ -87,36 +92,59 @@ pub trait MyHandler {
     fn timeout(&mut self, event_loop: &mut EventLoop<Relay>, token: Token);
 }
 
-fn init_relay<T: MyHandler, P: Caller, F>(f: F) -> Result<T>
-    where F: FnOnce(Token,
+// `f` receives a token, an address and a config for every port to listen on
+fn init_relay<T: MyHandler, P: Caller, F>(config: &Arc<SharedConfig>, f: F) -> Result<T>
+    where F: FnOnce(Vec<(Token, SocketAddr, Arc<ProxyConfig>)>,
                     Token,
                     RcCell<DnsResolver>,
                     RcCell<ServerChooser>,
-                    Holder<RcCell<P>>,
-                    SocketAddr)
+                    Holder<RcCell<P>>)
                     -> Result<T>
 {
+    let shared_config = config.clone();
+    let config = config.get();
+
     let mut processors = Holder::new();
-    let token = processors.alloc_token().ok_or(SocketError::AllocTokenFailed)?;
     let dns_token = processors.alloc_token().ok_or(SocketError::AllocTokenFailed)?;
 
-    let mut dns_resolver = DnsResolver::new(dns_token, None, CONFIG.prefer_ipv6)?;
-    let server_chooser = ServerChooser::new();
+    let mut dns_resolver = DnsResolver::new(dns_token, None, config.prefer_ipv6)?;
+    let server_chooser = ServerChooser::new(shared_config);
 
-    let host = CONFIG.address().clone();
-    let port = CONFIG.port();
+    let host = config.address().clone();
 
     let HostIpPair(_host, ip) = dns_resolver.block_resolve(host)
         .and_then(|h| h.ok_or(From::from(DnsError::Timeout)))?;
 
-    let socket_addr = pair2addr(&ip, port)?;
+    let mut listeners = vec![];
+    for proxy_conf in config.listen_confs() {
+        let token = processors.alloc_token().ok_or(SocketError::AllocTokenFailed)?;
+        let socket_addr = pair2addr(&ip, proxy_conf.port)?;
+        listeners.push((token, socket_addr, proxy_conf));
+    }
 
-    f(token,
+    f(listeners,
       dns_token,
       new_rc_cell(dns_resolver),
       new_rc_cell(server_chooser),
-      processors,
-      socket_addr)
+      processors)
+}
+
+// only ssserver: every relay remembers the IVs of the requests it received
+fn new_replay_filter(config: &Config) -> Option<ReplayFilter> {
+    if cfg!(feature = "sslocal") {
+        None
+    } else {
+        Some(ReplayFilter::new(config.replay_filter_capacity))
+    }
+}
+
+// sslocal is the client of ssserver
+fn role() -> Role {
+    if cfg!(feature = "sslocal") {
+        Role::Client
+    } else {
+        Role::Server
+    }
 }
 
 mod tcp_relay;
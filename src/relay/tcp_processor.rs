@@ -1,5 +1,6 @@
 use std::io;
 use std::fmt;
+use std::mem;
 use std::sync::Arc;
 use std::borrow::{Cow, Borrow};
 use std::io::{Read, Write};
@@ -8,20 +9,23 @@ use std::net::SocketAddr;
 use mio::tcp::{TcpStream, Shutdown};
 use mio::{EventLoop, Token, Timeout, EventSet, PollOpt};
 
-use mode::ServerChooser;
+use mode::{Mode, ServerChooser, StickyKey};
 use socks5;
 use socks5::{addr_type, Socks5Header};
 use util::{RcCell, shift_vec};
-use config::{CONFIG, ProxyConfig};
+use config::{Config, ProxyConfig, UserConfig};
 use crypto::Encryptor;
+use crypto::replay::ReplayFilter;
 use asyncdns::{Caller, DnsResolver, HostIpPair};
 use network::{pair2addr, NetworkWriteBytes, Address};
 use socks5::{pack_addr, parse_header, check_auth_method, CheckAuthResult};
 use error;
 use error::{Result, SocketError, ProcessError, Socks5Error};
-use super::Relay;
+use super::{role, Relay};
 
 pub struct TcpProcessor {
+    // the config when the connection is accepted, kept even if reloaded
+    config: Arc<Config>,
     proxy_conf: Arc<ProxyConfig>,
     server_chooser: RcCell<ServerChooser>,
     dns_resolver: RcCell<DnsResolver>,
@@ -35,17 +39,29 @@ pub struct TcpProcessor {
     remote_interest: EventSet,
     local_buf: Option<Vec<u8>>,
     remote_buf: Option<Vec<u8>>,
+    receive_buf: Option<Vec<u8>>,
     client_address: Address,
     server_address: Option<Address>,
     encryptor: Encryptor,
+    // only ssserver: the user identified by the first chunk, and the data received before
+    user: Option<Arc<UserConfig>>,
+    unidentified_buf: Vec<u8>,
+    replay_filter: Option<ReplayFilter>,
+    // only sslocal: whether the chosen ssserver has been reported to `server_chooser`
+    is_server_reported: bool,
 }
 
 impl TcpProcessor {
+    /// `proxy_conf` is the config of the port accepting `local_sock`,
+    /// sslocal uses the config of the chosen ssserver instead.
     pub fn new(local_token: Token,
                remote_token: Token,
                local_sock: TcpStream,
+               proxy_conf: &Arc<ProxyConfig>,
+               config: Arc<Config>,
                dns_resolver: &RcCell<DnsResolver>,
-               server_chooser: &RcCell<ServerChooser>)
+               server_chooser: &RcCell<ServerChooser>,
+               replay_filter: &Option<ReplayFilter>)
                -> Result<TcpProcessor> {
         let stage = if cfg!(feature = "sslocal") {
             HandleStage::Handshake1
@@ -58,11 +74,15 @@ impl TcpProcessor {
                 server_chooser.borrow_mut().choose().ok_or(ProcessError::NoServerAvailable)?;
             (Some(Address(proxy_conf.address.clone(), proxy_conf.port)), proxy_conf)
         } else {
-            (None, CONFIG.proxy_conf.clone())
+            (None, proxy_conf.clone())
         };
 
-        let encryptor = Encryptor::new(&proxy_conf.password, proxy_conf.method)
+        let mut encryptor = Encryptor::new(&proxy_conf.password, proxy_conf.method)
             .map_err(ProcessError::InitEncryptorFailed)?;
+        encryptor.set_role(role());
+        if let Some(ref filter) = *replay_filter {
+            encryptor.set_replay_filter(filter.clone());
+        }
 
         // TODO: this is a bug of mio 0.5.x (fixed in mio 0.6.x)
         let client_address = if cfg!(windows) {
@@ -74,7 +94,8 @@ impl TcpProcessor {
 
         local_sock.set_nodelay(true)?;
 
-        Ok(TcpProcessor {
+        let processor = TcpProcessor {
+            config: config,
             proxy_conf: proxy_conf,
             server_chooser: server_chooser.clone(),
             dns_resolver: dns_resolver.clone(),
@@ -86,12 +107,21 @@ impl TcpProcessor {
             remote_sock: None,
             local_buf: None,
             remote_buf: None,
+            receive_buf: Some(Vec::with_capacity(BUF_SIZE)),
             client_address: client_address,
             server_address: server_address,
             encryptor: encryptor,
+            user: None,
+            unidentified_buf: Vec::new(),
+            replay_filter: replay_filter.clone(),
+            is_server_reported: false,
             local_interest: EventSet::readable(),
             remote_interest: EventSet::readable() | EventSet::writable(),
-        })
+        };
+        if cfg!(feature = "sslocal") {
+            server_chooser.borrow_mut().add_connection(remote_token, &processor.proxy_conf);
+        }
+        Ok(processor)
     }
 
     fn get_sock(&mut self, is_local_sock: bool) -> &mut TcpStream {
@@ -130,6 +160,18 @@ impl TcpProcessor {
         self.set_buf(buf, is_local_sock);
     }
 
+    // buffer `data[offset..]`, take the memory of `data` if nothing is pending
+    fn buffer_data(&mut self, data: &mut Vec<u8>, offset: usize, is_local_sock: bool) {
+        let mut buf = self.get_buf(is_local_sock);
+        if buf.is_empty() {
+            mem::swap(&mut buf, data);
+            shift_vec(&mut buf, offset);
+        } else {
+            buf.extend_from_slice(&data[offset..]);
+        }
+        self.set_buf(buf, is_local_sock);
+    }
+
     pub fn reset_timeout(&mut self, event_loop: &mut EventLoop<Relay>) {
         if self.timeout.is_some() {
             let timeout = self.timeout.take().unwrap();
@@ -199,6 +241,20 @@ impl TcpProcessor {
         self.do_register(event_loop, is_local_sock, LOCAL)
     }
 
+    // a connection reports the chosen ssserver once, by its first answer or error
+    fn report_server(&mut self, is_ok: bool) {
+        if self.is_server_reported {
+            return;
+        }
+        self.is_server_reported = true;
+        let mut server_chooser = self.server_chooser.borrow_mut();
+        if is_ok {
+            server_chooser.succeed(&self.proxy_conf);
+        } else {
+            server_chooser.fail(&self.proxy_conf);
+        }
+    }
+
     fn record_activity(&self) {
         match self.stage {
             HandleStage::Handshake3 |
@@ -221,8 +277,25 @@ impl TcpProcessor {
         }
     }
 
+    // the returned buffer should be put back to `receive_buf` to be reused
     fn receive_data(&mut self, is_local_sock: bool) -> Result<Vec<u8>> {
-        let mut buf = Vec::with_capacity(BUF_SIZE);
+        let mut buf = self.receive_buf.take().unwrap_or_else(Vec::new);
+        match self.receive_into(is_local_sock, &mut buf) {
+            Ok(()) => Ok(buf),
+            Err(e) => {
+                self.put_back_receive_buf(buf);
+                Err(e)
+            }
+        }
+    }
+
+    fn put_back_receive_buf(&mut self, mut buf: Vec<u8>) {
+        buf.clear();
+        self.receive_buf = Some(buf);
+    }
+
+    fn receive_into(&mut self, is_local_sock: bool, buf: &mut Vec<u8>) -> Result<()> {
+        buf.reserve(BUF_SIZE);
         new_fat_slice_from_vec!(buf_slice, buf);
 
         // to avoid the stupid borrow error
@@ -248,9 +321,79 @@ impl TcpProcessor {
 
         if (cfg!(feature = "sslocal") && !is_local_sock) ||
            (!cfg!(feature = "sslocal") && is_local_sock) {
-            self.encryptor.decrypt(&buf).ok_or(From::from(ProcessError::DecryptFailed))
+            let config = self.config.clone();
+            match config.user_confs {
+                Some(ref users) if !cfg!(feature = "sslocal") && self.user.is_none() => {
+                    self.identify_user(users, buf)?;
+                }
+                _ => {
+                    let is_decrypted = self.encryptor.decrypt_in_place(buf).is_some();
+                    // the server answered properly or not
+                    if cfg!(feature = "sslocal") {
+                        self.report_server(is_decrypted);
+                    }
+                    if !is_decrypted {
+                        return err_from!(ProcessError::DecryptFailed);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Try the key of every user on the data received so far, then switch to the matched user.
+    // The address header may arrive in pieces, so the data is kept until a key decrypts
+    // (and for AEAD ciphers, authenticates) a complete header.
+    fn identify_user(&mut self, users: &[Arc<UserConfig>], data: &mut Vec<u8>) -> Result<()> {
+        let mut received = mem::replace(&mut self.unidentified_buf, Vec::new());
+        received.extend_from_slice(data);
+        data.clear();
+
+        let mut is_undecided = false;
+        for user in users {
+            let conf = &user.proxy_conf;
+            let mut encryptor = Encryptor::new(&conf.password, conf.method)
+                .map_err(ProcessError::InitEncryptorFailed)?;
+            encryptor.set_role(role());
+            if let Some(ref filter) = self.replay_filter {
+                encryptor.set_replay_filter(filter.clone());
+            }
+            // stream ciphers can't tell a wrong key from an incomplete header
+            let is_short = !conf.method.is_aead() &&
+                           received.len() < conf.method.info().1 + MAX_HEADER_LEN;
+            let decrypted = match encryptor.probe(&received) {
+                // wait the rest of the first AEAD chunk
+                Some(ref decrypted) if decrypted.is_empty() => {
+                    is_undecided = true;
+                    continue;
+                }
+                Some(ref decrypted) if parse_header(decrypted).is_none() => {
+                    is_undecided |= is_short;
+                    continue;
+                }
+                Some(decrypted) => decrypted,
+                None => {
+                    is_undecided |= is_short;
+                    continue;
+                }
+            };
+            if !encryptor.check_replay(&received, false) {
+                return err_from!(ProcessError::DecryptFailed);
+            }
+
+            *data = decrypted;
+            self.encryptor = encryptor;
+            self.proxy_conf = conf.clone();
+            self.user = Some(user.clone());
+            debug!("{:?} is identified", self);
+            return Ok(());
+        }
+
+        if is_undecided && received.len() <= MAX_UNIDENTIFIED_LEN {
+            self.unidentified_buf = received;
+            Ok(())
         } else {
-            Ok(buf)
+            err_from!(ProcessError::UnknownUser)
         }
     }
 
@@ -267,22 +410,18 @@ impl TcpProcessor {
     // data => remote_sock => ssserver/server
     fn handle_stage_stream(&mut self,
                            _event_loop: &mut EventLoop<Relay>,
-                           data: &[u8])
+                           data: &mut Vec<u8>)
                            -> Result<()> {
         trace!("{:?} handle stage stream", self);
 
-        let mut data = Cow::Borrowed(data);
-        if cfg!(feature = "sslocal") {
-            match self.encryptor.encrypt(data.borrow()) {
-                Some(encrypted) => data = Cow::Owned(encrypted),
-                None => return err_from!(ProcessError::EncryptFailed),
-            }
+        if cfg!(feature = "sslocal") && self.encryptor.encrypt_in_place(data).is_none() {
+            return err_from!(ProcessError::EncryptFailed);
         }
 
-        let nwrite = self.write_to_sock(data.borrow(), REMOTE)?;
+        let nwrite = self.write_to_sock(data, REMOTE)?;
         let is_finished = data.len() == nwrite;
         if !is_finished {
-            self.extend_buf(&data[nwrite..], REMOTE);
+            self.buffer_data(data, nwrite, REMOTE);
         }
         self.update_interest_depend_on(is_finished, REMOTE);
         Ok(())
@@ -290,19 +429,15 @@ impl TcpProcessor {
 
     fn handle_stage_connecting(&mut self,
                                _event_loop: &mut EventLoop<Relay>,
-                               data: &[u8])
+                               data: &mut Vec<u8>)
                                -> Result<()> {
         trace!("{:?} handle stage connecting", self);
 
-        let mut data = Cow::Borrowed(data);
-        if cfg!(feature = "sslocal") {
-            match self.encryptor.encrypt(data.borrow()) {
-                Some(encrypted) => data = Cow::Owned(encrypted),
-                None => return err_from!(ProcessError::EncryptFailed),
-            }
+        if cfg!(feature = "sslocal") && self.encryptor.encrypt_in_place(data).is_none() {
+            return err_from!(ProcessError::EncryptFailed);
         }
 
-        self.extend_buf(data.borrow(), REMOTE);
+        self.buffer_data(data, 0, REMOTE);
         Ok(())
     }
 
@@ -327,6 +462,11 @@ impl TcpProcessor {
     }
 
     fn check_one_time_auth(&mut self, addr_type: u8) -> Result<bool> {
+        // AEAD ciphers authenticate every chunk, so OTA is meaningless
+        if self.proxy_conf.method.is_aead() {
+            return Ok(false);
+        }
+
         let is_ota_enabled = self.proxy_conf.one_time_auth;
         let is_ota_session = if cfg!(feature = "sslocal") {
             is_ota_enabled
@@ -402,8 +542,11 @@ impl TcpProcessor {
         trace!("{:?} handle stage handshake3", self);
         let Socks5Header(addr_type, remote_address, remote_port, header_length) =
             parse_header(data).ok_or(Socks5Error::InvalidHeader)?;
-        info!("connecting to {}:{}", remote_address, remote_port);
+        info!("{:?} connecting to {}:{}", self, remote_address, remote_port);
         self.stage = HandleStage::Connecting;
+        if cfg!(feature = "sslocal") && self.config.mode == Mode::Sticky {
+            self.choose_sticky_server(&remote_address)?;
+        }
 
         let is_ota_session = self.check_one_time_auth(addr_type)?;
         let data = if is_ota_session {
@@ -444,17 +587,47 @@ impl TcpProcessor {
         }
     }
 
+    // the server of sticky mode depends on the destination or the client,
+    // so it is chosen again after the request is parsed
+    fn choose_sticky_server(&mut self, remote_address: &str) -> Result<()> {
+        let key = match self.config.sticky_key {
+            StickyKey::Destination => remote_address.to_string(),
+            StickyKey::Client => self.client_address.0.clone(),
+        };
+        let proxy_conf = self.server_chooser
+            .borrow_mut()
+            .choose_sticky(&key)
+            .ok_or(ProcessError::NoServerAvailable)?;
+        if proxy_conf != self.proxy_conf {
+            self.encryptor = Encryptor::new(&proxy_conf.password, proxy_conf.method)
+                .map_err(ProcessError::InitEncryptorFailed)?;
+            self.server_address = Some(Address(proxy_conf.address.clone(), proxy_conf.port));
+            self.server_chooser.borrow_mut().add_connection(self.get_id(), &proxy_conf);
+            self.proxy_conf = proxy_conf;
+        }
+        Ok(())
+    }
+
     fn on_local_read(&mut self, event_loop: &mut EventLoop<Relay>) -> Result<()> {
-        let data = self.receive_data(LOCAL)?;
+        let mut data = self.receive_data(LOCAL)?;
         self.reset_timeout(event_loop);
-        match self.stage {
-            HandleStage::Handshake1 => self.handle_stage_handshake1(event_loop, &data),
-            HandleStage::Handshake2 => self.handle_stage_handshake2(event_loop, &data),
-            HandleStage::Handshake3 => self.handle_stage_handshake3(event_loop, &data),
-            HandleStage::Connecting => self.handle_stage_connecting(event_loop, &data),
-            HandleStage::Stream => self.handle_stage_stream(event_loop, &data),
-            _ => Ok(()),
-        }
+
+        // wait a complete AEAD chunk
+        let res = if data.is_empty() {
+            Ok(())
+        } else {
+            match self.stage {
+                HandleStage::Handshake1 => self.handle_stage_handshake1(event_loop, &data),
+                HandleStage::Handshake2 => self.handle_stage_handshake2(event_loop, &data),
+                HandleStage::Handshake3 => self.handle_stage_handshake3(event_loop, &data),
+                HandleStage::Connecting => self.handle_stage_connecting(event_loop, &mut data),
+                HandleStage::Stream => self.handle_stage_stream(event_loop, &mut data),
+                _ => Ok(()),
+            }
+        };
+
+        self.put_back_receive_buf(data);
+        res
     }
 
     // remote_sock <= data
@@ -463,19 +636,29 @@ impl TcpProcessor {
         self.reset_timeout(event_loop);
 
         let mut data = self.receive_data(REMOTE)?;
-        if !cfg!(feature = "sslocal") {
-            match self.encryptor.encrypt(&data) {
-                Some(encrypted) => data = encrypted,
-                None => return err_from!(ProcessError::EncryptFailed),
-            }
+        // wait a complete AEAD chunk
+        let res = if data.is_empty() {
+            Ok(())
+        } else {
+            self.send_to_local(&mut data)
+        };
+
+        self.put_back_receive_buf(data);
+        res
+    }
+
+    fn send_to_local(&mut self, data: &mut Vec<u8>) -> Result<()> {
+        if !cfg!(feature = "sslocal") && self.encryptor.encrypt_in_place(data).is_none() {
+            return err_from!(ProcessError::EncryptFailed);
         }
 
         // buffer unfinished bytes
-        let nwrite = self.write_to_sock(&data, LOCAL)?;
-        if nwrite < data.len() {
-            self.extend_buf(&data[nwrite..], LOCAL);
+        let nwrite = self.write_to_sock(data, LOCAL)?;
+        let is_finished = data.len() == nwrite;
+        if !is_finished {
+            self.buffer_data(data, nwrite, LOCAL);
         }
-        self.update_interest_depend_on(data.len() == nwrite, LOCAL);
+        self.update_interest_depend_on(is_finished, LOCAL);
         Ok(())
     }
 
@@ -536,6 +719,9 @@ impl TcpProcessor {
         } else if token == self.remote_token {
             if events.is_error() {
                 let e = self.remote_sock.take().unwrap().take_socket_error().unwrap_err();
+                if cfg!(feature = "sslocal") {
+                    self.report_server(false);
+                }
                 if e.kind() != io::ErrorKind::ConnectionReset {
                     error!("events error on {:?}-remote: {}", self, e);
                     return err_from!(SocketError::EventError);
@@ -579,7 +765,9 @@ impl TcpProcessor {
         }
 
         if cfg!(feature = "sslocal") {
-            self.server_chooser.borrow_mut().punish(self.get_id(), &self.proxy_conf);
+            let mut server_chooser = self.server_chooser.borrow_mut();
+            server_chooser.punish(self.get_id(), &self.proxy_conf);
+            server_chooser.remove_connection(self.get_id());
         }
 
         self.dns_resolver.borrow_mut().remove_caller(self.get_id());
@@ -638,11 +826,18 @@ impl Caller for TcpProcessor {
 impl fmt::Debug for TcpProcessor {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let Address(ref ip, ref port) = self.client_address;
-        write!(f, "{}:{}/tcp", ip, port)
+        match self.user {
+            Some(ref user) => write!(f, "{}@{}:{}/tcp", user.name, ip, port),
+            None => write!(f, "{}:{}/tcp", ip, port),
+        }
     }
 }
 
 const BUF_SIZE: usize = 32 * 1024;
+// the longest address header: type, length, 255 bytes of hostname and port
+const MAX_HEADER_LEN: usize = 1 + 1 + 255 + 2;
+// the first chunk of shadowsocks 2022 can be 64KB
+const MAX_UNIDENTIFIED_LEN: usize = 0x10000 + 1024;
 pub const LOCAL: bool = true;
 pub const REMOTE: bool = false;
 
@@ -1,42 +1,53 @@
+use std::sync::Arc;
+
 use mio::tcp::{TcpListener, TcpStream};
 use mio::{Token, EventSet, EventLoop, PollOpt};
 
 use mode::ServerChooser;
-use collections::Holder;
+use config::{ProxyConfig, SharedConfig};
+use collections::{Holder, Dict};
 use asyncdns::DnsResolver;
 use util::{RcCell, new_rc_cell};
+use crypto::replay::ReplayFilter;
 use error::{Result, SocketError, Error as UnionError};
-use super::{init_relay, TcpProcessor, MyHandler, Relay};
+use super::{init_relay, new_replay_filter, TcpProcessor, MyHandler, Relay};
 use super::tcp_processor::LOCAL;
 
 pub struct TcpRelay {
-    token: Token,
-    listener: TcpListener,
+    config: Arc<SharedConfig>,
+    // listening sockets and the config of their ports
+    listeners: Dict<Token, (TcpListener, Arc<ProxyConfig>)>,
     dns_token: Token,
     dns_resolver: RcCell<DnsResolver>,
     server_chooser: RcCell<ServerChooser>,
     processors: Holder<RcCell<TcpProcessor>>,
+    replay_filter: Option<ReplayFilter>,
 }
 
 impl TcpRelay {
-    pub fn new() -> Result<TcpRelay> {
-        init_relay(|token, dns_token, dns_resolver, server_chooser, processors, socket_addr| {
-            let listener =
-                TcpListener::bind(&socket_addr).or(Err(SocketError::BindAddrFailed(socket_addr)))?;
+    pub fn new(config: Arc<SharedConfig>) -> Result<TcpRelay> {
+        init_relay(&config, |listen_confs, dns_token, dns_resolver, server_chooser, processors| {
+            let mut listeners = Dict::default();
+            for (token, socket_addr, proxy_conf) in listen_confs {
+                let listener = TcpListener::bind(&socket_addr)
+                    .or(Err(SocketError::BindAddrFailed(socket_addr)))?;
 
-            if cfg!(feature = "sslocal") {
-                info!("ssclient tcp relay listen on {}", socket_addr);
-            } else {
-                info!("ssserver tcp relay listen on {}", socket_addr);
+                if cfg!(feature = "sslocal") {
+                    info!("ssclient tcp relay listen on {}", socket_addr);
+                } else {
+                    info!("ssserver tcp relay listen on {}", socket_addr);
+                }
+                listeners.insert(token, (listener, proxy_conf));
             }
 
             Ok(TcpRelay {
-                token: token,
-                listener: listener,
+                config: config.clone(),
+                listeners: listeners,
                 dns_token: dns_token,
                 dns_resolver: dns_resolver,
                 server_chooser: server_chooser,
                 processors: processors,
+                replay_filter: new_replay_filter(&config.get()),
             })
         })
     }
@@ -44,11 +55,13 @@ impl TcpRelay {
     /// start event loop
     pub fn run(self) -> Result<()> {
         let mut event_loop = EventLoop::new()?;
-        event_loop.register(&self.listener,
-                      self.token,
-                      EventSet::readable(),
-                      PollOpt::edge() | PollOpt::oneshot())
-            .or(Err(SocketError::RegisterFailed))?;
+        for (&token, &(ref listener, _)) in &self.listeners {
+            event_loop.register(listener,
+                          token,
+                          EventSet::readable(),
+                          PollOpt::edge() | PollOpt::oneshot())
+                .or(Err(SocketError::RegisterFailed))?;
+        }
         self.dns_resolver
             .borrow_mut()
             .register(&mut event_loop)
@@ -68,13 +81,17 @@ impl TcpRelay {
                         event_loop: &mut EventLoop<Relay>,
                         local_token: Token,
                         remote_token: Token,
-                        conn: TcpStream)
+                        conn: TcpStream,
+                        proxy_conf: &Arc<ProxyConfig>)
                         -> Result<()> {
         let p = TcpProcessor::new(local_token,
                                   remote_token,
                                   conn,
+                                  proxy_conf,
+                                  self.config.get(),
                                   &self.dns_resolver,
-                                  &self.server_chooser)?;
+                                  &self.server_chooser,
+                                  &self.replay_filter)?;
         let p = new_rc_cell(p);
         self.processors.insert_with(local_token, p.clone());
         self.processors.insert_with(remote_token, p.clone());
@@ -92,35 +109,44 @@ impl TcpRelay {
     }
 
     /// Create `TcpProcessor` to handle the new TCP connection.
-    fn handle_events(&mut self, event_loop: &mut EventLoop<Relay>, events: EventSet) -> Result<()> {
-        event_loop.reregister(&self.listener,
-                        self.token,
-                        EventSet::readable(),
-                        PollOpt::edge() | PollOpt::oneshot())?;
-        if events.is_error() {
-            error!("events error on tcp relay: {:?}",
-                   self.listener.take_socket_error().unwrap_err());
-            return err_from!(SocketError::EventError);
-        }
+    fn handle_events(&mut self,
+                     event_loop: &mut EventLoop<Relay>,
+                     token: Token,
+                     events: EventSet)
+                     -> Result<()> {
+        let (conn, proxy_conf) = {
+            let &(ref listener, ref proxy_conf) = &self.listeners[&token];
+            event_loop.reregister(listener,
+                            token,
+                            EventSet::readable(),
+                            PollOpt::edge() | PollOpt::oneshot())?;
+            if events.is_error() {
+                error!("events error on tcp relay: {:?}",
+                       listener.take_socket_error().unwrap_err());
+                return err_from!(SocketError::EventError);
+            }
 
-        match self.listener.accept()? {
-            Some((conn, _addr)) => {
-                debug!("create tcp processor for {}", _addr);
-                let tokens = (self.processors.alloc_token(), self.processors.alloc_token());
-                if let (Some(local_token), Some(remote_token)) = tokens {
-                    self.create_processor(event_loop, local_token, remote_token, conn)
-                } else {
-                    match tokens {
-                        (None, None) => {}
-                        (Some(t), None) | (None, Some(t)) => {
-                            self.processors.remove(t);
-                        }
-                        _ => {}
-                    }
-                    err_from!(SocketError::AllocTokenFailed)
+            match listener.accept()? {
+                Some((conn, _addr)) => {
+                    debug!("create tcp processor for {}", _addr);
+                    (conn, proxy_conf.clone())
+                }
+                None => return Ok(()),
+            }
+        };
+
+        let tokens = (self.processors.alloc_token(), self.processors.alloc_token());
+        if let (Some(local_token), Some(remote_token)) = tokens {
+            self.create_processor(event_loop, local_token, remote_token, conn, &proxy_conf)
+        } else {
+            match tokens {
+                (None, None) => {}
+                (Some(t), None) | (None, Some(t)) => {
+                    self.processors.remove(t);
                 }
+                _ => {}
             }
-            None => Ok(()),
+            err_from!(SocketError::AllocTokenFailed)
         }
     }
 }
@@ -128,8 +154,8 @@ impl TcpRelay {
 impl MyHandler for TcpRelay {
     /// Dispatch events to relative handler.
     fn ready(&mut self, event_loop: &mut EventLoop<Relay>, token: Token, events: EventSet) {
-        if token == self.token {
-            if let Err(e) = self.handle_events(event_loop, events) {
+        if self.listeners.contains_key(&token) {
+            if let Err(e) = self.handle_events(event_loop, token, events) {
                 error!("tcp relay: {:?}", e);
             }
         } else if token == self.dns_token {
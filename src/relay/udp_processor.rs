@@ -8,8 +8,8 @@ use mio::udp::UdpSocket;
 use mio::{EventSet, Token, Timeout, EventLoop, PollOpt};
 
 use mode::ServerChooser;
-use util::RcCell;
-use config::{CONFIG, ProxyConfig};
+use util::{RcCell, new_rc_cell};
+use config::{Config, ProxyConfig};
 use collections::Dict;
 use crypto::Encryptor;
 use socks5::{parse_header, pack_addr, addr_type, Socks5Header};
@@ -17,7 +17,7 @@ use network::{pair2addr, NetworkWriteBytes};
 use asyncdns::{Caller, DnsResolver, HostIpPair};
 use error;
 use error::{Result, SocketError, ProcessError, Socks5Error};
-use super::Relay;
+use super::{role, Relay};
 
 type Socks5Requests = Vec<Vec<u8>>;
 type PortRequestMap = Dict<u16, Socks5Requests>;
@@ -43,11 +43,12 @@ impl UdpProcessor {
                addr: SocketAddr,
                relay_sock: &RcCell<UdpSocket>,
                proxy_conf: &Arc<ProxyConfig>,
+               config: &Config,
                dns_resolver: &RcCell<DnsResolver>,
                server_chooser: &RcCell<ServerChooser>,
                encryptor: &RcCell<Encryptor>)
                -> Result<UdpProcessor> {
-        let sock = if CONFIG.prefer_ipv6 {
+        let sock = if config.prefer_ipv6 {
             UdpSocket::v6()
         } else {
             UdpSocket::v4()
@@ -55,6 +56,16 @@ impl UdpProcessor {
 
         let sock = sock.map_err(|_| SocketError::InitSocketFailed)?;
 
+        // every client has its own session in shadowsocks 2022
+        let encryptor = if !cfg!(feature = "sslocal") && proxy_conf.method.is_aead_2022() {
+            let mut encryptor = Encryptor::new(&proxy_conf.password, proxy_conf.method)
+                .map_err(ProcessError::InitEncryptorFailed)?;
+            encryptor.set_role(role());
+            new_rc_cell(encryptor)
+        } else {
+            encryptor.clone()
+        };
+
         Ok(UdpProcessor {
             proxy_conf: proxy_conf.clone(),
             token: token,
@@ -66,12 +77,16 @@ impl UdpProcessor {
             relay_sock: relay_sock.clone(),
             receive_buf: Some(Vec::with_capacity(BUF_SIZE)),
             requests: Dict::default(),
-            encryptor: encryptor.clone(),
+            encryptor: encryptor,
             dns_resolver: dns_resolver.clone(),
             server_chooser: server_chooser.clone(),
         })
     }
 
+    pub fn set_udp_peer_session_id(&mut self, session_id: Option<u64>) {
+        self.encryptor.borrow_mut().set_udp_peer_session_id(session_id);
+    }
+
     pub fn addr(&self) -> &SocketAddr {
         &self.addr
     }
@@ -162,7 +177,9 @@ impl UdpProcessor {
         self.stage = HandleStage::Addr;
         self.reset_timeout(event_loop);
 
-        let is_ota_enabled = self.proxy_conf.one_time_auth;
+        // AEAD ciphers authenticate every packet, so OTA is meaningless
+        let is_aead = self.proxy_conf.method.is_aead();
+        let is_ota_enabled = self.proxy_conf.one_time_auth && !is_aead;
         let request = if cfg!(feature = "sslocal") {
             // if is a OTA session
             let encrypted: Option<Vec<u8>> = if is_ota_enabled {
@@ -177,7 +194,7 @@ impl UdpProcessor {
             Cow::Owned(encrypted)
         } else {
             // if is a OTA session
-            if addr_type & addr_type::AUTH == addr_type::AUTH {
+            if !is_aead && addr_type & addr_type::AUTH == addr_type::AUTH {
                 let decrypted: Option<Vec<u8>> = self.encryptor
                     .borrow_mut()
                     .decrypt_udp_ota(addr_type, data);
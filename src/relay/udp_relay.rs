@@ -18,6 +18,7 @@
 // +-------+--------------+
 // | Fixed |   Variable   |
 // +-------+--------------+
+use std::rc::Rc;
 use std::sync::Arc;
 use std::net::SocketAddr;
 
@@ -26,79 +27,132 @@ use mio::{Token, EventSet, EventLoop, PollOpt};
 
 use mode::ServerChooser;
 use util::{RcCell, new_rc_cell};
-use config::{CONFIG, ProxyConfig};
+use config::{ProxyConfig, SharedConfig, UserConfig};
 use socks5::parse_header;
 use crypto::Encryptor;
+use crypto::replay::ReplayFilter;
 use asyncdns::DnsResolver;
 use collections::{Holder, Dict};
 use error::{Result, SocketError, Error as UnionError, Socks5Error, ProcessError};
-use super::{init_relay, Relay, MyHandler, UdpProcessor};
+use super::{init_relay, new_replay_filter, role, Relay, MyHandler, UdpProcessor};
+
+// a listening socket with the config of its port
+struct Listener {
+    sock: RcCell<UdpSocket>,
+    proxy_conf: Arc<ProxyConfig>,
+    encryptor: RcCell<Encryptor>,
+    cache: Dict<SocketAddr, RcCell<UdpProcessor>>,
+}
 
 // only receive data from client/sslocal,
 // and relay the data to `UdpProcessor`
 pub struct UdpRelay {
-    proxy_conf: Arc<ProxyConfig>,
+    config: Arc<SharedConfig>,
     server_chooser: RcCell<ServerChooser>,
     dns_resolver: RcCell<DnsResolver>,
-    token: Token,
     interest: EventSet,
-    listener: RcCell<UdpSocket>,
+    listeners: Dict<Token, Listener>,
     receive_buf: Option<Vec<u8>>,
     dns_token: Token,
-    cache: Dict<SocketAddr, RcCell<UdpProcessor>>,
     processors: Holder<RcCell<UdpProcessor>>,
-    encryptor: RcCell<Encryptor>,
+    // only ssserver: users and their encryptors, rebuilt after the config is reloaded
+    users: Vec<(Arc<UserConfig>, RcCell<Encryptor>)>,
+    replay_filter: Option<ReplayFilter>,
+}
+
+fn new_encryptor(conf: &ProxyConfig, replay_filter: &Option<ReplayFilter>) -> Result<Encryptor> {
+    let mut encryptor = Encryptor::new(&conf.password, conf.method)
+        .map_err(ProcessError::InitEncryptorFailed)?;
+    encryptor.set_role(role());
+    if let Some(ref filter) = *replay_filter {
+        encryptor.set_replay_filter(filter.clone());
+    }
+    Ok(encryptor)
+}
+
+fn new_users(user_confs: &[Arc<UserConfig>],
+             replay_filter: &Option<ReplayFilter>)
+             -> Result<Vec<(Arc<UserConfig>, RcCell<Encryptor>)>> {
+    let mut users = vec![];
+    for user in user_confs {
+        let encryptor = new_encryptor(&user.proxy_conf, replay_filter)?;
+        users.push((user.clone(), new_rc_cell(encryptor)));
+    }
+    Ok(users)
 }
 
 impl UdpRelay {
-    pub fn new() -> Result<UdpRelay> {
-        init_relay(|token, dns_token, dns_resolver, server_chooser, processors, socket_addr| {
-            let proxy_conf = if cfg!(feature = "sslocal") {
-                server_chooser.borrow_mut().choose().ok_or(ProcessError::NoServerAvailable)?
-            } else {
-                CONFIG.proxy_conf.clone()
-            };
+    pub fn new(config: Arc<SharedConfig>) -> Result<UdpRelay> {
+        init_relay(&config, |listen_confs, dns_token, dns_resolver, server_chooser, processors| {
+            let shared_config = config.clone();
+            let config = config.get();
+            let replay_filter = new_replay_filter(&config);
+            let mut listeners = Dict::default();
+            for (token, socket_addr, proxy_conf) in listen_confs {
+                let proxy_conf = if cfg!(feature = "sslocal") {
+                    server_chooser.borrow_mut()
+                        .choose()
+                        .ok_or(ProcessError::NoServerAvailable)?
+                } else {
+                    proxy_conf
+                };
 
-            let encryptor = Encryptor::new(&proxy_conf.password, proxy_conf.method)
-                .map_err(ProcessError::InitEncryptorFailed)?;
+                let encryptor = new_encryptor(&proxy_conf, &replay_filter)?;
 
-            let listener = if CONFIG.prefer_ipv6 {
-                UdpSocket::v6()
-            } else {
-                UdpSocket::v4()
-            };
-            let listener = listener.map_err(|_| SocketError::InitSocketFailed)?;
-            listener.bind(&socket_addr).map_err(|_| SocketError::BindAddrFailed(socket_addr))?;
+                let sock = if config.prefer_ipv6 {
+                    UdpSocket::v6()
+                } else {
+                    UdpSocket::v4()
+                };
+                let sock = sock.map_err(|_| SocketError::InitSocketFailed)?;
+                sock.bind(&socket_addr).map_err(|_| SocketError::BindAddrFailed(socket_addr))?;
 
-            if cfg!(feature = "sslocal") {
-                info!("ssclient udp relay listen on {}", socket_addr);
-            } else {
-                info!("ssserver udp relay listen on {}", socket_addr);
+                if cfg!(feature = "sslocal") {
+                    info!("ssclient udp relay listen on {}", socket_addr);
+                } else {
+                    info!("ssserver udp relay listen on {}", socket_addr);
+                }
+
+                listeners.insert(token,
+                                 Listener {
+                                     sock: new_rc_cell(sock),
+                                     proxy_conf: proxy_conf,
+                                     encryptor: new_rc_cell(encryptor),
+                                     cache: Dict::default(),
+                                 });
             }
 
+            let users = match config.user_confs {
+                Some(ref user_confs) if !cfg!(feature = "sslocal") => {
+                    new_users(user_confs, &replay_filter)?
+                }
+                _ => vec![],
+            };
+
             Ok(UdpRelay {
-                proxy_conf: proxy_conf,
+                config: shared_config,
                 server_chooser: server_chooser,
                 dns_resolver: dns_resolver,
-                token: token,
                 interest: EventSet::readable(),
+                listeners: listeners,
                 receive_buf: Some(Vec::with_capacity(BUF_SIZE)),
-                listener: new_rc_cell(listener),
                 dns_token: dns_token,
-                cache: Dict::default(),
                 processors: processors,
-                encryptor: new_rc_cell(encryptor),
+                users: users,
+                replay_filter: replay_filter,
             })
         })
     }
 
     pub fn run(self) -> Result<()> {
         let mut event_loop = EventLoop::new()?;
-        event_loop.register(&*self.listener.borrow(),
-                      self.token,
-                      self.interest,
-                      PollOpt::edge() | PollOpt::oneshot())
-            .or(Err(SocketError::RegisterFailed))?;
+        for (&token, listener) in &self.listeners {
+            event_loop.register(&*listener.sock.borrow(),
+                          token,
+                          self.interest,
+                          PollOpt::edge() | PollOpt::oneshot())
+                .or(Err(SocketError::RegisterFailed))?;
+        }
         self.dns_resolver
             .borrow_mut()
             .register(&mut event_loop)
@@ -111,8 +165,15 @@ impl UdpRelay {
 
     fn remove_processor(&mut self, token: Token) -> Option<RcCell<UdpProcessor>> {
         let p = try_opt!(self.processors.remove(token));
-        let res = self.cache.remove(p.borrow().addr());
-        res
+        let addr = *p.borrow().addr();
+        for listener in self.listeners.values_mut() {
+            // a client may send to more than one port
+            let is_owner = listener.cache.get(&addr).map_or(false, |q| Rc::ptr_eq(q, &p));
+            if is_owner {
+                return listener.cache.remove(&addr);
+            }
+        }
+        None
     }
 
     fn destroy_processor(&mut self, event_loop: &mut EventLoop<Relay>, token: Token) {
@@ -122,18 +183,30 @@ impl UdpRelay {
 
     fn create_processor(&mut self,
                         event_loop: &mut EventLoop<Relay>,
+                        listener_token: Token,
                         token: Token,
-                        client_addr: SocketAddr)
+                        client_addr: SocketAddr,
+                        user: Option<usize>)
                         -> Result<()> {
-        let p = new_rc_cell(UdpProcessor::new(token,
-                                              client_addr,
-                                              &self.listener,
-                                              &self.proxy_conf,
-                                              &self.dns_resolver,
-                                              &self.server_chooser,
-                                              &self.encryptor)?);
+        let p = {
+            let listener = &self.listeners[&listener_token];
+            let (proxy_conf, encryptor) = match user {
+                Some(i) => (&self.users[i].0.proxy_conf, &self.users[i].1),
+                None => (&listener.proxy_conf, &listener.encryptor),
+            };
+            new_rc_cell(UdpProcessor::new(token,
+                                          client_addr,
+                                          &listener.sock,
+                                          proxy_conf,
+                                          &self.config.get(),
+                                          &self.dns_resolver,
+                                          &self.server_chooser,
+                                          encryptor)?)
+        };
         self.processors.insert_with(token, p.clone());
-        self.cache.insert(client_addr, p.clone());
+        if let Some(listener) = self.listeners.get_mut(&listener_token) {
+            listener.cache.insert(client_addr, p.clone());
+        }
         self.dns_resolver.borrow_mut().add_caller(p.clone());
         let res = p.borrow_mut().register(event_loop).map_err(|e| {
             self.destroy_processor(event_loop, token);
@@ -145,20 +218,29 @@ impl UdpRelay {
     // handle data from client or sslocal
     fn handle_request(&mut self,
                       event_loop: &mut EventLoop<Relay>,
+                      listener_token: Token,
                       client_addr: SocketAddr,
-                      data: &[u8])
+                      data: &[u8],
+                      peer_session_id: Option<u64>,
+                      user: Option<usize>)
                       -> Result<()> {
         // parse socks5 header
         match parse_header(data) {
             Some(header) => {
-                if !self.cache.contains_key(&client_addr) {
+                if !self.listeners[&listener_token].cache.contains_key(&client_addr) {
                     debug!("create udp processor for {:?}", client_addr);
                     let token = self.processors.alloc_token().ok_or(SocketError::AllocTokenFailed)?;
-                    self.create_processor(event_loop, token, client_addr)?;
+                    if let Some(i) = user {
+                        debug!("{} is identified as user {}", client_addr, self.users[i].0.name);
+                    }
+                    self.create_processor(event_loop, listener_token, token, client_addr, user)?;
                 }
 
                 if data.len() > 0 {
-                    let p = &self.cache[&client_addr];
+                    let p = &self.listeners[&listener_token].cache[&client_addr];
+                    if peer_session_id.is_some() {
+                        p.borrow_mut().set_udp_peer_session_id(peer_session_id);
+                    }
                     p.borrow_mut().handle_request(event_loop, data, header)?;
                 }
                 Ok(())
@@ -167,21 +249,65 @@ impl UdpRelay {
         }
     }
 
-    fn handle_events(&mut self, event_loop: &mut EventLoop<Relay>, events: EventSet) -> Result<()> {
-        event_loop.reregister(&*self.listener.borrow(),
-                        self.token,
+    // users of the reloaded config (e.g. by SIGHUP) take effect on the next request,
+    // the processors of identified clients keep the encryptors of their users
+    fn sync_users(&mut self) -> Result<()> {
+        let config = self.config.get();
+        let user_confs = config.user_confs.as_ref().map_or(&[][..], |users| &users[..]);
+        let is_changed = self.users.len() != user_confs.len() ||
+                         self.users.iter().zip(user_confs).any(|(&(ref u, _), c)| u != c);
+        if is_changed {
+            self.users = new_users(user_confs, &self.replay_filter)?;
+        }
+        Ok(())
+    }
+
+    // try the key of every user, return the matched user and decrypted data
+    fn identify_user(&self, data: &[u8]) -> Option<(usize, Vec<u8>)> {
+        for (i, &(_, ref encryptor)) in self.users.iter().enumerate() {
+            let mut encryptor = encryptor.borrow_mut();
+            let decrypted = match encryptor.probe_udp(data) {
+                Some(decrypted) => decrypted,
+                None => continue,
+            };
+            if decrypted.is_empty() || parse_header(&decrypted).is_none() {
+                continue;
+            }
+            return if encryptor.check_replay(data, true) {
+                Some((i, decrypted))
+            } else {
+                None
+            };
+        }
+        None
+    }
+
+    fn handle_events(&mut self,
+                     event_loop: &mut EventLoop<Relay>,
+                     token: Token,
+                     events: EventSet)
+                     -> Result<()> {
+        let (sock, encryptor) = {
+            let listener = &self.listeners[&token];
+            (listener.sock.clone(), listener.encryptor.clone())
+        };
+        event_loop.reregister(&*sock.borrow(),
+                        token,
                         self.interest,
                         PollOpt::edge() | PollOpt::oneshot())?;
         if events.is_error() {
             error!("events error on udp relay");
             return err_from!(SocketError::EventError);
         }
+        if !cfg!(feature = "sslocal") {
+            self.sync_users()?;
+        }
 
         let mut buf = self.receive_buf.take().unwrap();
         new_fat_slice_from_vec!(buf_slice, buf);
 
         let mut res = Ok(());
-        let result = self.listener.borrow().recv_from(buf_slice);
+        let result = sock.borrow().recv_from(buf_slice);
         match result {
             Ok(None) => {}
             Ok(Some((nwrite, addr))) => {
@@ -195,15 +321,37 @@ impl UdpRelay {
                     if cfg!(feature = "sslocal") {
                         if buf[2] == 0 {
                             // skip REV and FRAG fields
-                            res = self.handle_request(event_loop, addr, &buf[3..]);
+                            let data = &buf[3..];
+                            res = self.handle_request(event_loop, token, addr, data, None, None);
                         } else {
                             warn!("drop the udp request since FRAG is not 0");
                         }
+                    } else if !self.users.is_empty() {
+                        match self.identify_user(&buf) {
+                            Some((i, data)) => {
+                                let session_id = self.users[i].1.borrow().udp_peer_session_id();
+                                res = self.handle_request(event_loop,
+                                                          token,
+                                                          addr,
+                                                          &data,
+                                                          session_id,
+                                                          Some(i));
+                            }
+                            None => {
+                                res = err_from!(ProcessError::UnknownUser);
+                            }
+                        }
                     } else {
-                        let decrypted = self.encryptor.borrow_mut().decrypt_udp(&buf);
+                        let decrypted = encryptor.borrow_mut().decrypt_udp(&buf);
                         match decrypted {
                             Some(data) => {
-                                res = self.handle_request(event_loop, addr, &data);
+                                let session_id = encryptor.borrow().udp_peer_session_id();
+                                res = self.handle_request(event_loop,
+                                                          token,
+                                                          addr,
+                                                          &data,
+                                                          session_id,
+                                                          None);
                             }
                             None => {
                                 res = err_from!(ProcessError::DecryptFailed);
@@ -222,8 +370,8 @@ impl UdpRelay {
 
 impl MyHandler for UdpRelay {
     fn ready(&mut self, event_loop: &mut EventLoop<Relay>, token: Token, events: EventSet) {
-        if token == self.token {
-            if let Err(e) = self.handle_events(event_loop, events) {
+        if self.listeners.contains_key(&token) {
+            if let Err(e) = self.handle_events(event_loop, token, events) {
                 error!("udp relay: {:?}", e);
             }
         } else if token == self.dns_token {
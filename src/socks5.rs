@@ -1,7 +1,7 @@
 use std::fmt;
 use std::net::IpAddr;
 
-use network::{slice2ip4, slice2ip6, NetworkReadBytes};
+use network::{slice2ip4, slice2ip6, Address, NetworkReadBytes, NetworkWriteBytes};
 
 pub enum Error {
     CheckAuthFailed(CheckAuthResult),
@@ -145,6 +145,25 @@ pub fn pack_addr(ip: IpAddr) -> Vec<u8> {
     res
 }
 
+// Inverse of `parse_header`, `None` if the hostname is too long.
+pub fn pack_header(addr: &Address) -> Option<Vec<u8>> {
+    let mut header = match addr.0.parse::<IpAddr>() {
+        Ok(ip) => pack_addr(ip),
+        Err(_) => {
+            if addr.0.len() > 255 {
+                return None;
+            }
+            let mut header = Vec::with_capacity(2 + addr.0.len() + 2);
+            header.push(addr_type::HOST);
+            header.push(addr.0.len() as u8);
+            header.extend_from_slice(addr.0.as_bytes());
+            header
+        }
+    };
+    pack!(u16, header, addr.1);
+    Some(header)
+}
+
 #[allow(dead_code, non_snake_case)]
 pub mod addr_type {
     pub const IPV4: u8 = 0x01;
@@ -0,0 +1,116 @@
+extern crate shadowsocks;
+extern crate rustc_serialize;
+
+use std::sync::Arc;
+
+use rustc_serialize::hex::FromHex;
+use shadowsocks::crypto::{AeadCipher, Cipher, Method, Mode, StreamCipher};
+
+const PLAINTEXT: &'static str = "The quick brown fox jumps over the lazy dog";
+
+// produced by `openssl enc -<method> -K <key> -iv <iv> -nopad`,
+// where key is `00 01 02 ...` and iv is `f0 f1 ... ff`
+const CFB_VECTORS: &'static [(Method, &'static str)] =
+    &[(Method::aes_128_cfb,
+       "32cfa2c84527582bfc71bc755c61c38d3ce17e0678914a95594d20e37158a2a34601c5fe071a7c70a9ff8d"),
+      (Method::aes_192_cfb,
+       "7fea2d72258700f3d8f954709f92c6d3577f591b008efe8877931c48e2b39d3c16c121cde092489c4e8571"),
+      (Method::aes_256_cfb,
+       "c668a8ad52e3e9a8314984262f450d34bde46567508dd25ad3930ca26727c2cefa6fd3972af6f01f3f64a4"),
+      (Method::aes_128_cfb1,
+       "5bb7fe12ebf0b84105dabe6569e7a96cca9990f3b8655fd3420007015fd09894764cc229d224a73e82d106"),
+      (Method::aes_192_cfb1,
+       "088618cd11de00176445eae45a1ad7536dc2a8ff2dd0f46abfb8db0c4eeb6ad8b985b18f9ec26dbbf95caf"),
+      (Method::aes_256_cfb1,
+       "c1f1b2064aea7075ec44364a90c6c6b57cc61faeacfc6da1f3018f319c6f90401e2f21939d86c32b5f6b20"),
+      (Method::aes_128_cfb8,
+       "32b84a8611e14969ca18811fe25d765b589b1f0ed9204514ef27b077f1afebbc0f52d901ec943a59f751c5"),
+      (Method::aes_192_cfb8,
+       "7f813dd9d0e00153ed2df370e54b914b4cba85cdbdcea4f5278b30e1717e733d9a55748a6f503497c9d9d3"),
+      (Method::aes_256_cfb8,
+       "c6be8738092cfa54615a5f30b64be819f1f25174190e22ef3196018eb77264dc1f2bec4e16441233634e80")];
+
+// produced by shadowsocks-crypto with the same key (PSK) and iv (salt),
+// `seal(length of plaintext as u16 BE) | seal(plaintext)`
+const AEAD_2022_VECTORS: &'static [(Method, &'static str)] =
+    &[(Method::blake3_aes_128_gcm,
+       "b83b8c7f51784ccf25b6e5ad594244fa4a88214076fd3ca1e4b0cc070df4478f8e047bd643b813d7132fb1bc\
+        e72b2e7b2c0e5ffa1e8e04c2ecfd6bacdc8b40f780d05d47b7c8f60f81b87eac43"),
+      (Method::blake3_aes_256_gcm,
+       "958903f6414f22a81cedaec9b66bc1a6844e2c3908ef753bcf916482eedbef46fc8e8ef7aef31e9068456dd4\
+        ef10e9eee932883e6f024bab3cb5c61ea51650bea5d8172b91d58d4258da136a25"),
+      (Method::blake3_chacha20_poly1305,
+       "0e885b53922d0cef7508c9008611168d2e8fe9fdc584ce1e4848375049748152b52f483a21a4321edca763c6\
+        29110ddd8888b77f7985ad29e24554416ec5cc7ca3aff59fe9a44c397c3fda7b9f")];
+
+fn new_cipher(method: Method, mode: Mode) -> Cipher {
+    let (key_len, iv_len) = method.info();
+    let key: Vec<u8> = (0..key_len as u8).collect();
+    let iv: Vec<u8> = (0..iv_len as u8).map(|i| 0xf0u8.wrapping_add(i)).collect();
+    Cipher::new(method, mode, Arc::new(key), iv).unwrap()
+}
+
+// feed `input` in pieces of different sizes to check the state kept between calls
+fn update_in_pieces(cipher: &mut Cipher, input: &[u8]) -> Vec<u8> {
+    let mut output = vec![];
+    let mut offset = 0;
+    let mut size = 1;
+    while offset < input.len() {
+        let end = std::cmp::min(offset + size, input.len());
+        let mut piece = vec![];
+        cipher.update(&input[offset..end], &mut piece).unwrap();
+        output.extend_from_slice(&piece);
+        offset = end;
+        size += 3;
+    }
+    output
+}
+
+#[test]
+fn cfb_known_answer() {
+    for &(method, expected) in CFB_VECTORS {
+        let expected = expected.from_hex().unwrap();
+
+        let mut encrypted = vec![];
+        new_cipher(method, Mode::Encrypt).update(PLAINTEXT.as_bytes(), &mut encrypted).unwrap();
+        assert_eq!(expected, encrypted, "{} encrypt", method);
+
+        let encrypted = update_in_pieces(&mut new_cipher(method, Mode::Encrypt),
+                                         PLAINTEXT.as_bytes());
+        assert_eq!(expected, encrypted, "{} encrypt in pieces", method);
+
+        let decrypted = update_in_pieces(&mut new_cipher(method, Mode::Decrypt), &expected);
+        assert_eq!(PLAINTEXT.as_bytes(), &decrypted[..], "{} decrypt", method);
+    }
+}
+
+#[test]
+fn cfb_invalid_iv() {
+    let key = Arc::new(vec![0u8; 16]);
+    for iv_len in &[0, 8, 17] {
+        let iv = vec![0u8; *iv_len];
+        assert!(Cipher::new(Method::aes_128_cfb, Mode::Encrypt, key.clone(), iv).is_err());
+    }
+}
+
+#[test]
+fn aead_2022_known_answer() {
+    for &(method, expected) in AEAD_2022_VECTORS {
+        let expected = expected.from_hex().unwrap();
+        let len = [0, PLAINTEXT.len() as u8];
+
+        let mut encrypted = vec![];
+        let mut cipher = new_cipher(method, Mode::Encrypt);
+        cipher.seal(&len, &mut encrypted).unwrap();
+        cipher.seal(PLAINTEXT.as_bytes(), &mut encrypted).unwrap();
+        assert_eq!(expected, encrypted, "{} seal", method);
+
+        let (encrypted_len, encrypted_data) = expected.split_at(len.len() + 16);
+        let mut decrypted = vec![];
+        let mut cipher = new_cipher(method, Mode::Decrypt);
+        cipher.open(encrypted_len, &mut decrypted).unwrap();
+        cipher.open(encrypted_data, &mut decrypted).unwrap();
+        assert_eq!(&len[..], &decrypted[..2], "{} open", method);
+        assert_eq!(PLAINTEXT.as_bytes(), &decrypted[2..], "{} open", method);
+    }
+}
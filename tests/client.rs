@@ -0,0 +1,148 @@
+// Connect through ssserver relays running in the test process.
+#![cfg(not(feature = "sslocal"))]
+extern crate shadowsocks;
+
+use std::thread;
+use std::time::Duration;
+use std::sync::Arc;
+use std::io::prelude::*;
+use std::net::{TcpListener, UdpSocket, SocketAddr};
+
+use shadowsocks::client::{connect, UdpConnector};
+use shadowsocks::config::{Config, SharedConfig, ProxyConfig};
+use shadowsocks::crypto::Method;
+use shadowsocks::network::Address;
+use shadowsocks::relay::{TcpRelay, UdpRelay};
+
+const PASSWORD: &'static str = "foo";
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn tcp_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = conn.unwrap();
+            // the relay closes both sides at once, so echo until the client goes away
+            thread::spawn(move || {
+                let mut buf = [0u8; 1024];
+                while let Ok(n) = conn.read(&mut buf) {
+                    if n == 0 || conn.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+fn udp_echo_server() -> SocketAddr {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = sock.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        loop {
+            let (n, addr) = sock.recv_from(&mut buf).unwrap();
+            sock.send_to(&buf[..n], addr).unwrap();
+        }
+    });
+    addr
+}
+
+// Run the TCP and UDP relays of ssserver on the same port.
+fn ssserver(method: Method, one_time_auth: bool) -> Arc<ProxyConfig> {
+    let mut conf = Config::default();
+    {
+        let proxy_conf = Arc::get_mut(&mut conf.proxy_conf).unwrap();
+        proxy_conf.address = "127.0.0.1".to_string();
+        proxy_conf.port = free_port();
+        proxy_conf.method = method;
+        proxy_conf.password = PASSWORD.to_string();
+        proxy_conf.one_time_auth = one_time_auth;
+    }
+    let proxy_conf = conf.proxy_conf.clone();
+    let config = Arc::new(SharedConfig::new(conf));
+    let udp_config = config.clone();
+    thread::spawn(move || TcpRelay::new(config).and_then(|r| r.run()).unwrap());
+    thread::spawn(move || UdpRelay::new(udp_config).and_then(|r| r.run()).unwrap());
+    proxy_conf
+}
+
+fn tcp_echo_through(server: &ProxyConfig) -> Vec<u8> {
+    let echo_addr = tcp_echo_server();
+    let target = Address(echo_addr.ip().to_string(), echo_addr.port());
+    // wait for the relay to listen
+    let mut stream = None;
+    for _ in 0..50 {
+        match connect(server, target.clone()) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+    let mut stream = stream.unwrap();
+
+    stream.write_all(b"hello shadowsocks").unwrap();
+    let mut response = vec![0u8; b"hello shadowsocks".len()];
+    stream.read_exact(&mut response).unwrap();
+    response
+}
+
+fn udp_echo_through(server: &ProxyConfig) -> Vec<u8> {
+    let echo_addr = udp_echo_server();
+    let target = Address(echo_addr.ip().to_string(), echo_addr.port());
+    let mut connector = UdpConnector::new(server).unwrap();
+    connector.get_ref().set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+
+    // datagrams are lost before the relay listens
+    let mut buf = [0u8; 1024];
+    for _ in 0..50 {
+        connector.send_to(b"hello shadowsocks", &target).unwrap();
+        if let Ok((n, addr)) = connector.recv_from(&mut buf) {
+            assert_eq!(addr, target);
+            return buf[..n].to_vec();
+        }
+    }
+    panic!("no response from {}:{}", server.address, server.port);
+}
+
+#[test]
+fn tcp_connect() {
+    for &method in &[Method::aes_256_ctr, Method::chacha20_ietf_poly1305] {
+        let server = ssserver(method, false);
+        assert_eq!(tcp_echo_through(&server), b"hello shadowsocks");
+    }
+}
+
+#[test]
+fn udp_connect() {
+    for &method in &[Method::aes_256_ctr, Method::chacha20_ietf_poly1305] {
+        let server = ssserver(method, false);
+        assert_eq!(udp_echo_through(&server), b"hello shadowsocks");
+    }
+}
+
+#[test]
+fn one_time_auth() {
+    let server = ssserver(Method::aes_256_cfb, true);
+    assert_eq!(tcp_echo_through(&server), b"hello shadowsocks");
+    assert_eq!(udp_echo_through(&server), b"hello shadowsocks");
+
+    // ssserver with OTA enabled rejects requests without OTA
+    let mut plain = (*server).clone();
+    plain.one_time_auth = false;
+    let echo_addr = tcp_echo_server();
+    let mut stream = connect(&plain, Address(echo_addr.ip().to_string(), echo_addr.port()))
+        .unwrap();
+    let _ = stream.write_all(b"hello shadowsocks");
+    stream.get_ref().set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    assert!(response.is_empty());
+}
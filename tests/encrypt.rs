@@ -1,4 +1,5 @@
 extern crate shadowsocks;
+extern crate rustc_serialize;
 
 use std::str;
 use std::thread;
@@ -6,9 +7,16 @@ use std::io::prelude::*;
 use std::sync::mpsc::channel;
 use std::net::{TcpListener, TcpStream, Shutdown};
 
-use shadowsocks::crypto::{Encryptor, Method};
+use rustc_serialize::hex::FromHex;
+use shadowsocks::crypto::{Encryptor, Method, Role};
+use shadowsocks::crypto::replay::ReplayFilter;
+use shadowsocks::socks5::parse_header;
 
 const PASSWORD: &'static str = "foo";
+const PSK_128: &'static str = "AAECAwQFBgcICQoLDA0ODw==";
+const PSK_256: &'static str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+// 127.0.0.1:80
+const ADDR: &'static [u8] = &[1, 127, 0, 0, 1, 0, 80];
 const MESSAGES: &'static [&'static str] = &["a", "hi", "foo", "hello", "world"];
 
 macro_rules! assert_new {
@@ -62,7 +70,7 @@ macro_rules! assert_decrypt_udp {
 
 #[test]
 fn in_order() {
-    for method in Method::all() {
+    for method in Method::all().into_iter().filter(|m| !m.is_aead_2022()) {
         let mut encryptor = assert_new!(method);
         for msg in MESSAGES {
             let encrypted = assert_encrypt!(encryptor, msg.as_bytes());
@@ -74,7 +82,7 @@ fn in_order() {
 
 #[test]
 fn chaos() {
-    for method in Method::all() {
+    for method in Method::all().into_iter().filter(|m| !m.is_aead_2022()) {
         let mut encryptor = assert_new!(method);
         let mut buf_msg = vec![];
         let mut buf_encrypted = vec![];
@@ -121,7 +129,7 @@ fn tcp_server() {
     }
 
 
-    for method in Method::all() {
+    for method in Method::all().into_iter().filter(|m| !m.is_aead_2022()) {
         let (tx, rx) = channel();
 
         let t1 = thread::spawn(move || {
@@ -146,7 +154,7 @@ fn tcp_server() {
 
 #[test]
 fn udp() {
-    for method in Method::all() {
+    for method in Method::all().into_iter().filter(|m| !m.is_aead_2022()) {
         let mut encryptor = assert_new!(method);
         for msg in MESSAGES {
             let encrypted = assert_encrypt_udp!(encryptor, msg.as_bytes());
@@ -155,3 +163,283 @@ fn udp() {
         }
     }
 }
+
+#[test]
+fn aead_byte_by_byte() {
+    for method in Method::all().into_iter().filter(|m| m.is_aead() && !m.is_aead_2022()) {
+        let mut encryptor = assert_new!(method);
+        let mut encrypted = vec![];
+        let mut msgs = vec![];
+        for msg in MESSAGES {
+            encrypted.extend_from_slice(&assert_encrypt!(encryptor, msg.as_bytes()));
+            msgs.extend_from_slice(msg.as_bytes());
+        }
+
+        let mut decrypted = vec![];
+        for b in &encrypted {
+            decrypted.extend_from_slice(&assert_decrypt!(encryptor, &[*b]));
+        }
+        assert_eq!(msgs, decrypted);
+    }
+}
+
+#[test]
+fn aead_tampered() {
+    for method in Method::all().into_iter().filter(|m| m.is_aead() && !m.is_aead_2022()) {
+        let mut encryptor = assert_new!(method);
+        let mut encrypted = assert_encrypt!(encryptor, MESSAGES[0].as_bytes());
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 0x01;
+        assert!(encryptor.decrypt(&encrypted).is_none());
+
+        let mut encrypted = assert_encrypt_udp!(encryptor, MESSAGES[0].as_bytes());
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 0x01;
+        assert!(encryptor.decrypt_udp(&encrypted).is_none());
+    }
+}
+
+fn psk_of(method: Method) -> &'static str {
+    if method == Method::blake3_aes_128_gcm {
+        PSK_128
+    } else {
+        PSK_256
+    }
+}
+
+#[test]
+fn aead2022_tcp() {
+    fn server_of(method: Method, filter: &ReplayFilter) -> Encryptor {
+        let mut server = Encryptor::new(psk_of(method), method).unwrap();
+        server.set_role(Role::Server);
+        server.set_replay_filter(filter.clone());
+        server
+    }
+
+    for method in Method::all().into_iter().filter(|m| m.is_aead_2022()) {
+        let filter = ReplayFilter::new(0);
+        // without initial payload the request is padded
+        for payload in &[&b""[..], MESSAGES[3].as_bytes()] {
+            let mut client = Encryptor::new(psk_of(method), method).unwrap();
+            let mut server = server_of(method, &filter);
+            let mut request = ADDR.to_vec();
+            request.extend_from_slice(payload);
+            let encrypted_request = assert_encrypt!(client, &request);
+            assert_eq!(request, assert_decrypt!(server, &encrypted_request));
+
+            let encrypted = assert_encrypt!(server, MESSAGES[4].as_bytes());
+            assert_eq!(MESSAGES[4].as_bytes(), &assert_decrypt!(client, &encrypted)[..]);
+
+            // salt can't be used twice
+            let mut replayed = server_of(method, &filter);
+            assert!(replayed.decrypt(&encrypted_request).is_none());
+        }
+        assert_eq!(filter.rejected_count(), 2);
+    }
+}
+
+#[test]
+fn aead2022_udp() {
+    for method in Method::all().into_iter().filter(|m| m.is_aead_2022()) {
+        let filter = ReplayFilter::new(0);
+        let mut client = Encryptor::new(psk_of(method), method).unwrap();
+        let mut server = Encryptor::new(psk_of(method), method).unwrap();
+        server.set_role(Role::Server);
+        server.set_replay_filter(filter.clone());
+        let mut request = ADDR.to_vec();
+        request.extend_from_slice(MESSAGES[3].as_bytes());
+
+        let encrypted = assert_encrypt_udp!(client, &request);
+        assert_eq!(request, assert_decrypt_udp!(server, &encrypted));
+        assert!(server.udp_peer_session_id().is_some());
+
+        // packet id can't be used twice
+        let mut replayed = Encryptor::new(psk_of(method), method).unwrap();
+        replayed.set_role(Role::Server);
+        replayed.set_replay_filter(filter.clone());
+        assert!(replayed.decrypt_udp(&encrypted).is_none());
+        assert_eq!(filter.rejected_count(), 1);
+
+        // neither can the packet id of a response
+        let response = assert_encrypt_udp!(server, &request);
+        assert_eq!(request, assert_decrypt_udp!(client, &response));
+        assert!(client.decrypt_udp(&response).is_none());
+
+        // response must belong to our session
+        let mut other = Encryptor::new(psk_of(method), method).unwrap();
+        assert_encrypt_udp!(other, &request);
+        assert!(other.decrypt_udp(&assert_encrypt_udp!(server, &request)).is_none());
+    }
+}
+
+#[test]
+fn aead2022_invalid_psk() {
+    for method in Method::all().into_iter().filter(|m| m.is_aead_2022()) {
+        assert!(Encryptor::new(PASSWORD, method).is_err());
+    }
+}
+
+#[test]
+fn replayed_iv() {
+    let filter = ReplayFilter::new(1000);
+    // methods without IV can't be protected
+    for method in Method::all().into_iter().filter(|m| !m.is_aead_2022() && m.info().1 > 0) {
+        let mut client = assert_new!(method);
+        let mut server = assert_new!(method);
+        server.set_replay_filter(filter.clone());
+        let rejected = filter.rejected_count();
+
+        let encrypted = assert_encrypt!(client, MESSAGES[4].as_bytes());
+        assert_decrypt!(server, &encrypted);
+        let mut replayed = assert_new!(method);
+        replayed.set_replay_filter(filter.clone());
+        assert!(replayed.decrypt(&encrypted).is_none());
+        // the filter of another relay hasn't seen it
+        let mut other = assert_new!(method);
+        other.set_replay_filter(ReplayFilter::new(1000));
+        assert_decrypt!(other, &encrypted);
+
+        let encrypted = assert_encrypt_udp!(client, MESSAGES[4].as_bytes());
+        assert_decrypt_udp!(server, &encrypted);
+        assert!(server.decrypt_udp(&encrypted).is_none());
+
+        assert_eq!(filter.rejected_count(), rejected + 2);
+    }
+}
+
+#[test]
+fn no_iv_is_never_replayed() {
+    let filter = ReplayFilter::new(1000);
+    // every session of rc4 starts with the same (empty) IV
+    for _ in 0..3 {
+        let mut client = assert_new!(Method::rc4);
+        let mut server = assert_new!(Method::rc4);
+        server.set_replay_filter(filter.clone());
+        let encrypted = assert_encrypt!(client, MESSAGES[4].as_bytes());
+        assert_eq!(MESSAGES[4].as_bytes(), &assert_decrypt!(server, &encrypted)[..]);
+        let encrypted = assert_encrypt_udp!(client, MESSAGES[4].as_bytes());
+        assert_eq!(MESSAGES[4].as_bytes(), &assert_decrypt_udp!(server, &encrypted)[..]);
+    }
+}
+
+// `iv | ciphertext` of "The quick brown fox jumps over the lazy dog" with password "foo",
+// produced by `openssl enc` (the key of rc4-md5 is MD5(key | iv))
+const VECTORS: &'static [(Method, &'static str, &'static str)] =
+    &[(Method::chacha20_ietf,
+       "000102030405060708090a0b",
+       "0eecc7d7715c2d74913f03248ef9f7be08a963bc48a09b06b07a1305d95659695641ff9bc6e20ff6978cba"),
+      (Method::rc4_md5,
+       "000102030405060708090a0b0c0d0e0f",
+       "157d4b06ea2ef539ec601a1037b15869896bb89e16e861f6bebc9945e9ba064c979bf0844405c32cce5a78")];
+
+// AEAD ciphers of shadowsocks-libev and Outline, the salt is `00 01 02 ...`
+const AEAD_VECTORS: &'static [(Method, &'static str, &'static str)] =
+    &[(Method::aes_128_gcm,
+       "000102030405060708090a0b0c0d0e0f",
+       "ee7d7af0f4e90cbd48ddea2883224e966c3cc23a8b754a871cf06b14a70d622c441153d51981fcce\
+        52e13a02bb6d9f8822e75f95561b5769d6196e33d9784dfa2d987c4364e544b8010bbbf47c"),
+      (Method::aes_256_gcm,
+       "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+       "eb00e42422c3bc0412734909cdf577c04f405b2344583d426d5f1ee7f7b484ad46b12256a9fe2cdd\
+        78685bcb1d78b344ecdfb5e8fa61c0c1a6888220c9fdfc61c46639bf1b0f022878aa0383e2"),
+      (Method::chacha20_ietf_poly1305,
+       "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+       "ba18fe339e86e4ce4cd213fbb5079267aa6da713344b0b37c3eb355634ca322131a807611f4c8983\
+        1fa5578d4cf0af2345385ae1b223118369e9aa321a5559e9efba9ffe3d5e80544b14473999")];
+
+const AEAD_UDP_VECTORS: &'static [(Method, &'static str, &'static str)] =
+    &[(Method::aes_128_gcm,
+       "000102030405060708090a0b0c0d0e0f",
+       "ba3e104b8aa5f5a03d4e28950ddd48e733cc8b33ffcdf028ded6eb19e4572f5fd66c95ae4f767607\
+        ec0b2c06331c5d3fcdcae8cd35fc37a0d2c420"),
+      (Method::aes_256_gcm,
+       "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+       "bf4355da7e023844703202cfb918d2def4eeb9919801184ec30cdb124848e7f3027127516c27d7b6\
+        34362d646058762c908809a98c39ee7dce40f6"),
+      (Method::chacha20_ietf_poly1305,
+       "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+       "ee5b126a5d8b90abf1800d70ff9051872cd1b8d00915cd6287c2d1ba3ebf0edab75a80d9996665de\
+        7abaeb614047b409782112ac8664adbe2d652b")];
+
+#[cfg(feature = "openssl")]
+const OPENSSL_VECTORS: &'static [(Method, &'static str, &'static str)] =
+    &[(Method::bf_cfb,
+       "0001020304050607",
+       "f40842451748a0e587b62f356892b4519c1e624af9c1056d03924be9e05a4fc519b7b524f6816bcf239b57")];
+
+fn check_vectors(vectors: &[(Method, &str, &str)]) {
+    for &(method, iv, ciphertext) in vectors {
+        let mut data = iv.from_hex().unwrap();
+        data.extend_from_slice(&ciphertext.from_hex().unwrap());
+
+        let mut encryptor = assert_new!(method);
+        let decrypted = assert_decrypt!(encryptor, &data);
+        assert_eq!(b"The quick brown fox jumps over the lazy dog"[..],
+                   decrypted[..],
+                   "{}",
+                   method);
+    }
+}
+
+#[test]
+fn known_answer() {
+    check_vectors(VECTORS);
+}
+
+#[test]
+fn aead_known_answer() {
+    check_vectors(AEAD_VECTORS);
+
+    for &(method, salt, ciphertext) in AEAD_UDP_VECTORS {
+        let mut data = salt.from_hex().unwrap();
+        data.extend_from_slice(&ciphertext.from_hex().unwrap());
+
+        let mut encryptor = assert_new!(method);
+        let decrypted = assert_decrypt_udp!(encryptor, &data);
+        assert_eq!(b"The quick brown fox jumps over the lazy dog"[..],
+                   decrypted[..],
+                   "{}",
+                   method);
+    }
+}
+
+#[cfg(feature = "openssl")]
+#[test]
+fn openssl_known_answer() {
+    check_vectors(OPENSSL_VECTORS);
+}
+
+#[test]
+fn plain() {
+    assert_eq!(Ok(Method::none), "plain".parse::<Method>());
+    assert_eq!((0, 0), Method::none.info());
+
+    let mut encryptor = assert_new!(Method::none);
+    for msg in MESSAGES {
+        assert_eq!(msg.as_bytes(), &assert_encrypt!(encryptor, msg.as_bytes())[..]);
+        assert_eq!(msg.as_bytes(), &assert_encrypt_udp!(encryptor, msg.as_bytes())[..]);
+        assert_eq!(msg.as_bytes(), &assert_decrypt!(encryptor, msg.as_bytes())[..]);
+        assert_eq!(msg.as_bytes(), &assert_decrypt_udp!(encryptor, msg.as_bytes())[..]);
+    }
+
+    // the address header is sent as is, and parsed by the other side
+    let mut client = assert_new!(Method::none);
+    let mut server = assert_new!(Method::none);
+    for msg in MESSAGES {
+        let mut request = ADDR.to_vec();
+        request.extend_from_slice(msg.as_bytes());
+        for &is_udp in &[false, true] {
+            let decrypted = if is_udp {
+                let encrypted = assert_encrypt_udp!(client, &request);
+                assert_decrypt_udp!(server, &encrypted)
+            } else {
+                let encrypted = assert_encrypt!(client, &request);
+                assert_decrypt!(server, &encrypted)
+            };
+            assert_eq!(request, decrypted);
+            let header = parse_header(&decrypted).unwrap();
+            assert_eq!((&header.1[..], header.2), ("127.0.0.1", 80));
+            assert_eq!(msg.as_bytes(), &decrypted[header.3..]);
+        }
+    }
+}
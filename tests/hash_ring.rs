@@ -0,0 +1,58 @@
+extern crate shadowsocks;
+
+use shadowsocks::collections::HashRing;
+
+fn ring(nodes: &[&'static str]) -> HashRing<&'static str> {
+    let mut ring = HashRing::new(100);
+    for node in nodes {
+        ring.insert(*node, *node);
+    }
+    ring
+}
+
+#[test]
+fn remap_removed_node_only() {
+    let before = ring(&["a", "b", "c", "d"]);
+    let after = ring(&["a", "b", "c"]);
+    let keys: Vec<String> = (0..1000).map(|i| format!("host{}.example.com", i)).collect();
+
+    let mut moved = 0;
+    for key in &keys {
+        let (old, new) = (before.get(key).unwrap(), after.get(key).unwrap());
+        if old != new {
+            assert_eq!(*old, "d");
+            moved += 1;
+        }
+    }
+    // about a quarter of keys belong to `d`
+    assert!(moved > 100 && moved < 400, "{}", moved);
+}
+
+#[test]
+fn skip_rejected_nodes() {
+    let ring = ring(&["a", "b", "c"]);
+    assert!(HashRing::<&str>::new(100).get("example.com").is_none());
+
+    for i in 0..100 {
+        let key = format!("host{}.example.com", i);
+        let node = ring.get(&key).unwrap();
+        let other = ring.find(&key, |n| n != node).unwrap();
+        assert!(other != node);
+        assert!(ring.find(&key, |_| false).is_none());
+    }
+}
+
+#[test]
+fn place_nodes_by_id() {
+    let mut before = HashRing::new(100);
+    let mut after = HashRing::new(100);
+    for (i, id) in ["a", "b", "c"].iter().enumerate() {
+        before.insert(id, (*id, i));
+        // the same ids with other values
+        after.insert(id, (*id, i + 10));
+    }
+    for i in 0..100 {
+        let key = format!("host{}.example.com", i);
+        assert_eq!(before.get(&key).unwrap().0, after.get(&key).unwrap().0);
+    }
+}
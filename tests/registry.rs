@@ -0,0 +1,63 @@
+extern crate shadowsocks;
+
+use shadowsocks::crypto::{Encryptor, Method, Mode, StreamCipher};
+use shadowsocks::crypto::error::CipherResult;
+use shadowsocks::crypto::registry::{self, CipherFactory};
+
+// XOR with the first byte of key, just for testing
+struct Xor(u8);
+
+impl StreamCipher for Xor {
+    fn apply_keystream(&mut self, data: &mut [u8]) -> CipherResult<()> {
+        for b in data.iter_mut() {
+            *b ^= self.0;
+        }
+        Ok(())
+    }
+}
+
+struct XorFactory(&'static str);
+
+impl CipherFactory for XorFactory {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn key_len(&self) -> usize {
+        16
+    }
+
+    fn iv_len(&self) -> usize {
+        4
+    }
+
+    fn new_cipher(&self, _mode: Mode, key: &[u8], _iv: &[u8]) -> CipherResult<Box<StreamCipher>> {
+        Ok(Box::new(Xor(key[0])))
+    }
+}
+
+#[test]
+fn register_cipher() {
+    let method = registry::register(XorFactory("xor")).unwrap();
+    assert_eq!(method.to_string(), "xor");
+    assert_eq!(method.info(), (16, 4));
+    assert_eq!("xor".parse::<Method>(), Ok(method));
+    assert!(Method::all().contains(&method));
+
+    let mut encryptor = Encryptor::new("foo", method).unwrap();
+    let mut decryptor = Encryptor::new("foo", method).unwrap();
+    let encrypted = encryptor.encrypt(b"hello").unwrap();
+    assert_eq!(encrypted.len(), 4 + 5);
+    assert!(&encrypted[4..] != b"hello");
+    assert_eq!(decryptor.decrypt(&encrypted).unwrap(), b"hello");
+
+    // replace a built-in stream cipher
+    let xor_rc4 = registry::register(XorFactory("rc4")).unwrap();
+    assert!(xor_rc4 != Method::rc4);
+    assert_eq!("rc4".parse::<Method>(), Ok(xor_rc4));
+    let all = Method::all();
+    assert!(!all.contains(&Method::rc4));
+    assert_eq!(all.iter().filter(|m| m.to_string() == "rc4").count(), 1);
+    // but not a AEAD one
+    assert!(registry::register(XorFactory("aes-256-gcm")).is_err());
+}
@@ -0,0 +1,271 @@
+// Run ssserver in the test process, relays are built from injected configs.
+#![cfg(not(feature = "sslocal"))]
+extern crate shadowsocks;
+
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream, Shutdown, SocketAddr};
+
+use shadowsocks::client::connect;
+use shadowsocks::config::{Config, SharedConfig, ProxyConfig, UserConfig};
+use shadowsocks::crypto::{Encryptor, Method};
+use shadowsocks::mode::HealthChecker;
+use shadowsocks::network::Address;
+use shadowsocks::relay::TcpRelay;
+use shadowsocks::socks5::pack_header;
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn tcp_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = conn.unwrap();
+            // the relay closes both sides at once, so echo until the client goes away
+            thread::spawn(move || {
+                let mut buf = [0u8; 1024];
+                while let Ok(n) = conn.read(&mut buf) {
+                    if n == 0 || conn.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+// a stand-in of HTTP server, which answers every request with an empty page
+fn http_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = conn.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = conn.read(&mut buf);
+            let _ = conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        }
+    });
+    addr
+}
+
+fn ssserver(method: Method, password: &str) -> Config {
+    run(server_config(method, password))
+}
+
+fn server_config(method: Method, password: &str) -> Config {
+    let mut conf = Config::default();
+    {
+        let proxy_conf = Arc::get_mut(&mut conf.proxy_conf).unwrap();
+        proxy_conf.address = "127.0.0.1".to_string();
+        proxy_conf.port = free_port();
+        proxy_conf.method = method;
+        proxy_conf.password = password.to_string();
+        proxy_conf.one_time_auth = false;
+    }
+    conf
+}
+
+fn run(conf: Config) -> Config {
+    let config = Arc::new(SharedConfig::new(conf.clone()));
+    thread::spawn(move || TcpRelay::new(config).and_then(|r| r.run()).unwrap());
+    conf
+}
+
+// connect to the relay without shadowsocks
+fn connect_relay(conf: &Config) -> TcpStream {
+    let addr = (&conf.proxy_conf.address[..], conf.proxy_conf.port);
+    // wait for the relay to listen
+    for _ in 0..50 {
+        match TcpStream::connect(addr) {
+            Ok(conn) => return conn,
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+    panic!("relay doesn't listen on {}:{}", addr.0, addr.1);
+}
+
+fn echo_through(server: &ProxyConfig, echo_addr: SocketAddr) -> Vec<u8> {
+    let target = Address(echo_addr.ip().to_string(), echo_addr.port());
+    // wait for the relay to listen
+    let mut stream = None;
+    for _ in 0..50 {
+        match connect(server, target.clone()) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+    let mut stream = stream.unwrap();
+
+    stream.write_all(b"hello shadowsocks").unwrap();
+    let mut response = vec![0u8; b"hello shadowsocks".len()];
+    stream.read_exact(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn two_servers_in_one_process() {
+    let echo_addr = tcp_echo_server();
+    let server1 = ssserver(Method::aes_256_gcm, "foo");
+    let server2 = ssserver(Method::chacha20_ietf_poly1305, "bar");
+
+    assert_eq!(echo_through(&server1.proxy_conf, echo_addr), b"hello shadowsocks");
+    assert_eq!(echo_through(&server2.proxy_conf, echo_addr), b"hello shadowsocks");
+}
+
+#[test]
+fn plain() {
+    let echo_addr = tcp_echo_server();
+    let server = ssserver(Method::none, "foo");
+    assert_eq!(echo_through(&server.proxy_conf, echo_addr), b"hello shadowsocks");
+
+    // the address header and data are relayed as is
+    let mut request = pack_header(&Address(echo_addr.ip().to_string(), echo_addr.port()))
+        .unwrap();
+    request.extend_from_slice(b"hello shadowsocks");
+    let mut conn = connect_relay(&server);
+    conn.write_all(&request).unwrap();
+    let mut response = vec![0u8; b"hello shadowsocks".len()];
+    conn.read_exact(&mut response).unwrap();
+    assert_eq!(response, b"hello shadowsocks");
+}
+
+#[test]
+fn listen_on_several_ports() {
+    let echo_addr = tcp_echo_server();
+    let mut conf = server_config(Method::aes_256_gcm, "foo");
+    conf.port_confs = Some(["bar", "baz"]
+        .iter()
+        .map(|password| {
+            let mut port_conf = (*conf.proxy_conf).clone();
+            port_conf.port = free_port();
+            port_conf.password = password.to_string();
+            Arc::new(port_conf)
+        })
+        .collect());
+    let conf = run(conf);
+
+    for port_conf in conf.port_confs.as_ref().unwrap() {
+        assert_eq!(echo_through(port_conf, echo_addr), b"hello shadowsocks");
+    }
+    // `port` is replaced by the ports of `port_password`
+    assert!(TcpStream::connect(("127.0.0.1", conf.proxy_conf.port)).is_err());
+}
+
+#[test]
+fn health_check() {
+    let http_addr = http_server();
+    let healthy = ssserver(Method::aes_256_gcm, "foo").proxy_conf;
+    let mut dead = (*healthy).clone();
+    dead.port = free_port();
+    let dead = Arc::new(dead);
+
+    let checker = HealthChecker::new(Address(http_addr.ip().to_string(), http_addr.port()), 1);
+    let probes = checker.subscribe();
+    checker.set_servers(vec![healthy.clone(), dead.clone()]);
+
+    // the first probes may fail before ssserver listens
+    let (mut healthy_rtt, mut dead_rtt) = (None, Some(0));
+    let start = Instant::now();
+    while (healthy_rtt.is_none() || dead_rtt.is_some()) && start.elapsed().as_secs() < 10 {
+        for probe in probes.try_iter() {
+            if probe.server == healthy {
+                healthy_rtt = probe.rtt;
+            } else if probe.server == dead {
+                dead_rtt = probe.rtt;
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(healthy_rtt.is_some());
+    assert!(dead_rtt.is_none());
+}
+
+#[test]
+fn share_health_checker() {
+    let mut conf = Config::default();
+    conf.health_check_target = Some(Address("127.0.0.1".to_string(), free_port()));
+    conf.health_check_interval = 60;
+    let shared = SharedConfig::new(conf);
+    let checker = shared.health_checker(&shared.get()).unwrap();
+    assert!(Arc::ptr_eq(&checker, &shared.health_checker(&shared.get()).unwrap()));
+
+    // a reloaded config with another interval needs its own checker
+    let mut conf = (*shared.get()).clone();
+    conf.health_check_interval = 30;
+    assert!(!Arc::ptr_eq(&checker, &shared.health_checker(&conf).unwrap()));
+
+    conf.health_check_target = None;
+    assert!(shared.health_checker(&conf).is_none());
+}
+
+#[test]
+fn identify_user_of_split_request() {
+    let echo_addr = tcp_echo_server();
+    let mut conf = server_config(Method::aes_256_gcm, "foo");
+    let users = [("bob", Method::chacha20_ietf_poly1305), ("carol", Method::aes_128_gcm)];
+    conf.user_confs = Some(users.iter()
+        .map(|&(name, method)| {
+            let mut user = UserConfig::new(name, &conf.proxy_conf);
+            user.set_method(Some(&method.to_string())).unwrap();
+            user.set_password(Some(name)).unwrap();
+            Arc::new(user)
+        })
+        .collect());
+    let conf = run(conf);
+
+    for &(name, method) in &users {
+        let mut encryptor = Encryptor::new(name, method).unwrap();
+        let mut request = pack_header(&Address(echo_addr.ip().to_string(), echo_addr.port()))
+            .unwrap();
+        request.extend_from_slice(b"hello shadowsocks");
+        let request = encryptor.encrypt(&request).unwrap();
+
+        let mut conn = connect_relay(&conf);
+        conn.set_nodelay(true).unwrap();
+
+        // neither the salt (or IV) nor the header is complete in the first pieces
+        for piece in vec![&request[..1], &request[1..20], &request[20..]] {
+            conn.write_all(piece).unwrap();
+            thread::sleep(Duration::from_millis(100));
+        }
+        conn.shutdown(Shutdown::Write).unwrap();
+        let mut response = vec![];
+        conn.read_to_end(&mut response).unwrap();
+        assert_eq!(encryptor.decrypt(&response).unwrap(), b"hello shadowsocks", "{}", name);
+    }
+}
+
+#[test]
+fn replayed_request() {
+    let echo_addr = tcp_echo_server();
+    let mut encryptor = Encryptor::new("foo", Method::aes_256_gcm).unwrap();
+    let mut request = pack_header(&Address(echo_addr.ip().to_string(), echo_addr.port())).unwrap();
+    request.extend_from_slice(b"hello shadowsocks");
+    let request = encryptor.encrypt(&request).unwrap();
+
+    // every relay has its own replay filter
+    let server1 = ssserver(Method::aes_256_gcm, "foo");
+    let server2 = ssserver(Method::aes_256_gcm, "foo");
+    let send = |conf: &Config| {
+        let mut conn = connect_relay(conf);
+        conn.write_all(&request).unwrap();
+        // a replayed request is closed at once, otherwise the echo times out
+        conn.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut response = vec![];
+        let _ = conn.read_to_end(&mut response);
+        response
+    };
+    assert!(!send(&server1).is_empty());
+    assert!(send(&server1).is_empty());
+    assert!(!send(&server2).is_empty());
+}
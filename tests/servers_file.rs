@@ -0,0 +1,61 @@
+extern crate shadowsocks;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::process;
+use std::sync::Arc;
+
+use shadowsocks::config::{ProxyConfig, read_servers_file};
+use shadowsocks::crypto::Method;
+
+const SIP008: &'static str = r#"{
+    "version": 1,
+    "servers": [
+        {
+            "id": "27b8a625-4f4b-4428-9f0f-8a2317db7c79",
+            "remarks": "server 1",
+            "server": "example.com",
+            "server_port": 8388,
+            "password": "foo",
+            "method": "chacha20-ietf-poly1305",
+            "plugin": "obfs-local",
+            "plugin_opts": "obfs=http"
+        },
+        {
+            "server": "::1",
+            "server_port": 8389,
+            "password": "bar"
+        }
+    ],
+    "bytes_used": 274877906944
+}"#;
+
+#[test]
+fn read_sip008() {
+    let path = env::temp_dir().join(format!("shadowsocks_sip008_{}.json", process::id()));
+    File::create(&path).unwrap().write_all(SIP008.as_bytes()).unwrap();
+
+    let mut base = ProxyConfig::default();
+    base.method = Method::aes_256_gcm;
+    base.timeout = 300;
+    let servers = read_servers_file(&path, &Arc::new(base)).unwrap();
+    assert_eq!(servers.len(), 2);
+
+    assert_eq!(servers[0].address, "example.com");
+    assert_eq!(servers[0].port, 8388);
+    assert_eq!(servers[0].method, Method::chacha20_ietf_poly1305);
+    assert_eq!(servers[0].password, "foo");
+    assert_eq!(servers[0].plugin, Some("obfs-local".to_string()));
+    assert_eq!(servers[0].plugin_opts, Some("obfs=http".to_string()));
+    assert_eq!(servers[0].tag, Some("server 1".to_string()));
+    assert_eq!(servers[0].timeout, 300);
+
+    // missing fields are inherited
+    assert_eq!(servers[1].address, "::1");
+    assert_eq!(servers[1].method, Method::aes_256_gcm);
+
+    File::create(&path).unwrap().write_all(b"{\"servers\": [{\"server\": \"::1\"}]}").unwrap();
+    assert!(read_servers_file(&path, &Arc::new(ProxyConfig::default())).is_err());
+    fs::remove_file(&path).unwrap();
+}
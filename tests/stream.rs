@@ -0,0 +1,162 @@
+extern crate shadowsocks;
+
+use std::cmp;
+use std::thread;
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream, Shutdown};
+
+use shadowsocks::crypto::{EncryptedStream, Encryptor, Method, Role};
+use shadowsocks::socks5::addr_type;
+
+const PASSWORD: &'static str = "foo";
+const PSK_128: &'static str = "AAECAwQFBgcICQoLDA0ODw==";
+const PSK_256: &'static str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+// 127.0.0.1:80
+const ADDR: &'static [u8] = &[1, 127, 0, 0, 1, 0, 80];
+
+// An in-memory pipe which returns at most 3 bytes every read.
+struct Pipe {
+    incoming: io::Cursor<Vec<u8>>,
+    outgoing: Vec<u8>,
+}
+
+impl Pipe {
+    fn new(incoming: Vec<u8>) -> Pipe {
+        Pipe {
+            incoming: io::Cursor::new(incoming),
+            outgoing: vec![],
+        }
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len(), 3);
+        self.incoming.read(&mut buf[..len])
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn password_of(method: Method) -> &'static str {
+    match method {
+        m if m.is_aead_2022() && m.info().0 == 16 => PSK_128,
+        m if m.is_aead_2022() => PSK_256,
+        _ => PASSWORD,
+    }
+}
+
+// the receiving side of requests
+fn server_stream<T: Read + Write>(inner: T, method: Method) -> EncryptedStream<T> {
+    let mut encryptor = Encryptor::new(password_of(method), method).unwrap();
+    encryptor.set_role(Role::Server);
+    EncryptedStream::with_encryptor(inner, encryptor)
+}
+
+#[test]
+fn pipe_round_trip() {
+    let messages: Vec<Vec<u8>> = vec![ADDR.to_vec(), b"hello".to_vec(), vec![7u8; 40000]];
+    let expected = messages.concat();
+
+    for method in Method::all() {
+        let mut client = EncryptedStream::new(Pipe::new(vec![]), password_of(method), method)
+            .unwrap();
+        for msg in &messages {
+            client.write_all(msg).unwrap();
+        }
+
+        let sent = client.into_inner().outgoing;
+        let mut server = server_stream(Pipe::new(sent), method);
+        let mut received = vec![];
+        server.read_to_end(&mut received).unwrap();
+        assert!(received == expected, "{} round trip failed", method);
+    }
+}
+
+#[test]
+fn tcp_round_trip() {
+    let method = Method::aes_256_gcm;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // echo server
+    let server = thread::spawn(move || {
+        let conn = listener.accept().unwrap().0;
+        let mut server = server_stream(conn, method);
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = server.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            server.write_all(&buf[..n]).unwrap();
+        }
+    });
+
+    let conn = TcpStream::connect(addr).unwrap();
+    let mut client = EncryptedStream::new(conn, PASSWORD, method).unwrap();
+    let data = vec![42u8; 100000];
+    client.write_all(&data).unwrap();
+    client.get_ref().shutdown(Shutdown::Write).unwrap();
+
+    let mut echoed = vec![];
+    client.read_to_end(&mut echoed).unwrap();
+    assert!(echoed == data);
+    server.join().unwrap();
+}
+
+#[test]
+fn truncated_stream() {
+    for method in Method::all().into_iter().filter(|m| m.is_aead()) {
+        let mut client = EncryptedStream::new(Pipe::new(vec![]), password_of(method), method)
+            .unwrap();
+        client.write_all(ADDR).unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut sent = client.into_inner().outgoing;
+        // cut the tag of the last chunk
+        sent.pop();
+
+        let mut server = server_stream(Pipe::new(sent), method);
+        let mut received = vec![];
+        let e = server.read_to_end(&mut received).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof, "{}", method);
+    }
+}
+
+#[test]
+fn ota_round_trip() {
+    let method = Method::aes_256_cfb;
+    let mut header = ADDR.to_vec();
+    header[0] |= addr_type::AUTH;
+
+    let mut client = EncryptedStream::new(Pipe::new(vec![]), PASSWORD, method).unwrap();
+    client.enable_ota(header[0], header.len(), &header).unwrap();
+    client.write_all(b"hello").unwrap();
+    client.write_all(b"world").unwrap();
+    let sent = client.into_inner().outgoing;
+
+    // the signed header is followed by 10 bytes of HMAC-SHA1
+    let mut server = EncryptedStream::new(Pipe::new(sent.clone()), PASSWORD, method).unwrap();
+    let mut signed_header = vec![0u8; header.len() + 10];
+    server.read_exact(&mut signed_header).unwrap();
+    assert_eq!(header[..], signed_header[..header.len()]);
+    server.verify_ota(header[0], header.len(), &signed_header).unwrap();
+    let mut received = vec![];
+    server.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"helloworld");
+
+    // a tampered header is rejected
+    let mut server = EncryptedStream::new(Pipe::new(sent), PASSWORD, method).unwrap();
+    server.read_exact(&mut signed_header).unwrap();
+    signed_header[1] ^= 1;
+    assert!(server.verify_ota(header[0], header.len(), &signed_header).is_err());
+}
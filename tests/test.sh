@@ -97,6 +97,7 @@ function run_test {
 
 # ssserver sslocal
 run_test rs rs rs_server.toml rs_local.toml
+run_test rs rs ss.json ss.json
 run_test rs py rs_server.toml ss.json
 run_test rs py rs_server.toml ss_ota.json
 run_test py rs ss.json rs_local.toml
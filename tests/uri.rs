@@ -0,0 +1,51 @@
+extern crate shadowsocks;
+
+use shadowsocks::config::ProxyConfig;
+use shadowsocks::crypto::Method;
+
+fn decode(uri: &str) -> Option<ProxyConfig> {
+    let mut conf = ProxyConfig::default();
+    conf.base64_decode(uri).ok().map(|_| conf)
+}
+
+#[test]
+fn legacy_uri() {
+    // aes-256-ctr:foo@example.com:8388
+    let conf = decode("ss://YWVzLTI1Ni1jdHI6Zm9vQGV4YW1wbGUuY29tOjgzODg=#home").unwrap();
+    assert_eq!(conf.method, Method::aes_256_ctr);
+    assert_eq!(conf.password, "foo");
+    assert_eq!(conf.address, "example.com");
+    assert_eq!(conf.port, 8388);
+    assert_eq!(conf.tag, Some("home".to_string()));
+}
+
+#[test]
+fn sip002_uri() {
+    // base64url of "aes-256-gcm:foo?" without padding
+    let conf = decode("ss://YWVzLTI1Ni1nY206Zm9vPw@[::1]:8388/?plugin=obfs-local%3Bobfs%3Dhttp\
+                       #my%20server")
+        .unwrap();
+    assert_eq!(conf.method, Method::aes_256_gcm);
+    assert_eq!(conf.password, "foo?");
+    assert_eq!(conf.address, "::1");
+    assert_eq!(conf.port, 8388);
+    assert_eq!(conf.plugin, Some("obfs-local".to_string()));
+    assert_eq!(conf.plugin_opts, Some("obfs=http".to_string()));
+    assert_eq!(conf.tag, Some("my server".to_string()));
+
+    let encoded = conf.sip002_encode();
+    assert_eq!(decode(&encoded), Some(conf));
+}
+
+#[test]
+fn malformed_uri() {
+    for uri in &["ss://",
+                 "ss://YWVzLTI1Ni1jdHI6Zm9v",
+                 "ss://@example.com:8388",
+                 "ss://YWVzLTI1Ni1nY206Zm9v@example.com",
+                 "ss://YWVzLTI1Ni1nY206Zm9v@example.com:port",
+                 "ss://YWVzLTI1Ni1nY206Zm9v@example.com:8388#%zz",
+                 "http://example.com"] {
+        assert!(decode(uri).is_none(), "{}", uri);
+    }
+}